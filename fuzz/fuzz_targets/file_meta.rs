@@ -0,0 +1,6 @@
+#![no_main]
+
+use dftp::protocol::FileMeta;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| dftp_fuzz::decode::<FileMeta>(data));
@@ -0,0 +1,36 @@
+#![no_main]
+
+// plays the sender with whatever the fuzzer comes up with, against a
+// receiver that takes everything and writes it into a scratch directory
+
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use dftp::duplex::{pair, rendezvous};
+use dftp::protocol::{handshake_recv, recv_session, Inbox, Joins, Security};
+use libfuzzer_sys::fuzz_target;
+
+static RUNS: AtomicU64 = AtomicU64::new(0);
+
+fuzz_target!(|data: &[u8]| {
+    // one scratch directory per run, also with -jobs
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("dftp-fuzz-{}-{}", std::process::id(), run));
+    let _ = fs::remove_dir_all(&dir);
+    let (mut sender, mut receiver) = pair();
+    // nobody dials, so a --streams session fails at once instead of waiting
+    let (_, listen) = rendezvous();
+    let bytes = data.to_vec();
+    let feeder = thread::spawn(move || { let _ = sender.write_all(&bytes); });
+    let inbox = Inbox{ filename: "out".to_string(), dir: dir.to_string_lossy().to_string(), yes: true, log: false, preserve: true, quiet: true };
+    let security = Security{ code: None, tls: None };
+    let peer = "127.0.0.1:1".parse().unwrap();
+    // the receiver goes either way, so a feeder stuck on a full pipe gets to
+    // see it closed
+    let _ = handshake_recv(&mut receiver)
+        .and_then(|_| recv_session(Box::new(receiver), &peer, &Joins::Rendezvous(&listen), &inbox, &security));
+    feeder.join().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+});
@@ -0,0 +1,6 @@
+#![no_main]
+
+use dftp::protocol::StreamJoin;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| dftp_fuzz::decode::<StreamJoin>(data));
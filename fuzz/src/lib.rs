@@ -0,0 +1,20 @@
+use dftp::protocol::TcpShovable;
+
+// decodes a message off the front of data. whatever decodes has to encode
+// again, and decoding that has to give the same message back.
+pub fn decode<T: TcpShovable + Default>(data: &[u8]) {
+    let mut wire = data;
+    let mut message = T::default();
+    let read = match message.pull(&mut wire) {
+        Ok(n) => n,
+        Err(_) => { return; }
+    };
+    assert_eq!(read, data.len() - wire.len());
+    let mut once = Vec::new();
+    message.shove(&mut once).expect("a decoded message encodes");
+    let mut again = T::default();
+    again.pull(&mut &once[..]).expect("an encoded message decodes");
+    let mut twice = Vec::new();
+    again.shove(&mut twice).expect("a decoded message encodes");
+    assert_eq!(once, twice);
+}
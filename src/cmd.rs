@@ -2,28 +2,103 @@ extern crate getopts;
 
 use getopts::{Options, HasArg, Occur};
 use std::process::exit;
+use std::path::Path;
+use std::io;
+
+use dftp::tls::parse_fingerprint;
+use dftp::network::split_addr;
+use dftp::discovery::local_name;
+use dftp::compress::{parse_codec, Codec, CODEC_NONE};
+use dftp::parallel::MAX_STREAMS;
+use dftp::limit::parse_rate;
+use dftp::DftpError;
 
 pub const DIR_SEND: i16 = 1;
 pub const DIR_RECV: i16 = 0;
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub fn parse_args(argv:Vec<String>) -> Result<(i32, i16, String, String, bool), String>{
+pub struct Args {
+    pub port: i32,
+    pub direction: i16,
+    pub filenames: Vec<String>,
+    pub listen: bool,   // wait for the peer instead of dialing it
+    pub host: String,   // name or address of the peer to dial, or @name
+    pub peer_port: u16, // port the peer listens on
+    pub dir: String, // output directory, receiver side only
+    pub announce: Option<String>, // name to announce while listening, receiver side only
+    pub discover: bool, // list the receivers around and quit
+    pub codec: Codec,
+    pub streams: u8, // parallel data connections, sender side only
+    pub limit: Option<u64>, // bytes per second on the wire, sender side only
+    pub encrypt: bool,
+    pub code: Option<String>, // pairing code, dialing end only
+    pub tls: bool,
+    pub yes: bool, // accept incoming entries without asking, receiver side only
+    pub daemon: bool, // keep receiving into dir, one sender after another or at once
+    pub max_connections: usize, // senders served at the same time by the daemon
+    pub fingerprint: Option<[u8; 32]>, // pinned peer cert, dialing end only
+    pub preserve: bool, // carry over permissions and times
+    pub follow: bool, // send what symlinks in directories point at, sender side only
+}
+
+pub fn parse_args(argv:Vec<String>) -> Result<Args, String>{
     let mut opts = Options::new();
     let mut port:i32 = -1;
     let mut direction:i16 = DIR_SEND;
-    let mut filename:String = String::from("stdin");
-    let mut addrstring = String::from("");
-    let mut compress: bool = false;
+    let mut filenames:Vec<String> = Vec::new();
+    let mut listen: bool = false;
+    let mut host = String::from("");
+    let mut peer_port: u16 = 8086;
+    let mut dir = String::from(".");
+    let mut announce: Option<String> = None;
+    let mut discover: bool = false;
+    let mut codec = Codec::default();
+    let mut streams: u8 = 1;
+    let mut limit: Option<u64> = None;
+    let mut encrypt: bool = false;
+    let mut code: Option<String> = None;
+    let mut tls: bool = false;
+    let mut yes: bool = false;
+    let mut daemon: bool = false;
+    let mut max_connections: usize = 8;
+    let mut fingerprint: Option<[u8; 32]> = None;
+    let mut preserve: bool = true;
+    let mut follow: bool = false;
 
-    opts.opt("r", "recv", "act as recieving end", "recv", HasArg::No, Occur::Optional);
+    opts.opt("r", "recv", "act as recieving end. given an ADDR, dials the sender instead of waiting for it", "recv", HasArg::No, Occur::Optional);
+    opts.opt("l", "listen", "wait for the receiver to connect instead of dialing it", "listen", HasArg::No, Occur::Optional);
     opts.opt("p", "port", "use this port for self(default: 8086)", "port", HasArg::Yes, Occur::Optional);
-    opts.opt("f", "file", "use this file instead of stdin/out", "file", HasArg::Yes, Occur::Optional);
+    opts.opt("f", "file", "use this file instead of stdin/out. may be repeated on sender side", "file", HasArg::Yes, Occur::Multi);
+    opts.opt("d", "dir", "receive into this directory. nothing is written outside of it(default: .)", "dir", HasArg::Yes, Occur::Optional);
+    opts.opt("y", "yes", "accept whatever the sender offers without asking", "yes", HasArg::No, Occur::Optional);
+    opts.opt("", "daemon", "keep listening and receive whatever is sent into the -d directory, logging every transfer. receiver side only", "daemon", HasArg::No, Occur::Optional);
+    opts.opt("", "max-connections", "senders the daemon serves at the same time, others are turned away(default: 8)", "N", HasArg::Yes, Occur::Optional);
+    opts.opt("n", "name", "announce the receiver under this name(default: the host name)", "name", HasArg::Yes, Occur::Optional);
+    opts.opt("", "no-announce", "do not announce the receiver on the local network", "no-announce", HasArg::No, Occur::Optional);
+    opts.opt("", "discover", "list the receivers announcing themselves on the local network and quit", "discover", HasArg::No, Occur::Optional);
+    opts.opt("", "no-preserve", "do not carry over permissions and times. on the receiving end, ignore the ones the sender sends", "no-preserve", HasArg::No, Occur::Optional);
+    opts.opt("", "follow-links", "send what symlinks inside directories point at instead of the links. sender side only", "follow-links", HasArg::No, Occur::Optional);
     opts.opt("h", "help", "display this help message", "help", HasArg::No, Occur::Optional);
     opts.opt("v", "version", "displays dftp's build version", "help", HasArg::No, Occur::Optional);
-    opts.opt("x",  "compress", "compressed transportation. must be specified on sender side.", "compress", HasArg::No, Occur::Optional);
+    opts.opt("x",  "compress", "compressed transportation with deflate, zstd, lz4 or xz, optionally with a level as in zstd:3, or auto to only compress what shrinks(default: deflate). must be specified on sender side.", "codec", HasArg::Maybe, Occur::Optional);
+    opts.opt("", "streams", &format!("send large files over this many connections in parallel, up to {}(default: 1). sender side only", MAX_STREAMS), "N", HasArg::Yes, Occur::Optional);
+    opts.opt("", "limit", "cap the sending rate on the wire, in bytes per second with an optional K, M or G suffix as in 20M. sender side only", "RATE", HasArg::Yes, Occur::Optional);
+    opts.opt("e", "encrypt", "encrypted transportation. the listening end prints a pairing code the other end has to enter. must be specified on both sides.", "encrypt", HasArg::No, Occur::Optional);
+    opts.opt("c", "code", "pairing code to use instead of being asked for it. implies -e", "code", HasArg::Yes, Occur::Optional);
+    opts.opt("", "tls", "tls transportation. the listening end prints the fingerprint of its certificate for the other end to pin", "tls", HasArg::No, Occur::Optional);
+    opts.opt("", "fingerprint", "certificate fingerprint printed by the listening end. implies --tls", "fingerprint", HasArg::Yes, Occur::Optional);
 
     let appname = &argv[0];
 
+    // -x takes its codec from the next argument only when that names one,
+    // "dftp host -x file" has always meant compressing file
+    let mut argv = argv.clone();
+    for i in 1..argv.len() {
+        if argv[i] == "-x" && !argv.get(i + 1).is_some_and(|a| looks_like_codec(a)) {
+            argv[i] = "--compress".to_string();
+        }
+    }
+
     let matches = match opts.parse(&argv[1..]){
         Ok(m) => m,
         Err(m) => { return Err(format!("Error while parsing input arguments:\n    {}", m)); }
@@ -40,138 +115,216 @@ pub fn parse_args(argv:Vec<String>) -> Result<(i32, i16, String, String, bool),
     if matches.opt_present("r") {
         direction = DIR_RECV;
         //filename = String::from("default");
-        port = 8086;
     }
     if matches.opt_present("p") {
         port = match matches.opt_str("p").expect("Unexpected error").parse::<i32>() {
             Ok(p) => p,
             Err(_) => { return Err("Error while parsing -p: argument is not a number".to_string()); }
         };
-        if port < 1 || port > 65536 {
+        if !(1..=65536).contains(&port) {
             return Err("Error while parsing -p: port number out of range".to_string());
         }
     }
     if matches.opt_present("f"){
-        filename = matches.opt_str("f").expect("Unexpected Error");
+        filenames = matches.opt_strs("f");
+    }
+    if matches.opt_present("d"){
+        if direction == DIR_SEND {
+            return Err("Usage error: -d is for the receiving end".to_string());
+        }
+        dir = matches.opt_str("d").expect("Unexpected error");
+    }
+    if matches.opt_present("y"){
+        if direction == DIR_SEND {
+            return Err("Usage error: -y is for the receiving end".to_string());
+        }
+        yes = true;
+    }
+    if matches.opt_present("daemon"){
+        if direction == DIR_SEND {
+            return Err("Usage error: --daemon is for the receiving end".to_string());
+        }
+        if !matches.free.is_empty() {
+            return Err("Usage error: --daemon listens, it cannot dial a sender".to_string());
+        }
+        if matches.opt_present("f") {
+            return Err("Usage error: --daemon keeps the names the senders give, -f cannot be used with it".to_string());
+        }
+        daemon = true;
+        yes = true;
+    }
+    if matches.opt_present("max-connections"){
+        if !daemon {
+            return Err("Usage error: --max-connections is for --daemon".to_string());
+        }
+        max_connections = match matches.opt_str("max-connections").expect("Unexpected error").parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => { return Err("Error while parsing --max-connections: expected a positive number".to_string()); }
+        };
     }
     if matches.opt_present("x"){
-        compress = true;
+        codec = match matches.opt_str("x") {
+            Some(spec) => parse_codec(&spec),
+            None => parse_codec("deflate"),
+        }.map_err(|m| format!("Error while parsing -x: {}", m))?;
+    }
+    if matches.opt_present("streams"){
+        if direction == DIR_RECV {
+            return Err("Usage error: --streams is for the sending end".to_string());
+        }
+        streams = match matches.opt_str("streams").expect("Unexpected error").parse::<u8>() {
+            Ok(n) if (1..=MAX_STREAMS).contains(&n) => n,
+            _ => { return Err(format!("Error while parsing --streams: expected a number from 1 to {}", MAX_STREAMS)); }
+        };
+    }
+    if matches.opt_present("limit"){
+        if direction == DIR_RECV {
+            return Err("Usage error: --limit is for the sending end".to_string());
+        }
+        limit = match parse_rate(&matches.opt_str("limit").expect("Unexpected error")) {
+            Ok(r) => Some(r),
+            Err(m) => { return Err(format!("Error while parsing --limit: {}", m)); }
+        };
+    }
+    if matches.opt_present("e"){
+        encrypt = true;
+    }
+    if matches.opt_present("c"){
+        encrypt = true;
+        code = matches.opt_str("c");
+    }
+    if matches.opt_present("tls") {
+        tls = true;
+    }
+    if matches.opt_present("fingerprint") {
+        tls = true;
+        fingerprint = match parse_fingerprint(&matches.opt_str("fingerprint").expect("Unexpected error")) {
+            Ok(f) => Some(f),
+            Err(m) => { return Err(format!("Error while parsing --fingerprint: {}", m)); }
+        };
+    }
+    if matches.opt_present("no-preserve") {
+        preserve = false;
+    }
+    if matches.opt_present("follow-links") {
+        if direction == DIR_RECV {
+            return Err("Usage error: --follow-links is for the sending end".to_string());
+        }
+        follow = true;
+    }
+    if matches.opt_present("discover") {
+        if direction == DIR_RECV {
+            return Err("Usage error: --discover is for the sending end".to_string());
+        }
+        discover = true;
     }
-    if direction == DIR_SEND {
-        if matches.free.len() > 2 || matches.free.len() < 1{
+    if matches.opt_present("l") {
+        if direction == DIR_RECV {
+            return Err("Usage error: -l is for the sending end, a receiver without ADDR listens anyway".to_string());
+        }
+        listen = true;
+        filenames.extend(matches.free.iter().cloned());
+    }
+    if direction == DIR_RECV {
+        match matches.free.len() {
+            0 => { listen = true; },
+            1 => { (host, peer_port) = split_addr(&matches.free[0]).map_err(usage_addr)?; },
+            _ => { return Err("Usage error: unexpected number of arguments. See --help for more info".to_string()); }
+        }
+    }
+    if listen && port == -1 {
+        port = 8086;
+    }
+    // the listening end makes up the secrets, the dialing end is told them
+    if listen && code.is_some() {
+        return Err("Usage error: -c is for the dialing end, the listening end makes up the code".to_string());
+    }
+    if listen && fingerprint.is_some() {
+        return Err("Usage error: --fingerprint is for the dialing end, the listening end prints it".to_string());
+    }
+    if tls && !listen && fingerprint.is_none() {
+        return Err("Usage error: --tls needs the --fingerprint printed by the listening end".to_string());
+    }
+    if direction == DIR_SEND && !listen && !discover {
+        if matches.free.is_empty() {
             return Err("Usage error: unexpected number of arguments. See --help for more info".to_string());
         }
-        if matches.free.len() == 1 {
-            // check if the port is specifically specified
-            match matches.free[0].find(":") {
-                Some(_) => {
-                    addrstring = matches.free[0].clone();
-                },
-                None => {
-                    addrstring = format!("{}:8086", matches.free[0]);
-                }
+        let mut files = &matches.free[1..];
+        if let Some(name) = matches.free[0].strip_prefix('@') {
+            if name.is_empty() {
+                return Err("Usage error: @ needs the name of a receiver. See --discover".to_string());
             }
+            host = matches.free[0].clone();
+        } else {
+            (host, peer_port) = split_addr(&matches.free[0]).map_err(usage_addr)?;
         }
-        else if matches.free.len() == 2 {
-            addrstring = format!("{}:{}", matches.free[0], matches.free[1]);
-        }
-        if !is_addr_string_valid(&addrstring) {
-            return Err("Usage error: Invalid address specified. See --help for more info".to_string());
+        // ADDR PORT is still accepted as long as PORT doesnt name a file
+        if !host.starts_with('@') && matches.free.len() == 2 && !Path::new(&matches.free[1]).exists() {
+            if let Ok(p) = matches.free[1].parse::<u16>() {
+                peer_port = p;
+                files = &[];
+            }
         }
+        filenames.extend(files.iter().cloned());
+    }
+    if matches.opt_present("n") && (direction == DIR_SEND || !listen) {
+        return Err("Usage error: -n is for a listening receiver".to_string());
+    }
+    if direction == DIR_RECV && listen && !matches.opt_present("no-announce") {
+        announce = Some(matches.opt_str("n").unwrap_or_else(local_name));
+    }
+    if direction == DIR_RECV && filenames.len() > 1 {
+        return Err("Usage error: -f can only be given once on the receiving end".to_string());
+    }
+    if filenames.is_empty() {
+        filenames.push(String::from("stdin"));
+    }
+    if filenames.len() > 1 && filenames.iter().any(|f| f == "stdin") {
+        return Err("Usage error: stdin cannot be sent along with other files".to_string());
+    }
+    if streams > 1 && filenames[0] == "stdin" {
+        return Err("Usage error: stdin cannot be split over several connections".to_string());
     }
-    if compress == true && direction == DIR_RECV {
+    if codec.id != CODEC_NONE && direction == DIR_RECV {
         eprintln!("WARNING: you have specified -x on the receiving end. Take note that even though you have specified it, if the sender hasnt specified the flag your option will be ignored");
     }
-    Ok((port, direction, filename, addrstring, compress))
+    if encrypt && !listen && code.is_none() {
+        if direction == DIR_SEND && filenames[0] == "stdin" {
+            return Err("Usage error: the pairing code cannot be asked for while sending stdin. Use -c".to_string());
+        }
+        code = Some(ask_code()?);
+    }
+    Ok(Args{ port, direction, filenames, listen, host, peer_port, dir, announce, discover, codec, streams, limit, encrypt, code, tls, yes, daemon, max_connections, fingerprint, preserve, follow })
 }
 
-fn print_help(appname: &str, opts: Options){
-    let brief = format!("Usage: {} [OPTIONS] [ADDR]", appname);
-    let usage = opts.usage(&brief);
-    println!("{} v{}\n{}\nADDR =\t<IPv4 addr>:<port>\n", 
-        appname, 
-        APP_VERSION,
-        usage);
+fn looks_like_codec(arg: &str) -> bool {
+    let name = arg.split(':').next().unwrap_or("");
+    matches!(name, "deflate" | "zstd" | "lz4" | "xz" | "auto")
 }
 
-fn is_addr_string_valid(addrstring: &String) -> bool {
-    const STATE_IP1: u8 = 0;
-    const STATE_IP2: u8 = 1;
-    const STATE_IP3: u8 = 2;
-    const STATE_IP4: u8 = 3;
-    const STATE_PORT: u8 = 4;
-    let mut state = STATE_IP1;
-    let mut acc: String = String::new();
+fn usage_addr(m: DftpError) -> String {
+    format!("Usage error: {}. See --help for more info", m)
+}
 
-    for c in addrstring.chars() {
-        match state {
-            STATE_IP1 => {
-                if c == '.' {
-                    let parsed = acc.parse::<u8>();
-                    match parsed {
-                        Ok(_) => {
-                            state = STATE_IP2;
-                            acc = String::new()
-                        },
-                        Err(_) => {return false}
-                    }
-                } else {
-                    acc.push(c)
-                }
-            },
-            STATE_IP2 => {
-                if c == '.' {
-                    let parsed = acc.parse::<u8>();
-                    match parsed {
-                        Ok(_) => {
-                            state = STATE_IP3;
-                            acc = String::new();
-                        },
-                        Err(_) => {return false}
-                    }
-                } else {
-                    acc.push(c)
-                }
-            },
-            STATE_IP3 => {
-                if c == '.' {
-                    let parsed = acc.parse::<u8>();
-                    match parsed {
-                        Ok(_) => {
-                            state = STATE_IP4;
-                            acc = String::new();
-                        },
-                        Err(_) => {return false}
-                    }
-                } else {
-                    acc.push(c)
-                }
-            },
-            STATE_IP4 => {
-                if c == ':' {
-                    let parsed = acc.parse::<u8>();
-                    match parsed {
-                        Ok(_) => {
-                            state = STATE_PORT;
-                            acc = String::new();
-                        },
-                        Err(_) => {return false}
-                    }
-                } else {
-                    acc.push(c)
-                }
-            },
-            STATE_PORT => {
-                acc.push(c)
-            },
-            5_u8..=u8::MAX => unreachable!(),
-        }
+fn ask_code() -> Result<String, String> {
+    eprint!("Enter the pairing code shown by the other end: ");
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => {},
+        Err(m) => { return Err(format!("Cannot read the pairing code: {}", m)); }
     }
-
-    let parsed = acc.parse::<u16>();
-    match parsed {
-        Ok(_) => true,
-        Err(_) => false 
+    let line = line.trim().to_string();
+    if line.is_empty() {
+        return Err("No pairing code entered".to_string());
     }
+    Ok(line)
 }
 
+fn print_help(appname: &str, opts: Options){
+    let brief = format!("Usage: {} [OPTIONS] [ADDR | @NAME] [FILES...]", appname);
+    let usage = opts.usage(&brief);
+    println!("{} v{}\n{}\nADDR =\t<host>[:<port>] | <IPv4 addr>[:<port>] | [<IPv6 addr>[%<iface>]][:<port>]\n", 
+        appname, 
+        APP_VERSION,
+        usage);
+}
@@ -1,20 +1,421 @@
-//extern crate xz2;
+use std::io::{Read, Write, Error, ErrorKind};
 
-use std::io::{Read, Write};
-
-//use xz2::write::XzEncoder;
-//use xz2::read::XzDecoder;
+use crate::error::DftpError;
+use crate::protocol::TRANSFER_BUFF_SIZE;
 
 use flate2::read::DeflateDecoder;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
 
-pub fn wrap_compressor(writer: Box<dyn Write>) -> Box<dyn Write> {
-    //Box::new(XzEncoder::new(writer, 3))
-    Box::new(DeflateEncoder::new(writer, Compression::best()))
+// codec ids as they go in the protocol table
+pub const CODEC_NONE: u8    = 0;
+pub const CODEC_DEFLATE: u8 = 1;
+pub const CODEC_ZSTD: u8    = 2;
+pub const CODEC_LZ4: u8     = 3;
+pub const CODEC_XZ: u8      = 4;
+
+pub const CHUNK_SIZE: usize = TRANSFER_BUFF_SIZE; // plaintext bytes per chunk
+const CHUNK_MIN: usize      = 512; // shorter chunks are not worth compressing
+
+// chunk kinds, the byte in front of every chunk
+const CHUNK_STORED: u8      = 0;
+const CHUNK_COMPRESSED: u8  = 1;
+pub(crate) const CHUNK_HEAD: usize = 5; // kind, then the payload length
+
+#[derive(Clone)]
+pub struct Codec {
+    pub id: u8,
+    pub level: u32, // only means something to the compressing end
+    pub auto: bool, // sample the start of each file before compressing it
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec{ id: CODEC_NONE, level: 0, auto: false }
+    }
+}
+
+pub fn codec_name(id: u8) -> String {
+    match id {
+        CODEC_NONE => "none".to_string(),
+        CODEC_DEFLATE => "deflate".to_string(),
+        CODEC_ZSTD => "zstd".to_string(),
+        CODEC_LZ4 => "lz4".to_string(),
+        CODEC_XZ => "xz".to_string(),
+        _ => format!("unknown codec {}", id),
+    }
+}
+
+// the codecs this build can handle
+const SUPPORTED_CODECS: &[u8] = &[
+    CODEC_NONE,
+    CODEC_DEFLATE,
+    #[cfg(feature = "zstd")]
+    CODEC_ZSTD,
+    #[cfg(feature = "lz4")]
+    CODEC_LZ4,
+    #[cfg(feature = "xz")]
+    CODEC_XZ,
+];
+
+pub fn codec_supported(id: u8) -> bool {
+    SUPPORTED_CODECS.contains(&id)
+}
+
+// NAME[:LEVEL], e.g. "zstd:3". without a level each codec gets its default.
+// "auto" picks the fastest codec around and only uses it where it pays off.
+pub fn parse_codec(spec: &str) -> Result<Codec, DftpError> {
+    if spec == "auto" {
+        let name = if codec_supported(CODEC_ZSTD) { "zstd" } else { "deflate" };
+        let mut codec = parse_codec(name)?;
+        codec.auto = true;
+        return Ok(codec);
+    }
+    let (name, level) = match spec.split_once(':') {
+        Some((n, l)) => match l.parse::<u32>() {
+            Ok(l) => (n, Some(l)),
+            Err(_) => { return Err(DftpError::Usage(format!("{} is not a compression level", l))); }
+        },
+        None => (spec, None),
+    };
+    let (id, default, max) = match name {
+        "deflate" => (CODEC_DEFLATE, 9, 9),
+        "zstd" => (CODEC_ZSTD, 3, 22),
+        "lz4" => (CODEC_LZ4, 0, 0),
+        "xz" => (CODEC_XZ, 6, 9),
+        "auto" => { return Err(DftpError::Usage("auto picks its own level".to_string())); }
+        _ => { return Err(DftpError::Usage(format!("unknown codec {}. Try deflate, zstd, lz4, xz or auto", name))); }
+    };
+    if !codec_supported(id) {
+        return Err(DftpError::Usage(format!("this dftp was built without {} support", name)));
+    }
+    let level = match level {
+        Some(l) if max == 0 => { return Err(DftpError::Usage(format!("{} has no compression levels, got {}", name, l))); }
+        Some(l) if l > max => { return Err(DftpError::Usage(format!("{} levels go up to {}, got {}", name, max, l))); }
+        Some(l) => l,
+        None => default,
+    };
+    Ok(Codec{ id, level, auto: false })
+}
+
+fn compress_chunk(codec: &Codec, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec.id {
+        CODEC_DEFLATE => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(codec.level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::bulk::compress(data, codec.level as i32),
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => Ok(lz4_flex::block::compress(data)),
+        #[cfg(feature = "xz")]
+        CODEC_XZ => {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), codec.level);
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        id => Err(Error::other(format!("{} compression is not supported by this build", codec_name(id)))),
+    }
+}
+
+// a chunk never holds more than CHUNK_SIZE bytes, anything that inflates
+// past that is refused rather than buffered
+fn decompress_chunk(id: u8, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let too_big = || Error::new(ErrorKind::InvalidData, "compressed chunk inflates past the chunk size");
+    let limit = CHUNK_SIZE as u64 + 1;
+    let mut out = Vec::with_capacity(CHUNK_SIZE);
+    match id {
+        CODEC_DEFLATE => { DeflateDecoder::new(data).take(limit).read_to_end(&mut out)?; },
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => {
+            let decoded = zstd::stream::read::Decoder::with_buffer(data).and_then(|d| d.take(limit).read_to_end(&mut out));
+            if let Err(m) = decoded {
+                return Err(Error::new(ErrorKind::InvalidData, format!("corrupt zstd chunk: {}", m)));
+            }
+        },
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => {
+            out.resize(CHUNK_SIZE, 0);
+            match lz4_flex::block::decompress_into(data, &mut out) {
+                Ok(n) => out.truncate(n),
+                Err(m) => { return Err(Error::new(ErrorKind::InvalidData, format!("corrupt lz4 chunk: {}", m))); }
+            }
+        },
+        #[cfg(feature = "xz")]
+        CODEC_XZ => { xz2::read::XzDecoder::new(data).take(limit).read_to_end(&mut out)?; },
+        id => { return Err(Error::other(format!("{} compression is not supported by this build", codec_name(id)))); }
+    }
+    if out.len() > CHUNK_SIZE {
+        return Err(too_big());
+    }
+    Ok(out)
+}
+
+// decides how each chunk goes on the wire: compressed or as is, whichever
+// is shorter. in auto mode the first full chunk after a restart is a
+// sample: if it does not shrink by a tenth, the rest is stored without
+// trying until the next restart.
+pub(crate) struct Packer {
+    codec: Codec,
+    sampling: bool,
+    storing: bool,
+}
+
+impl Packer {
+    pub(crate) fn new(codec: &Codec) -> Packer {
+        Packer{ codec: codec.clone(), sampling: true, storing: false }
+    }
+
+    // the chunk for data, head included
+    pub(crate) fn pack(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut packed = None;
+        if data.len() >= CHUNK_MIN && !self.storing {
+            let compressed = compress_chunk(&self.codec, data)?;
+            if self.codec.auto && self.sampling {
+                self.sampling = false;
+                self.storing = compressed.len() > data.len() / 10 * 9;
+            }
+            if compressed.len() < data.len() {
+                packed = Some(compressed);
+            }
+        }
+        let (kind, payload) = match packed.as_ref() {
+            Some(p) => (CHUNK_COMPRESSED, &p[..]),
+            None => (CHUNK_STORED, data),
+        };
+        let mut chunk = Vec::with_capacity(CHUNK_HEAD + payload.len());
+        chunk.push(kind);
+        chunk.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(payload);
+        Ok(chunk)
+    }
+
+    pub(crate) fn restart(&mut self) {
+        self.sampling = true;
+        self.storing = false;
+    }
+}
+
+// the payload length a chunk head announces
+pub(crate) fn chunk_len(head: &[u8; CHUNK_HEAD]) -> Result<usize, Error> {
+    let len = u32::from_be_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    if len > CHUNK_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "oversized chunk"));
+    }
+    Ok(len)
 }
 
-pub fn wrap_decompressor(reader: Box<dyn Read>) -> Box<dyn Read> {
-    //Box::new(XzDecoder::new(reader))
-    Box::new(DeflateDecoder::new(reader))
+// the plaintext of a chunk, given the kind byte of its head
+pub(crate) fn unpack_chunk(codec: u8, kind: u8, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match kind {
+        CHUNK_STORED => Ok(payload),
+        CHUNK_COMPRESSED => decompress_chunk(codec, &payload),
+        k => Err(Error::new(ErrorKind::InvalidData, format!("invalid chunk kind {}", k))),
+    }
+}
+
+// cuts everything written to it into chunks. the sender flushes in front
+// of every file, and a flush restarts the packer, so that is where the
+// samples get taken in auto mode.
+struct ChunkWriter {
+    inner: Box<dyn Write>,
+    packer: Packer,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn emit(&mut self) -> Result<(), Error> {
+        if self.buf.is_empty() { return Ok(()); }
+        let chunk = self.packer.pack(&self.buf)?;
+        self.inner.write_all(&chunk)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let room = CHUNK_SIZE - self.buf.len();
+        let n = std::cmp::min(room, data.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.emit()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.emit()?;
+        self.packer.restart();
+        self.inner.flush()
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+struct ChunkReader {
+    inner: Box<dyn Read>,
+    codec: u8,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    // returns false on a clean end of stream between chunks
+    fn next_chunk(&mut self) -> Result<bool, Error> {
+        let mut head = [0u8; CHUNK_HEAD];
+        let mut got = 0;
+        while got < CHUNK_HEAD {
+            let n = self.inner.read(&mut head[got..])?;
+            if n == 0 {
+                if got == 0 { return Ok(false); }
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated chunk"));
+            }
+            got += n;
+        }
+        let mut payload = vec![0u8; chunk_len(&head)?];
+        self.inner.read_exact(&mut payload)?;
+        self.buf = unpack_chunk(self.codec, head[0], payload)?;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.buf.len() {
+            if !self.next_chunk()? { return Ok(0); }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub fn wrap_compressor(writer: Box<dyn Write>, codec: &Codec) -> Result<Box<dyn Write>, DftpError> {
+    if codec.id == CODEC_NONE {
+        return Ok(writer);
+    }
+    if !codec_supported(codec.id) {
+        return Err(DftpError::Incompatible(format!("{} compression is not supported by this build", codec_name(codec.id))));
+    }
+    Ok(Box::new(ChunkWriter{
+        inner: writer,
+        packer: Packer::new(codec),
+        buf: Vec::with_capacity(CHUNK_SIZE),
+    }))
+}
+
+pub fn wrap_decompressor(reader: Box<dyn Read>, id: u8) -> Result<Box<dyn Read>, DftpError> {
+    if id == CODEC_NONE {
+        return Ok(reader);
+    }
+    if !codec_supported(id) {
+        return Err(DftpError::Incompatible(format!("{} compression is not supported by this build", codec_name(id))));
+    }
+    Ok(Box::new(ChunkReader{ inner: reader, codec: id, buf: Vec::new(), pos: 0 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    // a writer whose output can still be looked at once it is boxed away
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    // what data looks like on the wire with codec, and what comes back of it
+    fn through(codec: &Codec, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let sink = Sink::default();
+        let mut writer = wrap_compressor(Box::new(sink.clone()), codec).unwrap();
+        writer.write_all(data).unwrap();
+        drop(writer);
+        let wire = sink.0.lock().unwrap().clone();
+        let mut back = Vec::new();
+        wrap_decompressor(Box::new(Cursor::new(wire.clone())), codec.id).unwrap().read_to_end(&mut back).unwrap();
+        (wire, back)
+    }
+
+    #[test]
+    fn every_codec_of_the_build_round_trips() {
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(20_000);
+        for name in ["deflate", "deflate:1", "zstd", "zstd:19", "lz4", "xz:0", "auto"] {
+            let codec = match parse_codec(name) {
+                Ok(c) => c,
+                Err(_) => { continue; } // left out of this build
+            };
+            let (wire, back) = through(&codec, &text);
+            assert!(back == text, "{} garbled the data", name);
+            assert!(wire.len() < text.len() / 10, "{} did not compress", name);
+        }
+        assert_eq!(parse_codec("deflate:4").unwrap().level, 4);
+        for spec in ["gzip", "deflate:10", "deflate:x", "lz4:1", "auto:3"] {
+            assert!(matches!(parse_codec(spec), Err(DftpError::Usage(_))), "{} let through", spec);
+        }
+        assert!(!codec_supported(CODEC_XZ + 1));
+        assert!(matches!(wrap_decompressor(Box::new(Cursor::new(Vec::new())), CODEC_XZ + 1), Err(DftpError::Incompatible(_))));
+    }
+
+    // bytes that do not compress much
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x: u32 = 7;
+        (0..len).map(|_| { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x as u8 }).collect()
+    }
+
+    #[test]
+    fn chunks_that_do_not_shrink_are_stored() {
+        let codec = parse_codec("deflate").unwrap();
+        let text = b"compress me ".repeat(CHUNK_SIZE / 12);
+        let mut packer = Packer::new(&codec);
+        let chunk = packer.pack(&noise(CHUNK_SIZE)).unwrap();
+        assert_eq!((chunk[0], chunk.len()), (CHUNK_STORED, CHUNK_HEAD + CHUNK_SIZE));
+        assert_eq!(packer.pack(&text).unwrap()[0], CHUNK_COMPRESSED);
+        // too short to bother
+        assert_eq!(packer.pack(&text[..CHUNK_MIN - 1]).unwrap()[0], CHUNK_STORED);
+
+        // auto gives up on the rest of a file whose start does not shrink,
+        // and samples again at the next one
+        let mut packer = Packer::new(&parse_codec("auto").unwrap());
+        assert_eq!(packer.pack(&noise(CHUNK_SIZE)).unwrap()[0], CHUNK_STORED);
+        assert_eq!(packer.pack(&text).unwrap()[0], CHUNK_STORED);
+        packer.restart();
+        assert_eq!(packer.pack(&text).unwrap()[0], CHUNK_COMPRESSED);
+
+        // mixed, the stream still reads back whole
+        let mixed = [noise(CHUNK_SIZE * 2), text.clone()].concat();
+        let (wire, back) = through(&codec, &mixed);
+        assert!(back == mixed);
+        assert!(wire.len() < CHUNK_SIZE * 2 + text.len() / 2);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn corrupt_zstd_chunks_are_not_called_too_big() {
+        let text = b"compress me ".repeat(CHUNK_SIZE / 12);
+        let frame = zstd::bulk::compress(&text, 3).unwrap();
+        assert!(decompress_chunk(CODEC_ZSTD, &frame).unwrap() == text);
+        let mut bent = frame.clone();
+        bent[..4].copy_from_slice(b"junk");
+        let corrupt = decompress_chunk(CODEC_ZSTD, &bent).unwrap_err().to_string();
+        assert!(corrupt.contains("corrupt zstd chunk"), "{}", corrupt);
+        let big = zstd::bulk::compress(&vec![0u8; CHUNK_SIZE + 1], 3).unwrap();
+        let too_big = decompress_chunk(CODEC_ZSTD, &big).unwrap_err().to_string();
+        assert!(too_big.contains("past the chunk size"), "{}", too_big);
+    }
 }
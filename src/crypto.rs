@@ -0,0 +1,341 @@
+use std::io::{Read, Write, Error, ErrorKind};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::DftpError;
+
+pub const SEALED_FRAME_SIZE: usize = 65536; // plaintext bytes per frame
+
+const CODE_WORDS: [&str; 64] = [
+    "acorn", "amber", "anchor", "apple", "arrow", "badger", "banjo", "basil",
+    "beacon", "birch", "bison", "cactus", "canyon", "cedar", "cobalt", "comet",
+    "coral", "cricket", "dune", "ember", "falcon", "fern", "fjord", "garnet",
+    "glacier", "granite", "harbor", "hazel", "heron", "indigo", "island", "jasper",
+    "juniper", "kettle", "lagoon", "lantern", "lemon", "lotus", "maple", "meadow",
+    "nectar", "nutmeg", "oasis", "olive", "orbit", "otter", "pebble", "pepper",
+    "pigeon", "quartz", "quill", "raven", "river", "saffron", "salmon", "spruce",
+    "thistle", "tundra", "velvet", "walnut", "willow", "yarrow", "zephyr", "zinnia",
+];
+
+// keys for the two directions of an encrypted session
+pub struct SessionKeys {
+    pub outgoing: [u8; 32],
+    pub incoming: [u8; 32],
+}
+
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), DftpError> {
+    match getrandom::getrandom(buf) {
+        Ok(_) => Ok(()),
+        Err(m) => Err(DftpError::Io(format!("Cannot gather randomness: {}", m), None)),
+    }
+}
+
+// a short code like "417-maple-quartz" for the receiver to read out
+pub fn generate_code() -> Result<String, DftpError> {
+    let mut buf = [0u8; 4];
+    random_bytes(&mut buf)?;
+    let number = u16::from_be_bytes([buf[0], buf[1]]) % 1000;
+    Ok(format!("{}-{}-{}", number,
+               CODE_WORDS[(buf[2] & 63) as usize],
+               CODE_WORDS[(buf[3] & 63) as usize]))
+}
+
+fn hash_to_point(label: &[u8]) -> RistrettoPoint {
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&Sha512::digest(label));
+    RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+fn code_scalar(code: &str) -> Scalar {
+    let mut sha = Sha512::new();
+    sha.update(b"dftp pairing code");
+    sha.update(code.trim().to_lowercase().as_bytes());
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&sha.finalize());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+fn random_scalar() -> Result<Scalar, DftpError> {
+    let mut wide = [0u8; 64];
+    random_bytes(&mut wide)?;
+    Ok(Scalar::from_bytes_mod_order_wide(&wide))
+}
+
+fn derive(label: &[u8], transcript: &[u8; 32]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.update(label);
+    sha.update(transcript);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&sha.finalize());
+    out
+}
+
+// every data connection of a --streams session seals with keys of its own,
+// so frame counters running on different connections never share a nonce
+pub fn stream_keys(keys: &SessionKeys, index: u8) -> SessionKeys {
+    let label = [&b"dftp stream "[..], &[index]].concat();
+    SessionKeys{
+        outgoing: derive(&label, &keys.outgoing),
+        incoming: derive(&label, &keys.incoming),
+    }
+}
+
+fn pake_error(e: Error) -> DftpError {
+    DftpError::Network("Pairing failed, connection lost".to_string(), Some(e))
+}
+
+// SPAKE2 over ristretto255. both ends blind their share with the code, so a
+// peer that does not know it ends up with a different key. the key
+// confirmation round makes that show up before any file data is sent.
+// agreed is what the two ends settled on in the clear before pairing. it
+// goes into the transcript, so anything in the middle changing it makes
+// the pairing fail as a wrong code would.
+fn pake<S: Read + Write>(peer: &mut S, code: &str, agreed: &[u8], is_sender: bool) -> Result<SessionKeys, DftpError> {
+    let m = hash_to_point(b"dftp spake2 M");
+    let n = hash_to_point(b"dftp spake2 N");
+    let w = code_scalar(code);
+    let (ours_blind, theirs_blind) = if is_sender { (m, n) } else { (n, m) };
+
+    let x = random_scalar()?;
+    let ours = (x * RISTRETTO_BASEPOINT_POINT + w * ours_blind).compress();
+    peer.write_all(ours.as_bytes()).map_err(pake_error)?;
+    let mut buf = [0u8; 32];
+    peer.read_exact(&mut buf).map_err(pake_error)?;
+    let theirs = CompressedRistretto(buf);
+    let theirs_point = match theirs.decompress() {
+        Some(p) => p,
+        None => { return Err(DftpError::Auth("Pairing failed: peer sent an invalid key share".to_string())); }
+    };
+    let shared = (x * (theirs_point - w * theirs_blind)).compress();
+
+    // sender share first so both ends hash the same transcript
+    let (first, second) = if is_sender { (ours, theirs) } else { (theirs, ours) };
+    let mut sha = Sha256::new();
+    sha.update(b"dftp spake2");
+    sha.update(first.as_bytes());
+    sha.update(second.as_bytes());
+    sha.update(shared.as_bytes());
+    sha.update(w.as_bytes());
+    sha.update((agreed.len() as u32).to_be_bytes());
+    sha.update(agreed);
+    let mut transcript = [0u8; 32];
+    transcript.copy_from_slice(&sha.finalize());
+
+    let sender_confirm = derive(b"dftp confirm sender", &transcript);
+    let recver_confirm = derive(b"dftp confirm recver", &transcript);
+    let (ours_confirm, theirs_confirm) = if is_sender {
+        (sender_confirm, recver_confirm)
+    } else {
+        (recver_confirm, sender_confirm)
+    };
+    if is_sender {
+        peer.write_all(&ours_confirm).map_err(pake_error)?;
+        peer.read_exact(&mut buf).map_err(pake_error)?;
+    } else {
+        peer.read_exact(&mut buf).map_err(pake_error)?;
+        // a wrong code still gets an answer, just not a valid one
+        let answer = if same_bytes(&buf, &theirs_confirm) { ours_confirm } else { [0u8; 32] };
+        peer.write_all(&answer).map_err(pake_error)?;
+    }
+    if !same_bytes(&buf, &theirs_confirm) {
+        return Err(DftpError::Auth("Pairing failed: wrong pairing code".to_string()));
+    }
+
+    let to_recver = derive(b"dftp key sender to recver", &transcript);
+    let to_sender = derive(b"dftp key recver to sender", &transcript);
+    if is_sender {
+        Ok(SessionKeys{ outgoing: to_recver, incoming: to_sender })
+    } else {
+        Ok(SessionKeys{ outgoing: to_sender, incoming: to_recver })
+    }
+}
+
+fn same_bytes(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    let mut diff = 0u8;
+    for i in 0..32 {
+        diff |= a[i] ^ b[i];
+    }
+    diff == 0
+}
+
+pub fn pair_send<S: Read + Write>(peer: &mut S, code: &str, agreed: &[u8]) -> Result<SessionKeys, DftpError> {
+    pake(peer, code, agreed, true)
+}
+
+pub fn pair_recv<S: Read + Write>(peer: &mut S, code: &str, agreed: &[u8]) -> Result<SessionKeys, DftpError> {
+    pake(peer, code, agreed, false)
+}
+
+fn frame_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+// seals everything written to it in length prefixed chacha20poly1305 frames.
+// each direction has its own key so a running counter is a safe nonce.
+struct SealingWriter {
+    inner: Box<dyn Write>,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl SealingWriter {
+    fn seal(&mut self) -> Result<(), Error> {
+        if self.buf.is_empty() { return Ok(()); }
+        let nonce = frame_nonce(self.counter);
+        let sealed = match self.cipher.encrypt(Nonce::from_slice(&nonce), &self.buf[..]) {
+            Ok(s) => s,
+            Err(_) => { return Err(Error::other("encryption failed")); }
+        };
+        self.counter += 1;
+        self.buf.clear();
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)
+    }
+}
+
+impl Write for SealingWriter {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let room = SEALED_FRAME_SIZE - self.buf.len();
+        let n = std::cmp::min(room, data.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == SEALED_FRAME_SIZE {
+            self.seal()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.seal()?;
+        self.inner.flush()
+    }
+}
+
+impl Drop for SealingWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+struct OpeningReader {
+    inner: Box<dyn Read>,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl OpeningReader {
+    // returns false on a clean end of stream between frames
+    fn open(&mut self) -> Result<bool, Error> {
+        let mut len = [0u8; 4];
+        let mut got = 0;
+        while got < 4 {
+            let n = self.inner.read(&mut len[got..])?;
+            if n == 0 {
+                if got == 0 { return Ok(false); }
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated encrypted frame"));
+            }
+            got += n;
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > SEALED_FRAME_SIZE + 16 {
+            return Err(Error::new(ErrorKind::InvalidData, "oversized encrypted frame"));
+        }
+        let mut sealed = vec![0u8; len];
+        self.inner.read_exact(&mut sealed)?;
+        let nonce = frame_nonce(self.counter);
+        self.buf = match self.cipher.decrypt(Nonce::from_slice(&nonce), &sealed[..]) {
+            Ok(b) => b,
+            Err(_) => { return Err(Error::new(ErrorKind::InvalidData, "encrypted frame failed authentication")); }
+        };
+        self.counter += 1;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl Read for OpeningReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.buf.len() {
+            if !self.open()? { return Ok(0); }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub fn wrap_encryptor(writer: Box<dyn Write>, key: &[u8; 32]) -> Box<dyn Write> {
+    Box::new(SealingWriter{
+        inner: writer,
+        cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        counter: 0,
+        buf: Vec::with_capacity(SEALED_FRAME_SIZE),
+    })
+}
+
+pub fn wrap_decryptor(reader: Box<dyn Read>, key: &[u8; 32]) -> Box<dyn Read> {
+    Box::new(OpeningReader{
+        inner: reader,
+        cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        counter: 0,
+        buf: Vec::new(),
+        pos: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::duplex::pair;
+
+    // what goes on the wire when message is written through a sealing writer
+    fn sealed(message: &[u8], key: &[u8; 32]) -> Vec<u8> {
+        let (ours, mut theirs) = pair();
+        let mut writer = wrap_encryptor(Box::new(ours), key);
+        writer.write_all(message).unwrap();
+        drop(writer);
+        let mut wire = Vec::new();
+        theirs.read_to_end(&mut wire).unwrap();
+        wire
+    }
+
+    #[test]
+    fn sealed_frames_hide_and_guard_the_data() {
+        let key = [5u8; 32];
+        let message = b"the secret plans ".repeat(5_000);
+        let wire = sealed(&message, &key);
+        assert!(!wire.windows(17).any(|w| w == b"the secret plans "));
+        let mut back = Vec::new();
+        wrap_decryptor(Box::new(Cursor::new(wire.clone())), &key).read_to_end(&mut back).unwrap();
+        assert!(back == message);
+        // a flipped bit and a wrong key both fail instead of reading garbage
+        let mut tampered = wire.clone();
+        tampered[100] ^= 1;
+        let err = wrap_decryptor(Box::new(Cursor::new(tampered)), &key).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(wrap_decryptor(Box::new(Cursor::new(wire)), &[6u8; 32]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn pairing_codes_are_a_number_and_two_words() {
+        let code = generate_code().unwrap();
+        let parts: Vec<&str> = code.split('-').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].parse::<u16>().unwrap() < 1000);
+        assert!(CODE_WORDS.contains(&parts[1]) && CODE_WORDS.contains(&parts[2]));
+        // typed back in any case and with stray spaces, it is the same code
+        assert!(code_scalar(&code) == code_scalar(&format!("  {} ", code.to_uppercase())));
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::io::ErrorKind::WouldBlock;
+use std::net::{Shutdown, TcpListener, TcpStream, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::discovery::start_beacon;
+use crate::error::{describe, DftpError};
+use crate::network::{build_listener, unmap_address, PeerStream};
+use crate::parallel::MAX_STREAMS;
+use crate::protocol::{
+    handshake_recv,
+    join_decline,
+    join_recv,
+    recv_session,
+    transport_refuse,
+    Inbox,
+    Joins,
+    Security,
+    StreamJoin,
+};
+
+const JOIN_WAIT: Duration = Duration::from_secs(10);
+// how long a peer may take to say who it is, and to say anything at all after
+const HANDSHAKE_WAIT: Duration = Duration::from_secs(10);
+const IDLE_WAIT: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// one line per event on stderr, with the time in front
+pub fn log(line: &str) {
+    eprintln!("{} {}", timestamp(), line);
+}
+
+// utc, without pulling in a date crate for it
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+    // civil from days, see howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+type Joined = (StreamJoin, Box<dyn PeerStream>);
+
+// sessions waiting for their data connections. the accept loop cannot
+// tell whose a data connection is until it has named its session, so it
+// hands them over through here.
+pub struct Registry {
+    sessions: Mutex<HashMap<[u8; 16], Sender<Joined>>>,
+}
+
+// a session's claim on its data connections, dropped when it has them all
+pub struct Expected<'a> {
+    registry: &'a Registry,
+    session: [u8; 16],
+    joined: Receiver<Joined>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry{ sessions: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn expect(&self, session: [u8; 16]) -> Expected<'_> {
+        let (tx, joined) = channel();
+        self.sessions.lock().unwrap().insert(session, tx);
+        Expected{ registry: self, session, joined }
+    }
+
+    // sessions register before the sender hears it may open its data
+    // connections, so one naming nobody is turned away right away
+    fn hand_over(&self, joined: Joined) -> Result<(), DftpError> {
+        let tx = self.sessions.lock().unwrap().get(&joined.0.session).cloned();
+        match tx {
+            Some(tx) => tx.send(joined).map_err(|_| DftpError::Protocol("the session is gone".to_string())),
+            None => {
+                join_decline(joined.1);
+                Err(DftpError::Protocol("no such session".to_string()))
+            }
+        }
+    }
+}
+
+impl Expected<'_> {
+    pub fn next(&self) -> Result<Joined, DftpError> {
+        match self.joined.recv_timeout(JOIN_WAIT) {
+            Ok(j) => Ok(j),
+            Err(_) => Err(DftpError::Timeout(format!("the sender did not connect again within {} seconds", JOIN_WAIT.as_secs()))),
+        }
+    }
+}
+
+impl Drop for Expected<'_> {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.session);
+    }
+}
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+// the slot of a running session or of a data connection on its way to
+// one, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Daemon {
+    inbox: Inbox,
+    security: Security,
+    registry: Registry,
+    running: Arc<AtomicUsize>,
+    max_sessions: usize,
+    joining: Arc<AtomicUsize>, // data connections not yet handed over
+    max_joins: usize,
+    greeting: Arc<AtomicUsize>, // connections still in the handshake
+    max_greetings: usize,
+}
+
+fn take_slot(count: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+    let taken = count.fetch_add(1, Ordering::Relaxed);
+    let slot = Slot(count.clone());
+    if taken >= max {
+        return None;
+    }
+    Some(slot)
+}
+
+// receives into dir until told to stop. every sender gets a thread of its
+// own, up to max_sessions at a time. SIGTERM or SIGINT stop the accepting
+// and let the transfers that are going finish.
+pub fn serve(port: i32, dir: String, announce: Option<String>, security: Security, max_sessions: usize, preserve: bool) -> Result<(), DftpError> {
+    let handler = on_signal as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    if let Err(m) = std::fs::create_dir_all(&dir) {
+        return Err(DftpError::Io(format!("Error while creating directory {}", dir), Some(m)));
+    }
+    let listener = build_listener(port)?;
+    if let Err(m) = listener.set_nonblocking(true) {
+        return Err(DftpError::Network(format!("Listening on port {} failed", port), Some(m)));
+    }
+    let _beacon = match announce {
+        Some(name) => match start_beacon(&name, port as u16) {
+            Ok(b) => { log(&format!("announcing as @{}", name)); Some(b) },
+            Err(m) => { log(&format!("WARNING: {}", describe(&m))); None }
+        },
+        None => None,
+    };
+    log(&format!("receiving into {} on port {}, up to {} transfers at a time", dir, port, max_sessions));
+    let inbox = Inbox{ filename: "stdin".to_string(), dir, yes: true, log: true, preserve, quiet: true };
+    run(&listener, Arc::new(Daemon::new(inbox, security, max_sessions)), &STOP);
+    log("stopped");
+    Ok(())
+}
+
+impl Daemon {
+    fn new(inbox: Inbox, security: Security, max_sessions: usize) -> Daemon {
+        // as many as the sessions running could want at once
+        let max_joins = max_sessions * MAX_STREAMS as usize;
+        Daemon{
+            inbox,
+            security,
+            registry: Registry::new(),
+            running: Arc::new(AtomicUsize::new(0)),
+            max_sessions,
+            joining: Arc::new(AtomicUsize::new(0)),
+            max_joins,
+            greeting: Arc::new(AtomicUsize::new(0)),
+            max_greetings: max_sessions + max_joins,
+        }
+    }
+}
+
+// a connection's thread, with a way to cut it off while it has not said
+// who it is yet
+struct Connection {
+    thread: JoinHandle<()>,
+    socket: TcpStream,
+    greeted: Arc<AtomicBool>,
+}
+
+fn run(listener: &TcpListener, daemon: Arc<Daemon>, stop: &AtomicBool) {
+    let mut connections: Vec<Connection> = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        let (peer, addr) = match listener.accept() {
+            Ok(p) => p,
+            Err(m) if m.kind() == WouldBlock => {
+                connections.retain(|c| !c.thread.is_finished());
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            },
+            Err(m) => { log(&format!("cannot accept peer: {}", m)); continue; }
+        };
+        let addr = unmap_address(addr);
+        // taken before the thread, so that peers saying nothing cannot
+        // pile up threads
+        let slot = match take_slot(&daemon.greeting, daemon.max_greetings) {
+            Some(s) => s,
+            None => {
+                log(&format!("{}: closed, {} connections still to say who they are", addr, daemon.max_greetings));
+                continue;
+            }
+        };
+        let socket = match peer.try_clone() {
+            Ok(s) => s,
+            Err(m) => { log(&format!("{}: {}", addr, m)); continue; }
+        };
+        let greeted = Arc::new(AtomicBool::new(false));
+        let (daemon, flag) = (daemon.clone(), greeted.clone());
+        let thread = thread::spawn(move || handle(peer, addr, daemon, slot, &flag));
+        connections.push(Connection{ thread, socket, greeted });
+    }
+    connections.retain(|c| !c.thread.is_finished());
+    log(&format!("shutting down, waiting for {} connection(s)", connections.len()));
+    // nothing is lost by closing those that have not started yet. the
+    // others end in time too, as a peer going quiet runs into IDLE_WAIT.
+    for c in connections.iter().filter(|c| !c.greeted.load(Ordering::Relaxed)) {
+        let _ = c.socket.shutdown(Shutdown::Both);
+    }
+    for c in connections {
+        let _ = c.thread.join();
+    }
+}
+
+fn handle(mut peer: TcpStream, addr: SocketAddr, daemon: Arc<Daemon>, greeting: Slot, greeted: &AtomicBool) {
+    let timeouts = |peer: &TcpStream, wait: Duration| {
+        peer.set_nonblocking(false)
+            .and_then(|_| peer.set_read_timeout(Some(wait)))
+            .and_then(|_| peer.set_write_timeout(Some(wait)))
+    };
+    if let Err(m) = timeouts(&peer, HANDSHAKE_WAIT) {
+        log(&format!("{}: {}", addr, m));
+        return;
+    }
+    let handshake = handshake_recv(&mut peer);
+    greeted.store(true, Ordering::Relaxed);
+    drop(greeting);
+    if let Err(m) = timeouts(&peer, IDLE_WAIT) {
+        log(&format!("{}: {}", addr, m));
+        return;
+    }
+    match handshake {
+        Err(m) => log(&format!("{}: handshake failed: {}", addr, describe(&m))),
+        Ok(true) => {
+            let _slot = match take_slot(&daemon.joining, daemon.max_joins) {
+                Some(s) => s,
+                None => {
+                    let _ = transport_refuse(Box::new(peer));
+                    log(&format!("{}: data connection turned away, {} waiting already", addr, daemon.max_joins));
+                    return;
+                }
+            };
+            let handed = join_recv(Box::new(peer), &daemon.security).and_then(|j| daemon.registry.hand_over(j));
+            if let Err(m) = handed {
+                log(&format!("{}: data connection refused: {}", addr, describe(&m)));
+            }
+        },
+        Ok(false) => {
+            let _slot = match take_slot(&daemon.running, daemon.max_sessions) {
+                Some(s) => s,
+                None => {
+                    let _ = transport_refuse(Box::new(peer));
+                    log(&format!("{}: turned away, {} transfers going on", addr, daemon.max_sessions));
+                    return;
+                }
+            };
+            log(&format!("{}: connected", addr));
+            match recv_session(Box::new(peer), &addr, &Joins::Handover(&daemon.registry), &daemon.inbox, &daemon.security) {
+                Ok(()) => log(&format!("{}: done", addr)),
+                Err(m) => log(&format!("{}: failed: {}", addr, describe(&m).trim())),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use crate::duplex::pair;
+    use crate::protocol::{transport_send, Simple, TcpShovable, SIMPLE_MSG_PN_DEC};
+
+    fn join(session: [u8; 16]) -> (Joined, crate::duplex::DuplexStream) {
+        let (ours, theirs) = pair();
+        let mut join = StreamJoin::default();
+        join.session = session;
+        ((join, Box::new(theirs)), ours)
+    }
+
+    #[test]
+    fn data_connections_go_to_registered_sessions_only() {
+        let registry = Registry::new();
+        let (stray, mut peer) = join([1u8; 16]);
+        let start = Instant::now();
+        assert!(matches!(registry.hand_over(stray), Err(DftpError::Protocol(_))));
+        assert!(start.elapsed() < JOIN_WAIT / 2);
+        let mut reply = Simple::default();
+        reply.pull(&mut peer).unwrap();
+        assert_eq!(reply.content, SIMPLE_MSG_PN_DEC);
+
+        let expected = registry.expect([2u8; 16]);
+        let (ours, _peer) = join([2u8; 16]);
+        registry.hand_over(ours).unwrap();
+        assert_eq!(expected.next().unwrap().0.session, [2u8; 16]);
+        drop(expected);
+        let (late, _peer) = join([2u8; 16]);
+        assert!(registry.hand_over(late).is_err());
+    }
+
+    #[test]
+    fn slots_run_out_at_the_limit() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let first = take_slot(&count, 2).unwrap();
+        let _second = take_slot(&count, 2).unwrap();
+        assert!(take_slot(&count, 2).is_none());
+        drop(first);
+        assert!(take_slot(&count, 2).is_some());
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_sender_beyond_the_limit_hears_that_the_receiver_is_busy() {
+        let (ours, theirs) = pair();
+        let refused = thread::spawn(move || transport_refuse(Box::new(theirs)));
+        assert!(matches!(transport_send(Box::new(ours), &None), Err(DftpError::Declined(_))));
+        refused.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_peer_saying_nothing_does_not_hold_up_the_shutdown() {
+        let dir = std::env::temp_dir().join(format!("dftp-test-daemon-{}", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let inbox = Inbox{ filename: "stdin".to_string(), dir: dir.to_string_lossy().to_string(), yes: true, log: true, preserve: true, quiet: true };
+        let daemon = Arc::new(Daemon::new(inbox, Security{ code: None, tls: None }, 1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = {
+            let stop = stop.clone();
+            thread::spawn(move || run(&listener, daemon, &stop))
+        };
+        let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(POLL_INTERVAL * 3);
+        let start = Instant::now();
+        stop.store(true, Ordering::Relaxed);
+        running.join().unwrap();
+        assert!(start.elapsed() < HANDSHAKE_WAIT / 2, "took {:?}", start.elapsed());
+    }
+}
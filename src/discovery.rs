@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind::{WouldBlock, TimedOut};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use net2::UdpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
+
+use crate::protocol::{Announcement, TcpShovable, COMPAT_NUMBER};
+use crate::error::DftpError;
+
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 84);
+pub const DISCOVERY_PORT: u16       = 8087;
+const ANNOUNCE_INTERVAL: Duration   = Duration::from_millis(1000);
+const DISCOVERY_WAIT: Duration      = Duration::from_secs(3);
+
+// what a receiver calls itself unless told otherwise
+pub fn local_name() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(n) if ret == 0 && !n.is_empty() => n.to_string(),
+        _ => "dftp".to_string(),
+    }
+}
+
+// announces a waiting receiver until dropped
+pub struct Beacon {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Beacon {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn start_beacon(name: &str, port: u16) -> Result<Beacon, DftpError> {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+        Ok(s) => s,
+        Err(m) => { return Err(DftpError::Network("Cannot open the announcement socket".to_string(), Some(m))); }
+    };
+    let datagram = announcement(name, port)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            // nobody listening or no route is not our problem
+            let _ = socket.send_to(&datagram, (DISCOVERY_GROUP, DISCOVERY_PORT));
+            thread::sleep(ANNOUNCE_INTERVAL);
+        }
+    });
+    Ok(Beacon{ stop })
+}
+
+// what a beacon sends, one datagram per announcement
+fn announcement(name: &str, port: u16) -> Result<Vec<u8>, DftpError> {
+    let mut message = Announcement::default();
+    message.port = port;
+    message.name = name.to_string();
+    let mut datagram: Vec<u8> = Vec::new();
+    message.shove(&mut datagram)?;
+    Ok(datagram)
+}
+
+// where the receiver that sent datagram waits, and what it said. anything
+// else on the port is ignored.
+fn heard(datagram: &[u8], from: SocketAddr) -> Option<(SocketAddr, Announcement)> {
+    let mut message = Announcement::default();
+    message.pull(&mut &datagram[..]).ok()?;
+    Some((SocketAddr::new(from.ip(), message.port), message))
+}
+
+fn listen_socket() -> Result<UdpSocket, DftpError> {
+    let socket = UdpBuilder::new_v4().and_then(|b| {
+        b.reuse_address(true)?;
+        // several senders may be looking at the same time
+        #[cfg(unix)]
+        b.reuse_port(true)?;
+        b.bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))
+    });
+    let socket = match socket {
+        Ok(s) => s,
+        Err(m) => { return Err(DftpError::Network(format!("Cannot listen for announcements on port {}", DISCOVERY_PORT), Some(m))); }
+    };
+    if let Err(m) = socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED) {
+        return Err(DftpError::Network(format!("Cannot join the discovery group {}", DISCOVERY_GROUP), Some(m)));
+    }
+    Ok(socket)
+}
+
+// hands every announcement heard to seen until it returns true or the wait
+// is over. returns whether seen was satisfied.
+fn watch(mut seen: impl FnMut(SocketAddr, Announcement) -> bool) -> Result<bool, DftpError> {
+    let socket = listen_socket()?;
+    let deadline = Instant::now() + DISCOVERY_WAIT;
+    let mut buf = [0u8; 512];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() { return Ok(false); }
+        if let Err(m) = socket.set_read_timeout(Some(left)) {
+            return Err(DftpError::Network("Cannot listen for announcements".to_string(), Some(m)));
+        }
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(m) if m.kind() == WouldBlock || m.kind() == TimedOut => { return Ok(false); }
+            Err(m) => { return Err(DftpError::Network("Cannot listen for announcements".to_string(), Some(m))); }
+        };
+        let (addr, message) = match heard(&buf[..n], from) {
+            Some(h) => h,
+            None => { continue; }
+        };
+        if seen(addr, message) { return Ok(true); }
+    }
+}
+
+pub fn find_receiver(name: &str) -> Result<SocketAddr, DftpError> {
+    eprintln!("Looking for {} on the local network", name);
+    let mut found = None;
+    let matched = watch(|addr, message| {
+        if message.name.eq_ignore_ascii_case(name) {
+            found = Some(addr);
+            return true;
+        }
+        false
+    })?;
+    match found {
+        Some(addr) if matched => Ok(addr),
+        _ => Err(DftpError::Timeout(format!("No receiver called {} announced itself within {} seconds", name, DISCOVERY_WAIT.as_secs()))),
+    }
+}
+
+pub fn print_receivers() -> Result<(), DftpError> {
+    eprintln!("Looking for receivers on the local network");
+    let mut found: BTreeMap<(String, SocketAddr), u8> = BTreeMap::new();
+    watch(|addr, message| {
+        found.insert((message.name, addr), message.compat_num);
+        false
+    })?;
+    if found.is_empty() {
+        println!("No receivers found");
+    }
+    for ((name, addr), compat_num) in found.iter() {
+        if *compat_num == COMPAT_NUMBER {
+            println!("@{}\t{}", name, addr);
+        } else {
+            println!("@{}\t{}\t(incompatible dftp version)", name, addr);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcements_name_where_the_receiver_waits() {
+        let from: SocketAddr = "192.168.1.9:40000".parse().unwrap();
+        let (addr, message) = heard(&announcement("alice-laptop", 9000).unwrap(), from).unwrap();
+        assert_eq!(addr, "192.168.1.9:9000".parse::<SocketAddr>().unwrap());
+        assert_eq!((message.name.as_str(), message.compat_num), ("alice-laptop", COMPAT_NUMBER));
+        // the longest name still fits the buffer it is read into
+        assert!(announcement(&"n".repeat(255), 9000).unwrap().len() <= 512);
+        assert!(matches!(announcement(&"n".repeat(256), 9000), Err(DftpError::Usage(_))));
+        assert!(heard(b"M-SEARCH * HTTP/1.1", from).is_none());
+        assert!(!local_name().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write, Error};
+use std::io::ErrorKind::BrokenPipe;
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(any(test, fuzzing))]
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::network::PeerStream;
+#[cfg(any(test, fuzzing))]
+use crate::network::Rendezvous;
+
+const PIPE_CAPACITY: usize = 256 * 1024; // a writer blocks past this, as on a full socket
+
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+// one direction of a connection
+#[derive(Default)]
+struct Pipe {
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+// one end of the connection, shared by all of its handles. when the last
+// handle goes both directions are closed, like a socket: the peer reads
+// EOF and its writes fail.
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+// one end of an in-memory connection, for running sessions without
+// sockets. it behaves like a TcpStream: reads block until the peer writes,
+// and dropping every handle on one end is an EOF on the other.
+pub struct DuplexStream {
+    end: Arc<End>,
+}
+
+// two connected ends
+pub fn pair() -> (DuplexStream, DuplexStream) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let left = End{ incoming: a.clone(), outgoing: b.clone() };
+    let right = End{ incoming: b, outgoing: a };
+    (DuplexStream{ end: Arc::new(left) }, DuplexStream{ end: Arc::new(right) })
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() { return Ok(0); }
+        let pipe = &self.end.incoming;
+        let mut buffer = pipe.buffer.lock().unwrap();
+        while buffer.data.is_empty() && !buffer.closed {
+            buffer = pipe.changed.wait(buffer).unwrap();
+        }
+        let n = std::cmp::min(buf.len(), buffer.data.len());
+        for (b, d) in buf[..n].iter_mut().zip(buffer.data.drain(..n)) {
+            *b = d;
+        }
+        pipe.changed.notify_all();
+        Ok(n)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() { return Ok(0); }
+        let pipe = &self.end.outgoing;
+        let mut buffer = pipe.buffer.lock().unwrap();
+        while buffer.data.len() >= PIPE_CAPACITY && !buffer.closed {
+            buffer = pipe.changed.wait(buffer).unwrap();
+        }
+        if buffer.closed {
+            return Err(Error::new(BrokenPipe, "the other end is gone"));
+        }
+        let n = std::cmp::min(buf.len(), PIPE_CAPACITY - buffer.data.len());
+        buffer.data.extend(&buf[..n]);
+        pipe.changed.notify_all();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl PeerStream for DuplexStream {
+    fn try_clone_stream(&self) -> Result<Box<dyn PeerStream>, Error> {
+        Ok(Box::new(DuplexStream{ end: self.end.clone() }))
+    }
+}
+
+// where more connections between the same two ends come from, as a
+// --streams session opens them: the dialing side gets a new pair and hands
+// one end of it over to the listening side
+#[cfg(any(test, fuzzing))]
+pub fn rendezvous() -> (Rendezvous, Rendezvous) {
+    let (tx, rx): (Sender<DuplexStream>, Receiver<DuplexStream>) = channel();
+    (Rendezvous::MemoryDial(tx), Rendezvous::MemoryListen(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn carries_more_than_a_pipe_full_then_eof() {
+        let (mut a, mut b) = pair();
+        let data: Vec<u8> = (0..PIPE_CAPACITY * 3 + 7).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = thread::spawn(move || a.write_all(&sent).unwrap());
+        let mut got = Vec::new();
+        b.read_to_end(&mut got).unwrap();
+        writer.join().unwrap();
+        assert!(got == data);
+    }
+
+    #[test]
+    fn end_stays_open_while_a_clone_is_around() {
+        let (mut a, b) = pair();
+        let clone = b.try_clone_stream().unwrap();
+        drop(b);
+        a.write_all(b"still there").unwrap();
+        drop(clone);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), BrokenPipe);
+    }
+}
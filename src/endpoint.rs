@@ -0,0 +1,419 @@
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::path::Path;
+
+use crate::compress::Codec;
+use crate::discovery::start_beacon;
+use crate::error::{describe, DftpError};
+use crate::network::{build_dial_stream, build_listener, peer_address, split_addr, Connect, Rendezvous};
+use crate::parallel::MAX_STREAMS;
+use crate::protocol::{handshake_recv, prepare_security, recv_session, send, Inbox, Joins, Security};
+
+// what both ends may ask for, turned into a Security when connecting
+#[derive(Clone, Default)]
+struct Secrets {
+    encrypt: bool,
+    code: Option<String>,
+    tls: bool,
+    fingerprint: Option<[u8; 32]>,
+}
+
+impl Secrets {
+    fn prepare(&self, listen: bool) -> Result<Security, DftpError> {
+        if listen && self.code.is_some() {
+            return Err(DftpError::Usage("The pairing code is for the dialing end, the listening end makes it up".to_string()));
+        }
+        if listen && self.fingerprint.is_some() {
+            return Err(DftpError::Usage("The fingerprint is for the dialing end, the listening end shows it".to_string()));
+        }
+        if !listen && self.encrypt && self.code.is_none() {
+            return Err(DftpError::Usage("Encryption needs the pairing code shown by the listening end".to_string()));
+        }
+        if !listen && self.tls && self.fingerprint.is_none() {
+            return Err(DftpError::Usage("TLS needs the fingerprint shown by the listening end".to_string()));
+        }
+        prepare_security(listen, self.encrypt, self.code.clone(), self.tls, self.fingerprint)
+    }
+}
+
+/// The sending end of a transfer.
+///
+/// ```no_run
+/// # fn main() -> Result<(), dftp::DftpError> {
+/// dftp::Sender::new("myhost.local").streams(4).send_files(&["a.iso", "b.iso"])?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Sender {
+    connect: Result<Connect, String>, // a bad address, reported when sending
+    port: i32, // bound when dialing, listened on otherwise
+    codec: Codec,
+    streams: u8,
+    limit: Option<u64>,
+    secrets: Secrets,
+    preserve: bool,
+    follow: bool,
+    quiet: bool,
+}
+
+impl Sender {
+    /// Dials the receiver at `host[:port]`, or the one announcing itself as `@name`.
+    pub fn new(addr: &str) -> Sender {
+        let connect = match addr.strip_prefix('@') {
+            Some(name) => Ok(Connect::Discover(name.to_string())),
+            None => split_addr(addr).map(|(host, port)| Connect::Dial(host, port)).map_err(|e| e.to_string()),
+        };
+        Sender::with(connect, -1)
+    }
+
+    /// Dials the receiver at host and port.
+    pub fn dial(host: &str, port: u16) -> Sender {
+        Sender::with(Ok(Connect::Dial(host.to_string(), port)), -1)
+    }
+
+    /// Waits on port for the receiver to dial in instead.
+    pub fn listen(port: u16) -> Sender {
+        Sender::with(Ok(Connect::Listen), port as i32)
+    }
+
+    fn with(connect: Result<Connect, String>, port: i32) -> Sender {
+        Sender{ connect, port, codec: Codec::default(), streams: 1, limit: None, secrets: Secrets::default(), preserve: true, follow: false, quiet: false }
+    }
+
+    /// Dials from this local port.
+    pub fn bind_port(mut self, port: u16) -> Sender {
+        self.port = port as i32;
+        self
+    }
+
+    pub fn compress(mut self, codec: Codec) -> Sender {
+        self.codec = codec;
+        self
+    }
+
+    /// Sends large files over this many connections side by side.
+    pub fn streams(mut self, streams: u8) -> Sender {
+        self.streams = streams;
+        self
+    }
+
+    /// Caps what goes on the wire, in bytes per second.
+    pub fn limit(mut self, rate: u64) -> Sender {
+        self.limit = Some(rate);
+        self
+    }
+
+    /// Encrypts the transfer. A listening sender makes up the pairing code
+    /// and shows it on stderr, a dialing one needs [`Sender::pairing_code`].
+    pub fn encrypt(mut self) -> Sender {
+        self.secrets.encrypt = true;
+        self
+    }
+
+    pub fn pairing_code(mut self, code: &str) -> Sender {
+        self.secrets.encrypt = true;
+        self.secrets.code = Some(code.to_string());
+        self
+    }
+
+    /// Goes over TLS. A listening sender makes up a certificate and shows
+    /// its fingerprint on stderr, a dialing one needs [`Sender::fingerprint`].
+    pub fn tls(mut self) -> Sender {
+        self.secrets.tls = true;
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: [u8; 32]) -> Sender {
+        self.secrets.tls = true;
+        self.secrets.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Sends the permissions and times of every file and directory along
+    /// with it, which is the default. The receiver may still ignore them.
+    pub fn preserve(mut self, preserve: bool) -> Sender {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Sends symlinks inside directories as the files and directories they
+    /// point at rather than as links. Paths given directly are always
+    /// followed.
+    pub fn follow_links(mut self, follow: bool) -> Sender {
+        self.follow = follow;
+        self
+    }
+
+    /// Prints nothing on stdout, neither progress nor the files and their
+    /// digests, leaving it to the program. Warnings and what a listening
+    /// end makes up for [`Sender::encrypt`] and [`Sender::tls`] still go
+    /// to stderr.
+    pub fn quiet(mut self) -> Sender {
+        self.quiet = true;
+        self
+    }
+
+    pub fn send_file<P: AsRef<Path>>(&self, path: P) -> Result<(), DftpError> {
+        self.send_files(&[path])
+    }
+
+    /// Sends files and directory trees in one session.
+    pub fn send_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<(), DftpError> {
+        if paths.is_empty() {
+            return Err(DftpError::Usage("Nothing to send".to_string()));
+        }
+        let names: Vec<String> = paths.iter().map(|p| p.as_ref().to_string_lossy().to_string()).collect();
+        if names.iter().any(|n| n == "stdin") {
+            return Err(DftpError::Usage("stdin is sent with send_stdin".to_string()));
+        }
+        self.send_names(names)
+    }
+
+    /// Sends whatever comes in on stdin as a single unnamed stream.
+    pub fn send_stdin(&self) -> Result<(), DftpError> {
+        if self.streams > 1 {
+            return Err(DftpError::Usage("stdin cannot be split over several connections".to_string()));
+        }
+        self.send_names(vec!["stdin".to_string()])
+    }
+
+    fn send_names(&self, names: Vec<String>) -> Result<(), DftpError> {
+        let connect = match &self.connect {
+            Ok(c) => c.clone(),
+            Err(m) => { return Err(DftpError::Usage(m.clone())); }
+        };
+        if !(1..=MAX_STREAMS).contains(&self.streams) {
+            return Err(DftpError::Usage(format!("Expected 1 to {} connections, not {}", MAX_STREAMS, self.streams)));
+        }
+        let security = self.secrets.prepare(matches!(connect, Connect::Listen))?;
+        send(self.port, names, connect, self.codec.clone(), self.streams, self.limit, security, self.preserve, self.follow, self.quiet)
+    }
+}
+
+enum Source {
+    Listener(TcpListener, u16),
+    Dial(String, u16),
+}
+
+/// The receiving end of a transfer. It can take one sender after another.
+pub struct Receiver {
+    source: Source,
+    secrets: Secrets,
+    security: Option<Security>, // made up on the first accept, kept for the rest
+    announce: Option<String>,
+    quiet: bool,
+}
+
+impl Receiver {
+    /// Listens on port for senders.
+    pub fn bind(port: u16) -> Result<Receiver, DftpError> {
+        let listener = build_listener(port as i32)?;
+        Ok(Receiver::with(Source::Listener(listener, port)))
+    }
+
+    /// Dials a sender that waits with [`Sender::listen`] instead.
+    pub fn dial(host: &str, port: u16) -> Receiver {
+        Receiver::with(Source::Dial(host.to_string(), port))
+    }
+
+    fn with(source: Source) -> Receiver {
+        Receiver{ source, secrets: Secrets::default(), security: None, announce: None, quiet: false }
+    }
+
+    /// Announces the listening receiver on the local network under name
+    /// while it waits for a sender.
+    pub fn announce(mut self, name: &str) -> Receiver {
+        self.announce = Some(name.to_string());
+        self
+    }
+
+    /// Only takes encrypted transfers. A listening receiver makes up the
+    /// pairing code and shows it on stderr, a dialing one needs
+    /// [`Receiver::pairing_code`].
+    pub fn encrypt(mut self) -> Receiver {
+        self.secrets.encrypt = true;
+        self
+    }
+
+    pub fn pairing_code(mut self, code: &str) -> Receiver {
+        self.secrets.encrypt = true;
+        self.secrets.code = Some(code.to_string());
+        self
+    }
+
+    /// Only takes transfers over TLS. A listening receiver makes up a
+    /// certificate and shows its fingerprint on stderr, a dialing one needs
+    /// [`Receiver::fingerprint`].
+    pub fn tls(mut self) -> Receiver {
+        self.secrets.tls = true;
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: [u8; 32]) -> Receiver {
+        self.secrets.tls = true;
+        self.secrets.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Prints nothing on stdout for any of the senders, as
+    /// [`Sender::quiet`] does. A stream from the sender's stdin written
+    /// to stdout still goes there.
+    pub fn quiet(mut self) -> Receiver {
+        self.quiet = true;
+        self
+    }
+
+    /// Waits for the next sender, or dials it, and shakes hands with it.
+    pub fn accept(&mut self) -> Result<Incoming<'_>, DftpError> {
+        if self.security.is_none() {
+            let listen = matches!(self.source, Source::Listener(..));
+            self.security = Some(self.secrets.prepare(listen)?);
+        }
+        let (mut stream, rendezvous) = match &self.source {
+            Source::Listener(listener, port) => {
+                let beacon = match self.announce.as_ref() {
+                    Some(name) => match start_beacon(name, *port) {
+                        Ok(b) => {
+                            eprintln!("Announcing as @{}", name);
+                            Some(b)
+                        },
+                        Err(m) => { eprintln!("WARNING: {}", describe(&m)); None }
+                    },
+                    None => None,
+                };
+                let accepted = accept_from(listener);
+                drop(beacon);
+                accepted?
+            },
+            Source::Dial(host, port) => build_dial_stream(-1, host, *port)
+                .map_err(|e| e.context(|m| format!("Error while starting stream:\n  {}", m)))?,
+        };
+        let peer = peer_address(&stream).map_err(|e| e.context(|m| format!("Error while starting stream:\n  {}", m)))?;
+        match handshake_recv(&mut stream) {
+            Ok(false) => {},
+            Ok(true) => { return Err(DftpError::Protocol("Handshake failed: the peer wants to join a session that is not going".to_string())); }
+            Err(e) => { return Err(e.context(|m| format!("Handshake failed: {}", m))); }
+        }
+        Ok(Incoming{
+            stream,
+            peer,
+            rendezvous,
+            security: self.security.as_ref().expect("prepared above"),
+            filename: "stdin".to_string(),
+            yes: true,
+            preserve: true,
+            quiet: self.quiet,
+        })
+    }
+}
+
+// the data connections of a session come in on a clone of the listener,
+// which leaves it nonblocking
+fn accept_from(listener: &TcpListener) -> Result<(TcpStream, Rendezvous), DftpError> {
+    let accepted = listener.set_nonblocking(false)
+        .and_then(|_| listener.accept())
+        .and_then(|(s, _)| Ok((s, Rendezvous::Listener(listener.try_clone()?))));
+    accepted.map_err(|m| DftpError::Network("Error while starting stream:\n  Cannot accept peer".to_string(), Some(m)))
+}
+
+/// A sender that has shaken hands, before anything is written.
+pub struct Incoming<'a> {
+    stream: TcpStream,
+    peer: SocketAddr,
+    rendezvous: Rendezvous,
+    security: &'a Security,
+    filename: String,
+    yes: bool,
+    preserve: bool,
+    quiet: bool,
+}
+
+impl Incoming<'_> {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Writes under this name instead of the sender's. With several files it
+    /// names the directory they go into. An unnamed stream from the sender's
+    /// stdin goes to stdout without it.
+    pub fn output(mut self, name: &str) -> Self {
+        self.filename = name.to_string();
+        self
+    }
+
+    /// Asks on the terminal before taking each file, as the command does
+    /// without `-y`. Everything is taken otherwise.
+    pub fn ask(mut self) -> Self {
+        self.yes = false;
+        self
+    }
+
+    /// Applies the permissions and times the sender sends along, and the
+    /// owner when running as root. On by default.
+    pub fn preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Receives the whole session into dir.
+    pub fn receive_to<P: AsRef<Path>>(self, dir: P) -> Result<(), DftpError> {
+        let inbox = Inbox{
+            filename: self.filename,
+            dir: dir.as_ref().to_string_lossy().to_string(),
+            yes: self.yes,
+            log: false,
+            preserve: self.preserve,
+            quiet: self.quiet,
+        };
+        recv_session(Box::new(self.stream), &self.peer, &Joins::Rendezvous(&self.rendezvous), &inbox, self.security)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process, thread};
+
+    use crate::compress::parse_codec;
+
+    #[test]
+    fn builders_run_a_whole_transfer() {
+        let dir = env::temp_dir().join(format!("dftp-test-endpoint-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("out")).unwrap();
+        fs::write(dir.join("a.txt"), b"first").unwrap();
+        fs::write(dir.join("b.txt"), b"second").unwrap();
+        let mut receiver = Receiver::bind(0).unwrap().quiet();
+        let port = match &receiver.source {
+            Source::Listener(listener, _) => listener.local_addr().unwrap().port(),
+            Source::Dial(..) => unreachable!(),
+        };
+        let out = dir.join("out");
+        let received = thread::spawn(move || receiver.accept()?.output("batch").receive_to(&out));
+        Sender::dial("127.0.0.1", port)
+            .compress(parse_codec("deflate").unwrap())
+            .limit(64 * 1024 * 1024)
+            .quiet()
+            .send_files(&[dir.join("a.txt"), dir.join("b.txt")])
+            .unwrap();
+        received.join().unwrap().unwrap();
+        assert_eq!(fs::read(dir.join("out/batch/a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(dir.join("out/batch/b.txt")).unwrap(), b"second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn builders_refuse_what_cannot_work_before_connecting() {
+        let usage = |r: Result<(), DftpError>| matches!(r, Err(DftpError::Usage(_)));
+        assert!(usage(Sender::new("host:0").send_file("a.iso")));
+        assert!(usage(Sender::dial("localhost", 9).streams(0).send_file("a.iso")));
+        assert!(usage(Sender::dial("localhost", 9).streams(MAX_STREAMS + 1).send_file("a.iso")));
+        assert!(usage(Sender::dial("localhost", 9).send_files::<&str>(&[])));
+        assert!(usage(Sender::dial("localhost", 9).send_file("stdin")));
+        assert!(usage(Sender::dial("localhost", 9).streams(2).send_stdin()));
+        // the secrets are for whichever end dials
+        assert!(usage(Sender::dial("localhost", 9).encrypt().send_file("a.iso")));
+        assert!(usage(Sender::listen(0).pairing_code("1-a-b").send_file("a.iso")));
+        assert!(usage(Sender::dial("localhost", 9).tls().send_file("a.iso")));
+        assert!(matches!(Receiver::dial("localhost", 9).fingerprint([0u8; 32]).tls().encrypt().accept(), Err(DftpError::Usage(_))));
+    }
+}
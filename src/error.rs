@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// what went wrong, sorted by what can be done about it. the message says
+// what dftp was doing at the time, the io error underneath is the source.
+#[derive(Debug)]
+pub enum DftpError {
+    Usage(String),                      // asked for something that makes no sense
+    Io(String, Option<io::Error>),      // reading or writing local files
+    Network(String, Option<io::Error>), // connecting, or the connection went away
+    Timeout(String),                    // the peer did not show up or answer in time
+    Protocol(String),                   // the peer said something it should not have
+    Incompatible(String),               // the ends cannot agree on version, codec or transport
+    Declined(String),                   // the peer or the user turned the transfer down
+    Auth(String),                       // pairing or the certificate check failed
+    Integrity(String),                  // the data arrived but does not match its digest
+}
+
+impl DftpError {
+    // what the dftp command exits with, 1 is left for anything else
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DftpError::Usage(_) => 2,
+            DftpError::Io(..) => 3,
+            DftpError::Network(..) => 4,
+            DftpError::Timeout(_) => 5,
+            DftpError::Protocol(_) => 6,
+            DftpError::Incompatible(_) => 7,
+            DftpError::Declined(_) => 8,
+            DftpError::Auth(_) => 9,
+            DftpError::Integrity(_) => 10,
+        }
+    }
+
+    // the same error, with the message reworded to say more about what
+    // was going on
+    pub fn context(self, reword: impl FnOnce(&str) -> String) -> DftpError {
+        match self {
+            DftpError::Usage(m) => DftpError::Usage(reword(&m)),
+            DftpError::Io(m, e) => DftpError::Io(reword(&m), e),
+            DftpError::Network(m, e) => DftpError::Network(reword(&m), e),
+            DftpError::Timeout(m) => DftpError::Timeout(reword(&m)),
+            DftpError::Protocol(m) => DftpError::Protocol(reword(&m)),
+            DftpError::Incompatible(m) => DftpError::Incompatible(reword(&m)),
+            DftpError::Declined(m) => DftpError::Declined(reword(&m)),
+            DftpError::Auth(m) => DftpError::Auth(reword(&m)),
+            DftpError::Integrity(m) => DftpError::Integrity(reword(&m)),
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            DftpError::Usage(m) |
+            DftpError::Io(m, _) |
+            DftpError::Network(m, _) |
+            DftpError::Timeout(m) |
+            DftpError::Protocol(m) |
+            DftpError::Incompatible(m) |
+            DftpError::Declined(m) |
+            DftpError::Auth(m) |
+            DftpError::Integrity(m) => m,
+        }
+    }
+}
+
+impl fmt::Display for DftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl Error for DftpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DftpError::Io(_, Some(e)) | DftpError::Network(_, Some(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the error followed by everything under it, for showing to a person
+pub fn describe(e: &dyn Error) -> String {
+    let mut s = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        s = format!("{}: {}", s, cause);
+        source = cause.source();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_follow_the_readme() {
+        let refused = || Some(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        let errors = [
+            (DftpError::Usage("a".to_string()), 2),
+            (DftpError::Io("a".to_string(), None), 3),
+            (DftpError::Network("a".to_string(), refused()), 4),
+            (DftpError::Timeout("a".to_string()), 5),
+            (DftpError::Protocol("a".to_string()), 6),
+            (DftpError::Incompatible("a".to_string()), 7),
+            (DftpError::Declined("a".to_string()), 8),
+            (DftpError::Auth("a".to_string()), 9),
+            (DftpError::Integrity("a".to_string()), 10),
+        ];
+        for (e, code) in errors {
+            assert_eq!(e.exit_code(), code, "{:?}", e);
+            // rewording keeps the kind, and with it the exit code
+            let e = e.context(|m| format!("While sending: {}", m));
+            assert_eq!(e.exit_code(), code, "{:?}", e);
+            assert_eq!(e.to_string(), "While sending: a");
+        }
+    }
+
+    #[test]
+    fn describe_follows_the_sources() {
+        let e = DftpError::Network("Could not connect to host".to_string(), Some(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")))
+            .context(|m| format!("{} on port 8086", m));
+        assert_eq!(describe(&e), "Could not connect to host on port 8086: refused");
+        assert_eq!(describe(&DftpError::Timeout("No answer".to_string())), "No answer");
+    }
+}
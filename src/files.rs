@@ -1,30 +1,357 @@
 use std::io;
-use std::io::{Write, BufReader, BufWriter, BufRead};
-use std::fs::File;
+use std::io::{Write, BufReader, BufWriter, BufRead, Seek, SeekFrom};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::DftpError;
+use crate::sparse::looks_sparse;
 
 pub fn defer_kind(filename: &String) -> bool {
-    return !(filename == "stdin");
+    filename != "stdin"
 }
 
-pub fn build_file_reader(filename:&String) -> Result<Box<dyn BufRead>, String>{
+pub fn build_file_reader(filename:&String) -> Result<Box<dyn BufRead>, DftpError>{
     if filename == "stdin" {
         return Ok(Box::new(BufReader::new(io::stdin())));
     }
     let file = match File::open(filename) {
         Ok(f) => f,
-        Err(m) => { return Err(format!("Error opening file {} for reading: {}", filename, m.to_string())); }
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for reading", filename), Some(m))); }
     };
     Ok(Box::new(BufReader::new(file)))
 }
 
-pub fn build_file_writer(filename:&String) -> Result<Box<dyn Write>, String>{
+pub fn build_file_writer(filename:&String) -> Result<Box<dyn Write>, DftpError>{
     if filename == "stdin" {
         return Ok(Box::new(BufWriter::new(io::stdout())));
     }
     let file = match File::create(filename) {
         Ok(f) => f,
-        Err(m) => { return Err(format!("Error opening file {} for writing: {}", filename, m.to_string())); }
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for writing", filename), Some(m))); }
     };
     Ok(Box::new(BufWriter::new(file)))
 }
 
+// opens a file to read it from offset on
+pub fn read_file_from(filename: &Path, offset: u64) -> Result<File, DftpError>{
+    let mut file = match File::open(filename) {
+        Ok(f) => f,
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for reading", filename.display()), Some(m))); }
+    };
+    if let Err(m) = file.seek(SeekFrom::Start(offset)) {
+        return Err(DftpError::Io(format!("Error seeking in file {}", filename.display()), Some(m)));
+    }
+    Ok(file)
+}
+
+// opens a file to write it from offset on. an existing one keeps its first
+// offset bytes and anything past that is dropped, at 0 it starts afresh.
+pub fn write_file_from(filename: &Path, offset: u64) -> Result<File, DftpError>{
+    let opened = if offset == 0 { File::create(filename) } else { OpenOptions::new().write(true).open(filename) };
+    let mut file = match opened {
+        Ok(f) => f,
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for writing", filename.display()), Some(m))); }
+    };
+    if let Err(m) = file.set_len(offset).and_then(|_| file.seek(SeekFrom::Start(offset))) {
+        return Err(DftpError::Io(format!("Error seeking in file {}", filename.display()), Some(m)));
+    }
+    Ok(file)
+}
+
+pub fn make_dir(dir: &Path) -> Result<(), DftpError>{
+    match fs::create_dir_all(dir) {
+        Ok(_) => Ok(()),
+        Err(m) => Err(DftpError::Io(format!("Error while creating directory {}", dir.display()), Some(m))),
+    }
+}
+
+pub struct LocalEntry {
+    pub path: PathBuf,  // where to read it from
+    pub name: String,   // '/' separated name relative to the transfer root
+    pub is_dir: bool,
+    pub length: u64,    // for a directory, the size of all files below it. for a link, of its target
+    pub link: Option<Link>,
+    pub sparse: bool,   // has holes, sent as its data extents
+}
+
+pub enum Link {
+    Symbolic(String), // what the link reads
+    Hard(String),     // name of the entry sent earlier that has the same data
+}
+
+impl Link {
+    pub fn target(&self) -> &str {
+        match self {
+            Link::Symbolic(t) | Link::Hard(t) => t,
+        }
+    }
+}
+
+// lists the entries to send for a path. a plain file yields a single entry,
+// a directory yields itself followed by its whole tree, parents first.
+// the path itself is always followed if it is a symlink, links inside the
+// tree only with follow. files seen under another name before become hard
+// links to it.
+pub fn collect_entries(filename: &str, follow: bool, seen: &mut HashMap<(u64, u64), String>) -> Result<Vec<LocalEntry>, DftpError> {
+    let root = Path::new(filename);
+    // "." and friends have no file name of their own
+    let canonical = fs::canonicalize(root).ok();
+    let name = root.file_name().or(canonical.as_ref().and_then(|c| c.file_name()));
+    let name = match name.and_then(|n| n.to_str()) {
+        Some(n) => n.to_string(),
+        None => { return Err(DftpError::Usage(format!("Cannot determine a name to send {} as", filename))); }
+    };
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    walk_entry(root.to_path_buf(), name, true, follow, seen, &mut visited, &mut entries)?;
+    Ok(entries)
+}
+
+// seen maps the device and inode of every file with more than one name to
+// the name it was sent under first. visited holds the device and inode of
+// every directory walked so far, a followed link back into one is a loop.
+fn walk_entry(path: PathBuf, name: String, top: bool, follow: bool, seen: &mut HashMap<(u64, u64), String>, visited: &mut HashSet<(u64, u64)>, entries: &mut Vec<LocalEntry>) -> Result<(), DftpError> {
+    let meta = if top || follow { fs::metadata(&path) } else { fs::symlink_metadata(&path) };
+    let meta = match meta {
+        Ok(m) => m,
+        Err(m) => { return Err(DftpError::Io(format!("Cannot stat {}", path.display()), Some(m))); }
+    };
+    if meta.file_type().is_symlink() {
+        let target = match fs::read_link(&path) {
+            Ok(t) => t,
+            Err(m) => { return Err(DftpError::Io(format!("Cannot read link {}", path.display()), Some(m))); }
+        };
+        match target.into_os_string().into_string() {
+            Ok(t) => entries.push(LocalEntry{ path, name, is_dir: false, length: t.len() as u64, link: Some(Link::Symbolic(t)), sparse: false }),
+            Err(t) => { eprintln!("WARNING: skipping {}, link to {:?} is not valid utf8", path.display(), t); }
+        }
+        return Ok(());
+    }
+    if meta.is_file() {
+        if meta.nlink() > 1 {
+            if let Some(first) = seen.get(&(meta.dev(), meta.ino())) {
+                let first = first.clone();
+                entries.push(LocalEntry{ path, name, is_dir: false, length: first.len() as u64, link: Some(Link::Hard(first)), sparse: false });
+                return Ok(());
+            }
+            seen.insert((meta.dev(), meta.ino()), name.clone());
+        }
+        entries.push(LocalEntry{ path, name, is_dir: false, length: meta.len(), link: None, sparse: looks_sparse(&meta) });
+        return Ok(());
+    }
+    if !meta.is_dir() {
+        eprintln!("WARNING: skipping {}, not a regular file, directory or symlink", path.display());
+        return Ok(());
+    }
+    if !visited.insert((meta.dev(), meta.ino())) {
+        eprintln!("WARNING: skipping {}, directory has been walked already", path.display());
+        return Ok(());
+    }
+    let listing = match fs::read_dir(&path) {
+        Ok(l) => l,
+        Err(m) => { return Err(DftpError::Io(format!("Cannot list directory {}", path.display()), Some(m))); }
+    };
+    let mut children = Vec::new();
+    for child in listing {
+        let child = match child {
+            Ok(c) => c,
+            Err(m) => { return Err(DftpError::Io(format!("Cannot list directory {}", path.display()), Some(m))); }
+        };
+        match child.file_name().into_string() {
+            Ok(n) => children.push(n),
+            Err(n) => { eprintln!("WARNING: skipping {:?}, name is not valid utf8", n); }
+        }
+    }
+    children.sort();
+    let at = entries.len();
+    entries.push(LocalEntry{ path: path.clone(), name: name.clone(), is_dir: true, length: 0, link: None, sparse: false });
+    for child in children {
+        walk_entry(path.join(&child), format!("{}/{}", name, child), false, follow, seen, visited, entries)?;
+    }
+    entries[at].length = entries[at + 1..].iter().filter(|e| !e.is_dir && e.link.is_none()).map(|e| e.length).sum();
+    Ok(())
+}
+
+pub const PARTIAL_SUFFIX: &str = ".dftp-partial";
+
+// where a file is received to until it is complete and checked. it sits
+// next to the target so moving it there is a rename on one file system.
+pub fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    target.with_file_name(name)
+}
+
+// where a session receives target when someone else holds its partial
+// file. nothing looks for these to resume from.
+pub fn session_partial_path(target: &Path, tag: &str) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}{}", tag, PARTIAL_SUFFIX));
+    target.with_file_name(name)
+}
+
+// opens a partial file, making it if need be, and takes an exclusive lock
+// on it for as long as the returned file is open. None if another session
+// holds it already.
+pub fn lock_partial(partial: &Path) -> Result<Option<File>, DftpError> {
+    let opened = OpenOptions::new().read(true).write(true).create(true).custom_flags(libc::O_NOFOLLOW).open(partial);
+    let file = match opened {
+        Ok(f) => f,
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for writing", partial.display()), Some(m))); }
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let m = io::Error::last_os_error();
+        if m.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(DftpError::Io(format!("Error locking {}", partial.display()), Some(m)));
+    }
+    Ok(Some(file))
+}
+
+// puts a received and checked file in place of target. it goes to disk
+// first, so a crash right after cannot leave target half written.
+pub fn commit_partial(partial: &Path, target: &Path) -> Result<(), DftpError> {
+    if let Err(m) = File::open(partial).and_then(|f| f.sync_all()) {
+        return Err(DftpError::Io(format!("Error while syncing {}", partial.display()), Some(m)));
+    }
+    if let Err(m) = fs::rename(partial, target) {
+        return Err(DftpError::Io(format!("Error while moving {} into place", partial.display()), Some(m)));
+    }
+    // the rename is an entry in the directory, which has to go to disk too
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let _ = File::open(parent).and_then(|d| d.sync_all());
+    }
+    Ok(())
+}
+
+pub const MAX_NAME_LEN: usize = 4096;     // whole relative name, in bytes
+pub const MAX_COMPONENT_LEN: usize = 255; // a single path component
+
+// names come off the wire, so nothing in them may point outside of the
+// output directory. only plain relative names of normal components pass.
+pub fn check_name(name: &str) -> Result<(), DftpError> {
+    if name.is_empty() {
+        return Err(DftpError::Protocol("empty file name".to_string()));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(DftpError::Protocol(format!("file name longer than {} bytes", MAX_NAME_LEN)));
+    }
+    if name.contains('\0') {
+        return Err(DftpError::Protocol("file name contains a NUL byte".to_string()));
+    }
+    if name.starts_with('/') || name.contains('\\') {
+        return Err(DftpError::Protocol(format!("{} is not a relative name", name)));
+    }
+    for component in name.split('/') {
+        match component {
+            "" | "." | ".." => { return Err(DftpError::Protocol(format!("{} has an invalid path component", name))); }
+            c if c.len() > MAX_COMPONENT_LEN => {
+                return Err(DftpError::Protocol(format!("{} has a component longer than {} bytes", name, MAX_COMPONENT_LEN)));
+            }
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// maps a received entry name to where it gets written, below dir. if an
+// output name was given it replaces the top level component of the entry,
+// unless the session carries several top level entries in which case it is
+// the parent directory. the name is expected to have passed check_name.
+pub fn output_path(dir: &str, output: &str, name: &str, multi: bool) -> PathBuf {
+    let mut path = PathBuf::from(dir);
+    if multi && output != "stdin" {
+        path.push(output);
+    }
+    for (i, component) in name.split('/').enumerate() {
+        if i == 0 && output != "stdin" && !multi {
+            path.push(output);
+        } else {
+            path.push(component);
+        }
+    }
+    path
+}
+
+// the directory every received entry has to end up in
+pub fn output_root(dir: &str, output: &str, multi: bool) -> PathBuf {
+    let mut root = PathBuf::from(dir);
+    if output == "stdin" {
+        return root;
+    }
+    root.push(output);
+    if multi {
+        return root;
+    }
+    match root.parent() {
+        Some(p) => p.to_path_buf(),
+        None => root,
+    }
+}
+
+// resolves whatever part of path already exists and makes sure it stays
+// below root, so a symlink sitting in the output cannot redirect a write.
+// the components that do not exist yet are plain names.
+pub fn check_confined(root: &Path, path: &Path) -> Result<(), DftpError> {
+    let root = if root.as_os_str().is_empty() { Path::new(".") } else { root };
+    let root = match fs::canonicalize(root) {
+        Ok(r) => r,
+        Err(m) => { return Err(DftpError::Io(format!("Cannot resolve output directory {}", root.display()), Some(m))); }
+    };
+    let mut existing = path;
+    let resolved = loop {
+        if fs::symlink_metadata(existing).is_ok() {
+            match fs::canonicalize(existing) {
+                Ok(r) => break r,
+                Err(m) => { return Err(DftpError::Io(format!("Cannot resolve {}", existing.display()), Some(m))); }
+            }
+        }
+        existing = match existing.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => { break fs::canonicalize(".").unwrap_or_default(); }
+        };
+    };
+    if !resolved.starts_with(&root) {
+        return Err(DftpError::Protocol(format!("{} resolves to {}, outside of {}", path.display(), resolved.display(), root.display())));
+    }
+    Ok(())
+}
+
+// a symlink about to be made at path may only lead somewhere below root.
+// the target has to be relative, with any .. in front: those may not climb
+// higher than the directory the link sits in is below root, and the names
+// after them are either new or links that went through here before.
+pub fn check_link(root: &Path, path: &Path, target: &str) -> Result<(), DftpError> {
+    if target.is_empty() || target.contains('\0') || target.len() > MAX_NAME_LEN {
+        return Err(DftpError::Protocol(format!("{} is not a valid link target", target)));
+    }
+    let mut up = 0;
+    let mut down = false;
+    for component in Path::new(target).components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir if !down => { up += 1; },
+            Component::Normal(_) => { down = true; },
+            _ => { return Err(DftpError::Protocol(format!("{} -> {} may lead out of the output directory", path.display(), target))); }
+        }
+    }
+    let root = if root.as_os_str().is_empty() { Path::new(".") } else { root };
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let (root, parent) = match (fs::canonicalize(root), fs::canonicalize(parent)) {
+        (Ok(r), Ok(p)) => (r, p),
+        (Err(m), _) | (_, Err(m)) => { return Err(DftpError::Io(format!("Cannot resolve {}", parent.display()), Some(m))); }
+    };
+    let depth = match parent.strip_prefix(&root) {
+        Ok(p) => p.components().count(),
+        Err(_) => { return Err(DftpError::Protocol(format!("{} is outside of {}", parent.display(), root.display()))); }
+    };
+    if up > depth {
+        return Err(DftpError::Protocol(format!("{} -> {} leads out of {}", path.display(), target, root.display())));
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! dftp, the direct file transfer protocol, as a library.
+//!
+//! [`Sender`] and [`Receiver`] do what the `dftp` command does:
+//!
+//! ```no_run
+//! use dftp::{Receiver, Sender};
+//!
+//! # fn main() -> Result<(), dftp::DftpError> {
+//! // on one end
+//! let mut receiver = Receiver::bind(8086)?;
+//! receiver.accept()?.receive_to("/srv/inbox")?;
+//!
+//! // on the other
+//! Sender::new("192.168.1.5:8086")
+//!     .compress(dftp::parse_codec("zstd")?)
+//!     .send_file("a.iso")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything fails with a [`DftpError`], sorted by what went wrong.
+//!
+//! The modules underneath are there for speaking the protocol directly.
+//! Every message on the wire is a [`protocol::TcpShovable`]. With the
+//! `async` feature, `nonblocking` has the same sessions on tokio.
+
+// messages are made by filling in a default one, field by field
+#![allow(clippy::field_reassign_with_default)]
+
+pub mod protocol;
+pub mod network;
+pub mod compress;
+pub mod crypto;
+pub mod tls;
+pub mod discovery;
+pub mod parallel;
+pub mod limit;
+pub mod sparse;
+pub mod daemon;
+pub mod error;
+pub mod duplex;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+mod files;
+mod endpoint;
+
+pub use crate::endpoint::{Sender, Receiver, Incoming};
+pub use crate::compress::{parse_codec, Codec};
+pub use crate::error::DftpError;
@@ -0,0 +1,135 @@
+use std::io::{Write, Error};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::DftpError;
+
+// RATE[K|M|G] in bytes per second, e.g. "20M". the suffixes are powers of 1024.
+pub fn parse_rate(spec: &str) -> Result<u64, DftpError> {
+    let (digits, unit) = match spec.chars().last() {
+        Some('k') | Some('K') => (&spec[..spec.len() - 1], 1024),
+        Some('m') | Some('M') => (&spec[..spec.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&spec[..spec.len() - 1], 1024 * 1024 * 1024),
+        _ => (spec, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => n.checked_mul(unit).ok_or_else(|| DftpError::Usage(format!("{} is too large", spec))),
+        _ => Err(DftpError::Usage(format!("{} is not a rate. Try something like 512K or 20M", spec))),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// everything the sender puts on the wire, over all of its connections.
+// it counts the bytes for the speed shown while sending, and with a limit
+// holds them back in a token bucket.
+pub struct Uplink {
+    sent: AtomicU64,
+    rate: u64,
+    bucket: Option<Mutex<Bucket>>,
+}
+
+impl Uplink {
+    pub fn new(limit: Option<u64>) -> Arc<Uplink> {
+        Arc::new(Uplink{
+            sent: AtomicU64::new(0),
+            rate: limit.unwrap_or(0),
+            bucket: limit.map(|_| Mutex::new(Bucket{ tokens: 0.0, last: Instant::now() })),
+        })
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    // a tenth of a second worth of bytes may go out in one burst
+    fn capacity(&self) -> f64 {
+        std::cmp::max(self.rate / 10, 1024) as f64
+    }
+
+    // blocks until some of want may go out, returns how much
+    fn take(&self, want: usize) -> usize {
+        let bucket = match self.bucket.as_ref() {
+            Some(b) => b,
+            None => { return want; }
+        };
+        let need = f64::min(want as f64, self.capacity());
+        loop {
+            let wait = {
+                let mut b = bucket.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(b.last).as_secs_f64() * self.rate as f64;
+                b.tokens = f64::min(b.tokens + refill, self.capacity());
+                b.last = now;
+                if b.tokens >= need {
+                    let n = f64::min(want as f64, b.tokens.floor()) as usize;
+                    b.tokens -= n as f64;
+                    return n;
+                }
+                (need - b.tokens) / self.rate as f64
+            };
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+struct LimitedWriter {
+    inner: Box<dyn Write>,
+    uplink: Arc<Uplink>,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if data.is_empty() { return Ok(0); }
+        let n = self.uplink.take(data.len());
+        let n = self.inner.write(&data[..n])?;
+        self.uplink.sent.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+pub fn wrap_limiter(writer: Box<dyn Write>, uplink: &Arc<Uplink>) -> Box<dyn Write> {
+    Box::new(LimitedWriter{ inner: writer, uplink: uplink.clone() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_take_binary_suffixes() {
+        for (spec, rate) in [("512", 512), ("512K", 512 * 1024), ("20m", 20 * 1024 * 1024), ("1G", 1024 * 1024 * 1024)] {
+            assert_eq!(parse_rate(spec).unwrap(), rate, "{}", spec);
+        }
+        for spec in ["", "0", "M", "1.5M", "-1K", "20MB", "99999999999999G"] {
+            assert!(matches!(parse_rate(spec), Err(DftpError::Usage(_))), "{} let through", spec);
+        }
+    }
+
+    #[test]
+    fn connections_share_one_limit() {
+        let uplink = Uplink::new(Some(1024 * 1024));
+        let start = Instant::now();
+        let writers: Vec<_> = (0..2).map(|_| {
+            let uplink = uplink.clone();
+            thread::spawn(move || wrap_limiter(Box::new(std::io::sink()), &uplink).write_all(&[0u8; 150 * 1024]).unwrap())
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        // 300 KiB at 1 MiB/s, starting from an empty bucket
+        let took = start.elapsed();
+        assert!(took >= Duration::from_millis(250), "took only {:?}", took);
+        assert!(took < Duration::from_secs(3), "took {:?}", took);
+        assert_eq!(uplink.sent(), 300 * 1024);
+    }
+}
@@ -13,26 +13,99 @@ use std::env;
 use std::process::exit;
 
 mod cmd;
-mod network;
-mod files;
-mod protocol;
-mod compress;
 
-use crate::cmd::{parse_args};
-use crate::protocol::*;
+use crate::cmd::{parse_args, Args, DIR_SEND};
+use dftp::{DftpError, Receiver, Sender};
+use dftp::error::describe;
+use dftp::discovery::print_receivers;
+use dftp::protocol::prepare_security;
 
 fn main(){
     let argv:Vec<String> = env::args().collect();
 
-    let (port, direction, filename, addrstring, compress) = match parse_args(argv){
+    let args = match parse_args(argv){
         Ok(s) => s,
-        Err(m) => { eprintln!("Error while parsing input arguments:\n  {}", m); exit(1); }
+        Err(m) => { eprintln!("Error while parsing input arguments:\n  {}", m); exit(2); }
     };
 
-    if direction == 1 {
-        send(port, filename, addrstring, compress); 
-    }else {
-        recv(port, filename, compress);
+    let result = if args.discover {
+        print_receivers()
+    } else if args.direction == DIR_SEND {
+        send(args)
+    } else if args.daemon {
+        serve(args)
+    } else {
+        recv(args)
+    };
+    if let Err(e) = result {
+        eprintln!("{}", describe(&e));
+        exit(e.exit_code());
+    }
+}
+
+fn send(args: Args) -> Result<(), DftpError> {
+    let mut sender = if args.listen {
+        Sender::listen(args.port as u16)
+    } else if args.host.starts_with('@') {
+        Sender::new(&args.host)
+    } else {
+        Sender::dial(&args.host, args.peer_port)
+    };
+    if !args.listen && args.port != -1 {
+        sender = sender.bind_port(args.port as u16);
+    }
+    sender = sender.compress(args.codec).streams(args.streams).preserve(args.preserve).follow_links(args.follow);
+    if let Some(rate) = args.limit {
+        sender = sender.limit(rate);
+    }
+    if args.encrypt {
+        sender = sender.encrypt();
+    }
+    if let Some(code) = args.code.as_ref() {
+        sender = sender.pairing_code(code);
+    }
+    if args.tls {
+        sender = sender.tls();
+    }
+    if let Some(fingerprint) = args.fingerprint {
+        sender = sender.fingerprint(fingerprint);
+    }
+    if args.filenames[0] == "stdin" {
+        sender.send_stdin()
+    } else {
+        sender.send_files(&args.filenames)
     }
 }
 
+fn recv(args: Args) -> Result<(), DftpError> {
+    let mut receiver = if args.listen {
+        Receiver::bind(args.port as u16).map_err(|e| e.context(|m| format!("Error while starting stream:\n  {}", m)))?
+    } else {
+        Receiver::dial(&args.host, args.peer_port)
+    };
+    if let Some(name) = args.announce.as_ref() {
+        receiver = receiver.announce(name);
+    }
+    if args.encrypt {
+        receiver = receiver.encrypt();
+    }
+    if let Some(code) = args.code.as_ref() {
+        receiver = receiver.pairing_code(code);
+    }
+    if args.tls {
+        receiver = receiver.tls();
+    }
+    if let Some(fingerprint) = args.fingerprint {
+        receiver = receiver.fingerprint(fingerprint);
+    }
+    let mut incoming = receiver.accept()?.output(&args.filenames[0]).preserve(args.preserve);
+    if !args.yes {
+        incoming = incoming.ask();
+    }
+    incoming.receive_to(&args.dir)
+}
+
+fn serve(args: Args) -> Result<(), DftpError> {
+    let security = prepare_security(true, args.encrypt, None, args.tls, None)?;
+    dftp::daemon::serve(args.port, args.dir, args.announce, security, args.max_connections, args.preserve)
+}
@@ -1,48 +1,266 @@
 extern crate net2;
 
-use std::net::TcpStream;
+use std::io::{Read, Write, Error};
+use std::io::ErrorKind::WouldBlock;
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(any(test, fuzzing))]
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use net2::TcpBuilder;
 
-pub fn build_send_stream(port:i32, addrstr:String) -> Result<TcpStream, String>{
-    let builder = match TcpBuilder::new_v4(){
-        Ok(s) => s,
-        Err(m)=> {return Err(m.to_string()); }
-    };
-    if port != -1 {
-        match builder.bind(("127.0.0.1", port as u16)) {
-            Ok(_) => {},
-            Err(m) => { return Err(format!("Cannot bind to port {}:\n    {}", port, m.to_string())); }
+use crate::discovery::find_receiver;
+#[cfg(any(test, fuzzing))]
+use crate::duplex::{pair, DuplexStream};
+use crate::error::{describe, DftpError};
+
+// a connection to the peer, plain or with a transport layer on top.
+// cloning gives another handle on the same connection.
+pub trait PeerStream: Read + Write + Send {
+    fn try_clone_stream(&self) -> Result<Box<dyn PeerStream>, Error>;
+}
+
+impl PeerStream for TcpStream {
+    fn try_clone_stream(&self) -> Result<Box<dyn PeerStream>, Error> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+// how an end reaches its peer. either end of a transfer can do either,
+// the handshake decides who sends.
+#[derive(Clone)]
+pub enum Connect {
+    Listen,
+    Dial(String, u16), // host, port
+    Discover(String),  // name a receiver announces itself with
+}
+
+// how the first connection came about, so more connections to the same
+// peer can follow it
+pub enum Rendezvous {
+    Listener(TcpListener),
+    Dialed(SocketAddr),
+    // the in-memory kind, see duplex. only tests and fuzzing need it.
+    #[cfg(any(test, fuzzing))]
+    MemoryDial(mpsc::Sender<DuplexStream>),
+    #[cfg(any(test, fuzzing))]
+    MemoryListen(mpsc::Receiver<DuplexStream>),
+}
+
+const JOIN_WAIT: Duration = Duration::from_secs(10);
+
+impl Rendezvous {
+    pub fn another_stream(&self) -> Result<Box<dyn PeerStream>, DftpError> {
+        match self {
+            Rendezvous::Listener(listener) => Ok(Box::new(accept_within(listener, JOIN_WAIT)?)),
+            // the port given with -p is taken by the first connection
+            Rendezvous::Dialed(addr) => Ok(Box::new(connect_from(-1, addr)?)),
+            #[cfg(any(test, fuzzing))]
+            Rendezvous::MemoryDial(peer) => {
+                let (ours, theirs) = pair();
+                if peer.send(theirs).is_err() {
+                    return Err(DftpError::Network("Cannot connect: the other end is gone".to_string(), None));
+                }
+                Ok(Box::new(ours))
+            },
+            #[cfg(any(test, fuzzing))]
+            Rendezvous::MemoryListen(peer) => match peer.recv_timeout(JOIN_WAIT) {
+                Ok(s) => Ok(Box::new(s)),
+                Err(_) => Err(DftpError::Timeout(format!("the peer did not connect again within {} seconds", JOIN_WAIT.as_secs()))),
+            },
         }
     }
-    /*
-    match builder.connect(addrstr) {
-        Ok(s) => s,
-        Err(_) => { return Err(3); }
-    }; */
-    let stream = match builder.connect(addrstr) { //builder.to_tcp_stream() {
-        Ok(s) => s,
-        Err(m) => { return Err(format!("connection failed: {}", m.to_string())); }
+}
+
+pub fn build_stream(port:i32, connect: &Connect) -> Result<(TcpStream, Rendezvous), DftpError>{
+    match connect {
+        Connect::Listen => build_listen_stream(port),
+        Connect::Dial(host, peer_port) => build_dial_stream(port, host, *peer_port),
+        Connect::Discover(name) => {
+            let addr = find_receiver(name)?;
+            let stream = connect_from(port, &addr)?;
+            eprintln!("Connected to {}", addr);
+            Ok((stream, Rendezvous::Dialed(addr)))
+        },
+    }
+}
+
+// splits ADDR into a host and a port. the host is resolved later, so
+// anything that is not obviously malformed goes through here.
+//   host, host:port, v4:port, v6, [v6], [v6]:port, [v6%iface]:port
+pub fn split_addr(addr: &str) -> Result<(String, u16), DftpError> {
+    let invalid = || DftpError::Usage(format!("Invalid address {}", addr));
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else {
+        match addr.matches(':').count() {
+            0 => (addr, None),
+            1 => {
+                let (host, port) = addr.split_once(':').expect("there is a colon");
+                (host, Some(port))
+            },
+            _ => (addr, None), // a bare v6 address
+        }
     };
-    return Ok(stream);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(p) => match p.parse::<u16>() {
+            Ok(p) if p != 0 => p,
+            _ => { return Err(invalid()); }
+        },
+        None => 8086,
+    };
+    Ok((host.to_string(), port))
 }
 
-pub fn build_recv_stream(port:i32) -> Result<TcpStream, String>{
-    let builder = match TcpBuilder::new_v4(){
-        Ok(s) => s,
-        Err(m)=> {return Err(m.to_string()); }
+// resolves host and tries every address it has until one connects
+pub fn build_dial_stream(port:i32, host: &str, peer_port: u16) -> Result<(TcpStream, Rendezvous), DftpError>{
+    let addrs: Vec<SocketAddr> = match (host, peer_port).to_socket_addrs() {
+        Ok(a) => a.collect(),
+        Err(m) => { return Err(DftpError::Network(format!("Cannot resolve {}", host), Some(m))); }
     };
-    let builder = match builder.bind(("0.0.0.0", port as u16)){
-        Ok(s) => s,
-        Err(m) => {return Err(format!("Cannot bind to port {}: {}", port, m.to_string())); }
+    if addrs.is_empty() {
+        return Err(DftpError::Network(format!("Cannot resolve {}: no addresses found", host), None));
+    }
+    let mut failures = Vec::new();
+    for addr in addrs.iter() {
+        match connect_from(port, addr) {
+            Ok(s) => {
+                eprintln!("Connected to {}", addr);
+                return Ok((s, Rendezvous::Dialed(*addr)));
+            },
+            Err(m) => failures.push(format!("{}: {}", addr, describe(&m))),
+        }
+    }
+    Err(DftpError::Network(format!("connection failed:\n    {}", failures.join("\n    ")), None))
+}
+
+fn connect_from(port:i32, addr: &SocketAddr) -> Result<TcpStream, DftpError>{
+    let (builder, any) = match addr {
+        SocketAddr::V4(_) => (TcpBuilder::new_v4(), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        SocketAddr::V6(_) => (TcpBuilder::new_v6(), IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
     };
-    let listener = match builder.listen(10) {
+    let builder = match builder {
         Ok(s) => s,
-        Err(m) => { return Err(format!("Listening on port {} failed: {}", port, m.to_string())); }
+        Err(m)=> {return Err(DftpError::Network("Cannot open a socket".to_string(), Some(m))); }
     };
+    if port != -1 {
+        match builder.bind((any, port as u16)) {
+            Ok(_) => {},
+            Err(m) => { return Err(DftpError::Network(format!("Cannot bind to port {}", port), Some(m))); }
+        }
+    }
+    match builder.connect(addr) {
+        Ok(s) => Ok(s),
+        Err(m) => Err(DftpError::Network("Cannot connect".to_string(), Some(m))),
+    }
+}
+
+pub fn build_listen_stream(port:i32) -> Result<(TcpStream, Rendezvous), DftpError>{
+    let listener = build_listener(port)?;
     let (recvr, _) = match listener.accept() {
         Ok(s) => s,
-        Err(m) => { return Err(format!("Cannot accept peer: {}", m.to_string())); }
+        Err(m) => { return Err(DftpError::Network("Cannot accept peer".to_string(), Some(m))); }
     };
-    Ok(recvr)
+    Ok((recvr, Rendezvous::Listener(listener)))
+}
+
+// listens on both v4 and v6 where the system allows it, v4 only otherwise
+pub fn build_listener(port:i32) -> Result<TcpListener, DftpError>{
+    let builder = match dual_stack_builder(port) {
+        Ok(b) => b,
+        Err(_) => {
+            let builder = match TcpBuilder::new_v4(){
+                Ok(s) => s,
+                Err(m)=> {return Err(DftpError::Network("Cannot open a socket".to_string(), Some(m))); }
+            };
+            match builder.bind(("0.0.0.0", port as u16)){
+                Ok(_) => builder,
+                Err(m) => {return Err(DftpError::Network(format!("Cannot bind to port {}", port), Some(m))); }
+            }
+        }
+    };
+    match builder.listen(10) {
+        Ok(l) => Ok(l),
+        Err(m) => Err(DftpError::Network(format!("Listening on port {} failed", port), Some(m))),
+    }
+}
+
+// gives up on a peer that does not show up in time instead of waiting
+// for it forever
+fn accept_within(listener: &TcpListener, wait: Duration) -> Result<TcpStream, DftpError>{
+    let deadline = Instant::now() + wait;
+    let failed = |m| DftpError::Network("Cannot accept peer".to_string(), Some(m));
+    listener.set_nonblocking(true).map_err(failed)?;
+    loop {
+        match listener.accept() {
+            Ok((s, _)) => {
+                s.set_nonblocking(false).map_err(failed)?;
+                return Ok(s);
+            },
+            Err(m) if m.kind() == WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(DftpError::Timeout(format!("the peer did not connect again within {} seconds", wait.as_secs())));
+                }
+                thread::sleep(Duration::from_millis(20));
+            },
+            Err(m) => { return Err(failed(m)); }
+        }
+    }
+}
+
+fn dual_stack_builder(port:i32) -> Result<TcpBuilder, Error>{
+    let builder = TcpBuilder::new_v6()?;
+    builder.only_v6(false)?;
+    builder.bind((Ipv6Addr::UNSPECIFIED, port as u16))?;
+    Ok(builder)
+}
+
+// the address of the peer, v4 peers on a dual stack socket are shown as v4
+pub fn peer_address(stream: &TcpStream) -> Result<SocketAddr, DftpError>{
+    match stream.peer_addr() {
+        Ok(a) => Ok(unmap_address(a)),
+        Err(m) => Err(DftpError::Network("Cannot get the peer address".to_string(), Some(m))),
+    }
+}
+
+pub fn unmap_address(addr: SocketAddr) -> SocketAddr{
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_split_into_host_and_port() {
+        for (addr, host, port) in [("myhost.local", "myhost.local", 8086), ("myhost:9000", "myhost", 9000),
+                                   ("192.168.1.5:1", "192.168.1.5", 1), ("fe80::1", "fe80::1", 8086),
+                                   ("[::1]", "::1", 8086), ("[fe80::1%eth0]:8087", "fe80::1%eth0", 8087)] {
+            assert_eq!(split_addr(addr).unwrap(), (host.to_string(), port), "{}", addr);
+        }
+        for addr in ["", ":8086", "host:", "host:0", "host:65536", "host:port", "[::1", "[::1]8086", "[]:8086"] {
+            assert!(matches!(split_addr(addr), Err(DftpError::Usage(_))), "{} let through", addr);
+        }
+    }
+
+    #[test]
+    fn mapped_v4_peers_are_shown_as_v4() {
+        let mapped: SocketAddr = "[::ffff:192.168.1.7]:8086".parse().unwrap();
+        assert_eq!(unmap_address(mapped), "192.168.1.7:8086".parse::<SocketAddr>().unwrap());
+        let v6: SocketAddr = "[fe80::1]:8086".parse().unwrap();
+        assert_eq!(unmap_address(v6), v6);
+    }
+}
@@ -0,0 +1,787 @@
+//! The sender and the receiver on tokio, for programs that already run on
+//! it. Needs the `async` cargo feature.
+//!
+//! Both speak the protocol of [`crate::protocol`] byte for byte, so either
+//! end of a transfer can be the blocking one. The messages are encoded and
+//! decoded by the same [`TcpShovable`] impls, compression uses the same
+//! chunking, negotiation, resuming and hashing are the blocking side's own
+//! code, and only the reading and writing around them is async:
+//!
+//! ```no_run
+//! use dftp::nonblocking::{handshake_send, send_session};
+//! use dftp::DftpError;
+//!
+//! # async fn run() -> Result<(), DftpError> {
+//! let mut peer = tokio::net::TcpStream::connect("192.168.1.5:8086").await
+//!     .map_err(|m| DftpError::Network("Cannot connect".to_string(), Some(m)))?;
+//! handshake_send(&mut peer).await?;
+//! send_session(peer, &["a.iso".to_string()], &dftp::parse_codec("zstd")?, true).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Sessions go over one plain connection. A peer asking for tls,
+//! encryption or extra data connections is declined during negotiation,
+//! the way a blocking receiver started without `--tls` or `-e` declines it.
+
+use std::collections::HashMap;
+use std::io::{Error, Write, SeekFrom};
+use std::io::ErrorKind::{ConnectionReset, UnexpectedEof};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{self as aio, split, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::task::spawn_blocking;
+
+use crate::compress::{chunk_len, unpack_chunk, Codec, Packer, CHUNK_HEAD, CHUNK_SIZE, CODEC_NONE};
+use crate::error::DftpError;
+use crate::files::{defer_kind, make_dir, output_root, read_file_from, write_file_from, LocalEntry};
+use crate::sparse::data_extents;
+use crate::protocol::{
+    accept_answer, apply_meta, check_stream, collect_session, entry_head, entry_target, frame_head, frame_length, is_link, link_len, needs_accept, place_link,
+    recv_file_header, recv_link, resume_send, send_end_header, send_error, session_table, session_tag, table_answer, table_verdict,
+    transport_answer, transport_verdict, wire_error,
+    ExtentMap, FileHeader, FileMeta, FileTrailer, Inbox, Incoming, Payload, ProtocolTable, ResumePoint, Simple,
+    SparseCursor, TcpShovable, WireLength,
+    FH_TYPE_DIR, FH_TYPE_END, FH_TYPE_SPARSE, TRANSFER_BUFF_SIZE,
+    SIMPLE_MSG_FH_ACC, SIMPLE_MSG_HS_ACK, SIMPLE_MSG_HS_JOIN, SIMPLE_MSG_PN_DEC,
+    SIMPLE_MSG_RECVER_ID, SIMPLE_MSG_SENDER_ID, SIMPLE_MSG_TR_PLAIN,
+};
+
+// what comes from the peer, decompressed once a codec is agreed on. it
+// never reads further than it has to, so a plain one can be thrown away
+// after a message without losing anything.
+struct Inflow<R> {
+    inner: R,
+    codec: u8,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> Inflow<R> {
+    fn new(inner: R) -> Inflow<R> {
+        Inflow{ inner, codec: CODEC_NONE, buf: Vec::new(), pos: 0 }
+    }
+
+    // returns false on a clean end of stream between chunks
+    async fn next_chunk(&mut self) -> Result<bool, Error> {
+        let mut head = [0u8; CHUNK_HEAD];
+        if self.inner.read(&mut head[..1]).await? == 0 {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut head[1..]).await?;
+        let mut payload = vec![0u8; chunk_len(&head)?];
+        self.inner.read_exact(&mut payload).await?;
+        self.buf = unpack_chunk(self.codec, head[0], payload)?;
+        self.pos = 0;
+        Ok(true)
+    }
+
+    async fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        if self.codec == CODEC_NONE {
+            return self.inner.read(out).await;
+        }
+        while self.pos == self.buf.len() {
+            if !self.next_chunk().await? { return Ok(0); }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+
+    async fn read_exact(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let mut got = 0;
+        while got < out.len() {
+            match self.read(&mut out[got..]).await? {
+                0 => { return Err(Error::from(UnexpectedEof)); }
+                n => { got += n; }
+            }
+        }
+        Ok(())
+    }
+
+    // the bytes of one whole message of type T
+    async fn message<T: WireLength>(&mut self) -> Result<Vec<u8>, DftpError> {
+        let mut wire = Vec::new();
+        loop {
+            let more = T::missing(&wire)?;
+            if more == 0 { return Ok(wire); }
+            let at = wire.len();
+            wire.resize(at + more, 0);
+            self.read_exact(&mut wire[at..]).await.map_err(wire_error)?;
+        }
+    }
+
+    async fn pull<T: WireLength>(&mut self) -> Result<T, DftpError> {
+        let wire = self.message::<T>().await?;
+        let mut message = T::default();
+        message.pull(&mut &wire[..])?;
+        Ok(message)
+    }
+}
+
+// what goes to the peer, cut into chunks once a codec is agreed on. it
+// flushes where a ChunkWriter would, so auto mode samples the same spots.
+struct Outflow<W> {
+    inner: W,
+    packer: Option<Packer>,
+    buf: Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin> Outflow<W> {
+    fn new(inner: W) -> Outflow<W> {
+        Outflow{ inner, packer: None, buf: Vec::new() }
+    }
+
+    async fn write_all(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.packer.is_none() {
+            return self.inner.write_all(data).await;
+        }
+        while !data.is_empty() {
+            let n = std::cmp::min(CHUNK_SIZE - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == CHUNK_SIZE {
+                self.emit().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn emit(&mut self) -> Result<(), Error> {
+        let packer = match self.packer.as_mut() {
+            Some(p) if !self.buf.is_empty() => p,
+            _ => { return Ok(()); }
+        };
+        let chunk = packer.pack(&self.buf)?;
+        self.inner.write_all(&chunk).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), DftpError> {
+        self.emit().await.map_err(wire_error)?;
+        if let Some(p) = self.packer.as_mut() {
+            p.restart();
+        }
+        self.inner.flush().await.map_err(wire_error)
+    }
+
+    // writes out whatever encode puts in a buffer, so the encoding stays
+    // the one of the blocking side
+    async fn put(&mut self, encode: impl FnOnce(&mut dyn Write) -> Result<(), DftpError>) -> Result<(), DftpError> {
+        let mut wire: Vec<u8> = Vec::new();
+        encode(&mut wire)?;
+        self.write_all(&wire).await.map_err(wire_error)
+    }
+
+    async fn shove(&mut self, message: &impl TcpShovable) -> Result<(), DftpError> {
+        self.put(|w| message.shove(w).map(|_| ())).await
+    }
+}
+
+async fn shove_simple<W: AsyncWrite + Unpin>(peer: &mut W, content: u8) -> Result<(), DftpError> {
+    let mut message = Simple::default();
+    message.content = content;
+    Outflow::new(peer).shove(&message).await
+}
+
+async fn pull_simple<R: AsyncRead + Unpin>(peer: &mut R) -> Result<u8, DftpError> {
+    Ok(Inflow::new(peer).pull::<Simple>().await?.content)
+}
+
+// runs blocking file system work off the runtime's threads
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, DftpError> + Send + 'static) -> Result<T, DftpError> {
+    match spawn_blocking(work).await {
+        Ok(r) => r,
+        Err(m) => Err(DftpError::Io("A file system task failed".to_string(), Some(Error::other(m)))),
+    }
+}
+
+/// The sending end's handshake, as [`crate::protocol::handshake_send`]
+/// does it for the first connection of a session.
+pub async fn handshake_send<S: AsyncRead + AsyncWrite + Unpin>(peer: &mut S) -> Result<(), DftpError> {
+    shove_simple(peer, SIMPLE_MSG_SENDER_ID).await?;
+    match pull_simple(peer).await? {
+        SIMPLE_MSG_RECVER_ID => {},
+        SIMPLE_MSG_SENDER_ID => { return Err(DftpError::Protocol("Cannot perform handshake. The peer is also a sender".to_string())); }
+        _ => { return Err(DftpError::Protocol("Malfunction 1".to_string())); }
+    }
+    shove_simple(peer, SIMPLE_MSG_HS_ACK).await
+}
+
+/// The receiving end's handshake. A data connection of a `--streams`
+/// session is turned away here, there is no session for it to join.
+pub async fn handshake_recv<S: AsyncRead + AsyncWrite + Unpin>(peer: &mut S) -> Result<(), DftpError> {
+    if pull_simple(peer).await? != SIMPLE_MSG_SENDER_ID {
+        return Err(DftpError::Protocol("Malfunction 2".to_string()));
+    }
+    shove_simple(peer, SIMPLE_MSG_RECVER_ID).await?;
+    match pull_simple(peer).await? {
+        SIMPLE_MSG_HS_ACK => Ok(()),
+        SIMPLE_MSG_HS_JOIN => Err(DftpError::Protocol("The sender opened a data connection, which the async receiver does not take".to_string())),
+        _ => Err(DftpError::Protocol("Malfunction 3".to_string())),
+    }
+}
+
+// transport and protocol table, as transport_send and protocol_adjust_send
+// do them on a plain connection without -e
+async fn negotiate_send<R, W>(sender: &mut Outflow<W>, replies: &mut Inflow<R>, filenames: &[String], codec: &Codec, preserve: bool) -> Result<ProtocolTable, DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    shove_simple(&mut sender.inner, SIMPLE_MSG_TR_PLAIN).await?;
+    transport_answer(replies.pull::<Simple>().await?.content, false)?;
+
+    let table = session_table(filenames, codec, false, preserve);
+    sender.shove(&table).await?;
+    table_answer(replies.pull::<Simple>().await?.content, &table)?;
+    if codec.id != CODEC_NONE {
+        sender.packer = Some(Packer::new(codec));
+    }
+    Ok(table)
+}
+
+// the receiving side of negotiate_send. returns the agreed protocol table
+async fn negotiate_recv<R, W>(recvr: &mut Inflow<R>, replies: &mut Outflow<W>) -> Result<ProtocolTable, DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let (content, refusal) = transport_verdict(recvr.pull::<Simple>().await?.content, false)?;
+    shove_simple(&mut replies.inner, content).await?;
+    if let Some(e) = refusal {
+        return Err(e);
+    }
+
+    let table = match recvr.pull::<ProtocolTable>().await {
+        Ok(t) => t,
+        Err(m) => {
+            shove_simple(&mut replies.inner, SIMPLE_MSG_PN_DEC).await?;
+            return Err(m);
+        }
+    };
+    let (mut content, mut refusal) = table_verdict(&table, false);
+    // there is nowhere to take data connections from
+    if refusal.is_none() && table.streams > 1 {
+        content = SIMPLE_MSG_PN_DEC;
+        refusal = Some(DftpError::Incompatible("Protocol negotiation failed: the sender asked for data connections, which the async receiver does not take.".to_string()));
+    }
+    shove_simple(&mut replies.inner, content).await?;
+    if let Some(e) = refusal {
+        return Err(e);
+    }
+    recvr.codec = table.codec;
+    Ok(table)
+}
+
+/// Everything after the handshake of a sending session: the files and
+/// directory trees named, or stdin if the only name is `"stdin"`, as the
+/// blocking [`crate::protocol::send`] sends them. Resuming works the same
+/// way too, and symlinks inside directories are sent as links. With
+/// `preserve` the permissions and times of every entry go along.
+pub async fn send_session<S: AsyncRead + AsyncWrite + Unpin>(peer: S, filenames: &[String], codec: &Codec, preserve: bool) -> Result<(), DftpError> {
+    if filenames.is_empty() {
+        return Err(DftpError::Usage("Nothing to send".to_string()));
+    }
+    // walk the trees before saying anything, as the blocking sender does
+    let names = filenames.to_vec();
+    let entries = blocking(move || collect_session(&names, false)).await?;
+    let (reader, writer) = split(peer);
+    let (mut sender, mut replies) = (Outflow::new(writer), Inflow::new(reader));
+    let table = negotiate_send(&mut sender, &mut replies, filenames, codec, preserve).await?;
+    if !defer_kind(&filenames[0]) {
+        let mut trailer = FileTrailer::default();
+        trailer.hash = send_stream(&mut sender, &mut aio::stdin()).await?;
+        if let Err(e) = sender.shove(&trailer).await {
+            return Err(e.context(|m| format!("\nError while sending stream digest: {}", m)));
+        }
+        return finish_send(sender).await;
+    }
+    for entry in entries.iter() {
+        let head = entry_head(entry, table.meta)?;
+        if let Err(m) = sender.write_all(&head).await {
+            return Err(wire_error(m).context(|m| format!("Error while sending file header: {}", m)));
+        }
+        if needs_accept(&entry.name) {
+            let reply = match sender.flush().await {
+                Ok(_) => replies.pull::<Simple>().await,
+                Err(e) => Err(e),
+            };
+            accept_answer(reply.map_err(|e| e.context(|m| format!("Error while waiting for the receiver: {}", m)))?.content, entry)?;
+        }
+        if let Some(link) = entry.link.as_ref() {
+            if let Err(m) = sender.write_all(link.target().as_bytes()).await {
+                return Err(wire_error(m).context(|m| format!("Error while sending link target: {}", m)));
+            }
+            continue;
+        }
+        if entry.is_dir { continue; }
+        let offer = match sender.flush().await {
+            Ok(_) => replies.pull::<ResumePoint>().await,
+            Err(e) => Err(e),
+        };
+        let offer = offer.map_err(|e| e.context(|m| format!("Error while waiting for the receiver: {}", m)))?;
+        let (path, length) = (entry.path.clone(), entry.length);
+        let opened = blocking(move || resume_send(&path, length, &offer)).await;
+        let (file, start, sha) = opened.map_err(|e| e.context(|m| format!("Error while reading file:\n  {}", m)))?;
+        let mut file = File::from_std(file);
+        let mut answer = ResumePoint::default();
+        answer.offset = start;
+        if let Err(e) = sender.shove(&answer).await {
+            return Err(e.context(|m| format!("Error while answering the receiver: {}", m)));
+        }
+        let mut trailer = FileTrailer::default();
+        trailer.hash = if entry.sparse {
+            send_sparse(&mut sender, &mut file, entry, start, sha).await?
+        } else {
+            send_body(&mut sender, &mut file, entry.length, start, sha).await?
+        };
+        if let Err(e) = sender.shove(&trailer).await {
+            return Err(e.context(|m| format!("\nError while sending file digest: {}", m)));
+        }
+    }
+    if let Err(e) = sender.put(send_end_header).await {
+        return Err(e.context(|m| format!("Error while finishing the transfer: {}", m)));
+    }
+    finish_send(sender).await
+}
+
+async fn finish_send<W: AsyncWrite + Unpin>(mut sender: Outflow<W>) -> Result<(), DftpError> {
+    if let Err(e) = sender.flush().await {
+        return Err(e.context(|m| format!("Error while finishing the transfer: {}", m)));
+    }
+    // nothing more is coming
+    sender.inner.shutdown().await.map_err(wire_error)
+}
+
+// streams a payload to the peer, see send_body in protocol. nothing is
+// printed, the caller has its own idea of progress.
+async fn send_body<R, W>(sender: &mut Outflow<W>, reader: &mut R, length: u64, start: u64, sha: Sha256) -> Result<[u8; 32], DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut payload = Payload::new(Some(length), start, sha);
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    loop {
+        let want = payload.want();
+        if want == 0 { break; }
+        let n = match reader.read(&mut buff[..want]).await {
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io("\nError while reading the input".to_string(), Some(m))); }
+        };
+        if n == 0 { break; }
+        if let Err(e) = sender.write_all(&buff[..n]).await {
+            return Err(send_error(e));
+        }
+        payload.moved(&buff[..n]);
+    }
+    payload.sent()
+}
+
+// sends stdin in frames, see send_stream in protocol
+async fn send_stream<R, W>(sender: &mut Outflow<W>, reader: &mut R) -> Result<[u8; 32], DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut payload = Payload::new(None, 0, Sha256::new());
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    loop {
+        let n = match reader.read(&mut buff).await {
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io("\nError while reading the input".to_string(), Some(m))); }
+        };
+        if let Err(e) = sender.write_all(&frame_head(n)).await {
+            return Err(send_error(e));
+        }
+        if let Err(e) = sender.write_all(&buff[..n]).await {
+            return Err(send_error(e));
+        }
+        if n == 0 { break; }
+        payload.moved(&buff[..n]);
+    }
+    payload.sent()
+}
+
+// sends the data extents of a sparse file after their map, see send_sparse
+// in protocol
+async fn send_sparse<W: AsyncWrite + Unpin>(sender: &mut Outflow<W>, file: &mut File, entry: &LocalEntry, start: u64, sha: Sha256) -> Result<[u8; 32], DftpError> {
+    let (path, length) = (entry.path.clone(), entry.length);
+    let map = blocking(move || {
+        let mut map = ExtentMap::default();
+        map.extents = data_extents(&read_file_from(&path, 0)?, start, length);
+        Ok(map)
+    }).await?;
+    if let Err(e) = sender.shove(&map).await {
+        return Err(e.context(|m| format!("Error while sending extent map: {}", m)));
+    }
+    let name = entry.path.display();
+    let mut cursor = SparseCursor::new(&map, &entry.path, start, length, sha)?;
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    while let Some(piece) = cursor.next() {
+        if let Some(offset) = piece.seek {
+            if let Err(m) = file.seek(SeekFrom::Start(offset)).await {
+                return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+            }
+        }
+        let n = match file.read(&mut buff[..piece.want]).await {
+            Ok(0) => { return Err(DftpError::Io("\nThe file changed size during the transfer. Aborting.".to_string(), None)); }
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io(format!("\nError while reading {}", name), Some(m))); }
+        };
+        if let Err(m) = sender.write_all(&buff[..n]).await {
+            return Err(wire_error(m).context(|m| format!("\nError while sending: {}", m)));
+        }
+        cursor.moved(&buff[..n]);
+    }
+    Ok(cursor.finish())
+}
+
+/// Everything after the handshake of a receiving session, written where
+/// [`crate::protocol::recv_session`] would write it. There is nobody to ask,
+/// so `inbox.yes` has to be set, and no progress is printed whatever
+/// `inbox.quiet` says.
+pub async fn recv_session<S: AsyncRead + AsyncWrite + Unpin>(peer: S, inbox: &Inbox) -> Result<(), DftpError> {
+    if !inbox.yes {
+        return Err(DftpError::Usage("The async receiver cannot ask before accepting, set yes in the inbox".to_string()));
+    }
+    let (reader, writer) = split(peer);
+    let (mut recvr, mut replies) = (Inflow::new(reader), Outflow::new(writer));
+    let table = negotiate_recv(&mut recvr, &mut replies).await?;
+    let (filename, dir) = (&inbox.filename, &inbox.dir);
+    if !table.isfile {
+        let mut writer: Box<dyn AsyncWrite + Unpin + Send> = if filename == "stdin" {
+            Box::new(aio::stdout())
+        } else {
+            let target = Path::new(dir).join(filename);
+            Box::new(BufWriter::new(File::from_std(blocking(move || write_file_from(&target, 0)).await?)))
+        };
+        let (_, hash) = recv_stream(&mut recvr, &mut writer).await?;
+        let trailer = recvr.pull::<FileTrailer>().await.map_err(|e| e.context(|m| format!("\nError while receiving stream digest: {}", m)))?;
+        return check_stream(filename, &hash, &trailer);
+    }
+    // nothing the sender names may end up outside of this
+    let root = output_root(dir, filename, table.multi);
+    create_dir(&root).await?;
+    let tag = session_tag(&table)?;
+    // directories get theirs last, writing into them changes their times
+    let mut dirs: Vec<(PathBuf, FileMeta)> = Vec::new();
+    // what hard links may point at
+    let mut received: HashMap<String, PathBuf> = HashMap::new();
+    loop {
+        let header = match recvr.message::<FileHeader>().await {
+            Ok(wire) => recv_file_header(&mut &wire[..]),
+            Err(e) => Err(e),
+        };
+        let header = header.map_err(|e| e.context(|m| format!("Error while receiving file header: {}", m)))?;
+        if header.file_type == FH_TYPE_END { break; }
+        let meta = if table.meta {
+            let meta = recvr.pull::<FileMeta>().await.map_err(|e| e.context(|m| format!("Error while receiving file metadata: {}", m)))?;
+            Some(meta).filter(|_| inbox.preserve)
+        } else { None };
+        let (r, i, h, multi) = (root.clone(), inbox.clone(), header.clone(), table.multi);
+        let target = blocking(move || entry_target(&r, &i, multi, &h)).await?;
+        if needs_accept(&header.name) {
+            let sent = match shove_simple(&mut replies.inner, SIMPLE_MSG_FH_ACC).await {
+                Ok(_) => replies.flush().await,
+                Err(e) => Err(e),
+            };
+            sent.map_err(|e| e.context(|m| format!("Error while answering the sender: {}", m)))?;
+        }
+        if header.file_type == FH_TYPE_DIR {
+            create_dir(&target).await?;
+            if let Some(meta) = meta {
+                dirs.push((target, meta));
+            }
+            continue;
+        }
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+            create_dir(parent).await?;
+        }
+        if is_link(&header) {
+            let mut wire = vec![0u8; link_len(&header)?];
+            if let Err(m) = recvr.read_exact(&mut wire).await {
+                return Err(wire_error(m).context(|m| format!("Error while receiving link target: {}", m)));
+            }
+            let link = recv_link(&mut &wire[..], &header)?;
+            let r = root.clone();
+            received = blocking(move || place_link(&r, &target, &header, &link, &received).map(|_| received)).await?;
+            continue;
+        }
+        recv_file(&mut recvr, &mut replies, &target, &header, &tag).await?;
+        received.insert(header.name.clone(), target.clone());
+        if let Some(meta) = meta {
+            blocking(move || { apply_meta(&target, &meta); Ok(()) }).await?;
+        }
+    }
+    blocking(move || {
+        for (dir, meta) in dirs.iter().rev() {
+            apply_meta(dir, meta);
+        }
+        Ok(())
+    }).await
+}
+
+// see recv_file in protocol
+async fn recv_file<R, W>(recvr: &mut Inflow<R>, replies: &mut Outflow<W>, target: &Path, header: &FileHeader, tag: &str) -> Result<(), DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let (t, length) = (target.to_path_buf(), header.length);
+    let tag = tag.to_string();
+    let incoming = blocking(move || Incoming::new(&t, length, &tag)).await?;
+    let sent = match replies.shove(&incoming.offer).await {
+        Ok(_) => replies.flush().await,
+        Err(e) => Err(e),
+    };
+    sent.map_err(|e| e.context(|m| format!("Error while answering the sender: {}", m)))?;
+    let answer = recvr.pull::<ResumePoint>().await.map_err(|e| e.context(|m| format!("Error while waiting for the sender: {}", m)))?;
+    let opening = incoming.clone();
+    let (file, start, sha) = blocking(move || opening.open(&answer)).await?;
+    let file = File::from_std(file);
+    let (total, hash) = if header.file_type == FH_TYPE_SPARSE {
+        recv_sparse(recvr, file, &incoming.partial, header.length, start, sha).await?
+    } else {
+        recv_body(recvr, &mut BufWriter::new(file), header.length, start, sha).await?
+    };
+    incoming.arrived(total, header.length)?;
+    let trailer = recvr.pull::<FileTrailer>().await.map_err(|e| e.context(|m| format!("\nError while receiving file digest: {}", m)))?;
+    let t = target.to_path_buf();
+    blocking(move || incoming.settle(&t, &hash, &trailer)).await
+}
+
+// puts the data extents of a sparse file in place, see recv_sparse in
+// protocol
+async fn recv_sparse<R: AsyncRead + Unpin>(recvr: &mut Inflow<R>, mut file: File, target: &Path, length: u64, start: u64, sha: Sha256) -> Result<(u64, [u8; 32]), DftpError> {
+    let name = target.display();
+    let map = recvr.pull::<ExtentMap>().await.map_err(|e| e.context(|m| format!("Error while receiving extent map: {}", m)))?;
+    let mut cursor = SparseCursor::new(&map, target, start, length, sha)?;
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    while let Some(piece) = cursor.next() {
+        if let Some(offset) = piece.seek {
+            if let Err(m) = file.seek(SeekFrom::Start(offset)).await {
+                return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+            }
+        }
+        let n = match recvr.read(&mut buff[..piece.want]).await {
+            Ok(n) => n,
+            Err(m) if m.kind() == ConnectionReset || m.kind() == UnexpectedEof => 0,
+            Err(m) => { return Err(wire_error(m).context(|s| format!("\nError while receiving: {}", s))); }
+        };
+        if n == 0 {
+            return Ok((cursor.at(), [0u8; 32]));
+        }
+        if let Err(m) = file.write_all(&buff[..n]).await {
+            return Err(DftpError::Io(format!("\nError while writing {}", name), Some(m)));
+        }
+        cursor.moved(&buff[..n]);
+    }
+    let done = match file.flush().await {
+        Ok(_) => file.set_len(length).await,
+        Err(m) => Err(m),
+    };
+    if let Err(m) = done {
+        return Err(DftpError::Io(format!("\nError while writing {}", name), Some(m)));
+    }
+    Ok((length, cursor.finish()))
+}
+
+// reads a payload off the peer, see recv_body in protocol
+async fn recv_body<R, W>(recvr: &mut Inflow<R>, writer: &mut W, length: u64, start: u64, sha: Sha256) -> Result<(u64, [u8; 32]), DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut payload = Payload::new(Some(length), start, sha);
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    loop {
+        let want = payload.want();
+        if want == 0 { break; }
+        let n = match recvr.read(&mut buff[..want]).await {
+            Ok(n) => n,
+            Err(m) if m.kind() == ConnectionReset || m.kind() == UnexpectedEof => 0,
+            Err(m) => { return Err(wire_error(m).context(|s| format!("\nError while receiving: {}", s))); }
+        };
+        if n == 0 { break; }
+        if let Err(m) = writer.write_all(&buff[..n]).await {
+            return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+        }
+        payload.moved(&buff[..n]);
+    }
+    if let Err(m) = writer.flush().await {
+        return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+    }
+    Ok(payload.received())
+}
+
+// reads the frames of a stream off the peer, see recv_stream in protocol
+async fn recv_stream<R, W>(recvr: &mut Inflow<R>, writer: &mut W) -> Result<(u64, [u8; 32]), DftpError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut payload = Payload::new(None, 0, Sha256::new());
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    loop {
+        let mut head = [0u8; 4];
+        if let Err(m) = recvr.read_exact(&mut head).await {
+            return Err(wire_error(m).context(|s| format!("\nThe stream ended early: {}", s)));
+        }
+        let n = frame_length(head)?;
+        if n == 0 { break; }
+        if let Err(m) = recvr.read_exact(&mut buff[..n]).await {
+            return Err(wire_error(m).context(|s| format!("\nThe stream ended early: {}", s)));
+        }
+        if let Err(m) = writer.write_all(&buff[..n]).await {
+            return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+        }
+        payload.moved(&buff[..n]);
+    }
+    if let Err(m) = writer.flush().await {
+        return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+    }
+    Ok(payload.received())
+}
+
+async fn create_dir(dir: &Path) -> Result<(), DftpError> {
+    let dir = dir.to_path_buf();
+    blocking(move || make_dir(&dir)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+
+    use crate::compress::parse_codec;
+    use crate::network::Rendezvous;
+    use crate::protocol::{self, Joins, Security};
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dftp-async-test-{}-{}", name, process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // a file and a tree under dir/src, returns the names to send them by
+    fn sources(dir: &Path) -> Vec<String> {
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("tree/sub")).unwrap();
+        std::fs::write(src.join("a.txt"), b"hello ".repeat(50_000)).unwrap();
+        let mut x: u32 = 7;
+        let noise: Vec<u8> = (0..300_000).map(|_| { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x as u8 }).collect();
+        std::fs::write(src.join("tree/sub/b.bin"), noise).unwrap();
+        std::fs::write(src.join("tree/empty.txt"), b"").unwrap();
+        std::os::unix::fs::symlink("sub/b.bin", src.join("tree/link")).unwrap();
+        let sparse = std::fs::File::create(src.join("tree/sparse.img")).unwrap();
+        sparse.set_len(8 << 20).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&sparse, b"data in the middle", 3 << 20).unwrap();
+        vec![src.join("a.txt").to_string_lossy().to_string(), src.join("tree").to_string_lossy().to_string()]
+    }
+
+    fn assert_arrived(dir: &Path) {
+        for name in ["a.txt", "tree/sub/b.bin", "tree/empty.txt", "tree/sparse.img"] {
+            let sent = std::fs::read(dir.join("src").join(name)).unwrap();
+            let got = std::fs::read(dir.join("out").join(name)).unwrap();
+            assert!(sent == got, "{} differs", name);
+        }
+        assert_eq!(std::fs::read_link(dir.join("out/tree/link")).unwrap(), Path::new("sub/b.bin"));
+        assert!(!dir.join("out/tree/sub/b.bin.dftp-partial").exists());
+        let sparse = std::fs::metadata(dir.join("out/tree/sparse.img")).unwrap();
+        assert!(std::os::unix::fs::MetadataExt::blocks(&sparse) * 512 < 1 << 20, "the holes were filled in");
+    }
+
+    fn inbox(dir: &Path) -> Inbox {
+        Inbox{ filename: "stdin".to_string(), dir: dir.join("out").to_string_lossy().to_string(), yes: true, log: false, preserve: true, quiet: true }
+    }
+
+    fn plain() -> Security {
+        Security{ code: None, tls: None }
+    }
+
+    // the blocking sender in a thread of its own, dialing addr
+    fn blocking_sender(addr: std::net::SocketAddr, names: Vec<String>, codec: Codec, security: Security) -> thread::JoinHandle<Result<(), DftpError>> {
+        thread::spawn(move || {
+            let entries = collect_session(&names, false)?;
+            let mut peer = std::net::TcpStream::connect(addr).unwrap();
+            protocol::handshake_send(&mut peer, false)?;
+            protocol::send_session(Box::new(peer), Rendezvous::Dialed(addr), &names, &entries, &codec, 1, None, &security, true, true)
+        })
+    }
+
+    #[tokio::test]
+    async fn async_sender_to_blocking_receiver() {
+        let dir = scratch("to-blocking");
+        let names = sources(&dir);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbox = inbox(&dir);
+        let receiver = thread::spawn(move || {
+            let (mut peer, peer_addr) = listener.accept().unwrap();
+            protocol::handshake_recv(&mut peer)?;
+            let joins = Rendezvous::Listener(listener);
+            protocol::recv_session(Box::new(peer), &peer_addr, &Joins::Rendezvous(&joins), &inbox, &plain())
+        });
+        let sent = tokio::spawn(async move {
+            let mut peer = tokio::net::TcpStream::connect(addr).await.unwrap();
+            handshake_send(&mut peer).await?;
+            send_session(peer, &names, &parse_codec("auto").unwrap(), true).await
+        });
+        sent.await.unwrap().unwrap();
+        receiver.join().unwrap().unwrap();
+        assert_arrived(&dir);
+    }
+
+    #[tokio::test]
+    async fn blocking_sender_to_async_receiver_resumes() {
+        let dir = scratch("from-blocking");
+        let names = sources(&dir);
+        // an earlier attempt left the first part of a file behind
+        let partial = std::fs::read(dir.join("src/tree/sub/b.bin")).unwrap();
+        std::fs::create_dir_all(dir.join("out/tree/sub")).unwrap();
+        std::fs::write(dir.join("out/tree/sub/b.bin.dftp-partial"), &partial[..100_000]).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sender = blocking_sender(listener.local_addr().unwrap(), names, parse_codec("deflate").unwrap(), plain());
+        let (mut peer, _) = listener.accept().await.unwrap();
+        handshake_recv(&mut peer).await.unwrap();
+        recv_session(peer, &inbox(&dir)).await.unwrap();
+        sender.join().unwrap().unwrap();
+        assert_arrived(&dir);
+    }
+
+    #[tokio::test]
+    async fn async_receiver_declines_encryption() {
+        let dir = scratch("declines");
+        let names = sources(&dir);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let security = Security{ code: Some("417-maple-quartz".to_string()), tls: None };
+        let sender = blocking_sender(listener.local_addr().unwrap(), names, Codec::default(), security);
+        let (mut peer, _) = listener.accept().await.unwrap();
+        handshake_recv(&mut peer).await.unwrap();
+        assert!(matches!(recv_session(peer, &inbox(&dir)).await, Err(DftpError::Incompatible(_))));
+        assert!(matches!(sender.join().unwrap(), Err(DftpError::Incompatible(_))));
+    }
+
+    #[tokio::test]
+    async fn async_ends_over_an_in_memory_pipe() {
+        let dir = scratch("both-async");
+        let names = sources(&dir);
+        let (mut ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let sent = tokio::spawn(async move {
+            handshake_send(&mut ours).await?;
+            send_session(ours, &names, &parse_codec("auto").unwrap(), true).await
+        });
+        handshake_recv(&mut theirs).await.unwrap();
+        recv_session(theirs, &inbox(&dir)).await.unwrap();
+        sent.await.unwrap().unwrap();
+        assert_arrived(&dir);
+    }
+
+    #[tokio::test]
+    async fn async_sender_leaves_metadata_out_without_preserve() {
+        let dir = scratch("no-preserve");
+        let names = sources(&dir);
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let times = std::fs::FileTimes::new().set_modified(old);
+        std::fs::File::options().write(true).open(dir.join("src/a.txt")).unwrap().set_times(times).unwrap();
+        let (mut ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let sent = tokio::spawn(async move {
+            handshake_send(&mut ours).await?;
+            send_session(ours, &names, &Codec::default(), false).await
+        });
+        handshake_recv(&mut theirs).await.unwrap();
+        recv_session(theirs, &inbox(&dir)).await.unwrap();
+        sent.await.unwrap().unwrap();
+        assert_arrived(&dir);
+        assert_ne!(std::fs::metadata(dir.join("out/a.txt")).unwrap().modified().unwrap(), old);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::DftpError;
+use crate::network::PeerStream;
+use crate::compress::{wrap_compressor, wrap_decompressor, Codec};
+use crate::crypto::{wrap_encryptor, wrap_decryptor};
+use crate::limit::{wrap_limiter, Uplink};
+use crate::protocol::TRANSFER_BUFF_SIZE;
+
+pub const MAX_STREAMS: u8   = 16;
+pub const PARALLEL_MIN: u64 = 4 * 1024 * 1024; // smaller files go over the control connection
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+
+// a piece of a file carried by one data connection
+pub struct Range {
+    pub offset: u64,
+    pub length: u64,
+}
+
+// splits what is left of a file after start into one range per data
+// connection, or None if it is too small to bother. both ends work this
+// out for themselves, so the ranges never go over the wire.
+pub fn split_ranges(start: u64, length: u64, streams: usize) -> Option<Vec<Range>> {
+    let left = length.saturating_sub(start);
+    if streams < 2 || left < PARALLEL_MIN {
+        return None;
+    }
+    let each = left / streams as u64;
+    let mut ranges = Vec::with_capacity(streams);
+    for i in 0..streams as u64 {
+        let offset = start + i * each;
+        let length = if i == streams as u64 - 1 { length - offset } else { each };
+        ranges.push(Range{ offset, length });
+    }
+    Some(ranges)
+}
+
+// an extra connection of a --streams session, with its own keys if the
+// session is encrypted
+pub struct DataStream {
+    pub peer: Box<dyn PeerStream>,
+    pub key: Option<[u8; 32]>,
+}
+
+struct Job {
+    path: PathBuf,
+    range: Range,
+}
+
+// one thread per data connection, each working off the ranges handed to
+// it in order. the stacks on top of the connections are built inside the
+// threads since the layers are not Send.
+pub struct Pool {
+    jobs: Vec<Sender<Job>>,
+    done: Receiver<Result<(), DftpError>>,
+    handles: Vec<JoinHandle<()>>,
+    progress: Arc<AtomicU64>, // bytes moved by all connections so far
+}
+
+pub fn start_send_pool(streams: Vec<DataStream>, codec: &Codec, uplink: &Arc<Uplink>) -> Pool {
+    start_pool(streams, |stream, jobs, done, progress, (codec, uplink): (Codec, Arc<Uplink>)| {
+        let writer = wrap_limiter(stream.peer, &uplink);
+        let writer = match stream.key {
+            Some(k) => wrap_encryptor(writer, &k),
+            None => writer,
+        };
+        let mut writer = match wrap_compressor(writer, &codec) {
+            Ok(w) => w,
+            Err(m) => { let _ = done.send(Err(m)); return; }
+        };
+        for job in jobs.iter() {
+            let result = send_range(&mut writer, &job, &progress);
+            let failed = result.is_err();
+            if done.send(result).is_err() || failed { return; }
+        }
+    }, (codec.clone(), uplink.clone()))
+}
+
+pub fn start_recv_pool(streams: Vec<DataStream>, codec: u8) -> Pool {
+    start_pool(streams, |stream, jobs, done, progress, codec: u8| {
+        let reader: Box<dyn Read> = stream.peer;
+        let reader = match stream.key {
+            Some(k) => wrap_decryptor(reader, &k),
+            None => reader,
+        };
+        let mut reader = match wrap_decompressor(reader, codec) {
+            Ok(r) => r,
+            Err(m) => { let _ = done.send(Err(m)); return; }
+        };
+        for job in jobs.iter() {
+            let result = recv_range(&mut reader, &job, &progress);
+            let failed = result.is_err();
+            if done.send(result).is_err() || failed { return; }
+        }
+    }, codec)
+}
+
+type Worker<C> = fn(DataStream, Receiver<Job>, Sender<Result<(), DftpError>>, Arc<AtomicU64>, C);
+
+fn start_pool<C: Clone + Send + 'static>(streams: Vec<DataStream>, worker: Worker<C>, arg: C) -> Pool {
+    let (done_tx, done) = channel();
+    let progress = Arc::new(AtomicU64::new(0));
+    let mut jobs = Vec::new();
+    let mut handles = Vec::new();
+    for stream in streams {
+        let (job_tx, job_rx) = channel();
+        let done_tx = done_tx.clone();
+        let progress = progress.clone();
+        let arg = arg.clone();
+        handles.push(thread::spawn(move || worker(stream, job_rx, done_tx, progress, arg)));
+        jobs.push(job_tx);
+    }
+    Pool{ jobs, done, handles, progress }
+}
+
+impl Pool {
+    pub fn size(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn moved(&self) -> u64 {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    // hands range i to connection i
+    pub fn dispatch(&self, path: &Path, ranges: Vec<Range>) -> Result<(), DftpError> {
+        for (i, range) in ranges.into_iter().enumerate() {
+            let job = Job{ path: path.to_path_buf(), range };
+            if self.jobs[i].send(job).is_err() {
+                return Err(DftpError::Network(format!("data connection {} is gone", i), None));
+            }
+        }
+        Ok(())
+    }
+
+    // waits for count ranges to be done, calling status every now and then
+    pub fn wait(&self, count: usize, mut status: impl FnMut(u64)) -> Result<(), DftpError> {
+        let mut left = count;
+        while left > 0 {
+            match self.done.recv_timeout(STATUS_INTERVAL) {
+                Ok(Ok(())) => { left -= 1; },
+                Ok(Err(m)) => { return Err(m); }
+                Err(RecvTimeoutError::Timeout) => status(self.moved()),
+                Err(RecvTimeoutError::Disconnected) => { return Err(DftpError::Network("the data connections are gone".to_string(), None)); }
+            }
+        }
+        Ok(())
+    }
+
+    // lets the workers run out of jobs and waits for them to close their
+    // connections
+    pub fn finish(self) {
+        drop(self.jobs);
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn send_range(writer: &mut Box<dyn Write>, job: &Job, progress: &AtomicU64) -> Result<(), DftpError> {
+    let name = job.path.display();
+    let mut file = match File::open(&job.path) {
+        Ok(f) => f,
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for reading", name), Some(m))); }
+    };
+    if let Err(m) = file.seek(SeekFrom::Start(job.range.offset)) {
+        return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+    }
+    let mut reader = file.take(job.range.length);
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    let mut moved: u64 = 0;
+    loop {
+        let n = match reader.read(&mut buff) {
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io(format!("Error while reading {}", name), Some(m))); }
+        };
+        if n == 0 { break; }
+        if let Err(m) = writer.write_all(&buff[..n]) {
+            return Err(DftpError::Network("Data connection lost".to_string(), Some(m)));
+        }
+        moved += n as u64;
+        progress.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    if moved != job.range.length {
+        return Err(DftpError::Io(format!("{} changed size during the transfer", name), None));
+    }
+    writer.flush().map_err(|m| DftpError::Network("Data connection lost".to_string(), Some(m)))
+}
+
+fn recv_range(reader: &mut Box<dyn Read>, job: &Job, progress: &AtomicU64) -> Result<(), DftpError> {
+    let name = job.path.display();
+    let mut file = match OpenOptions::new().write(true).open(&job.path) {
+        Ok(f) => f,
+        Err(m) => { return Err(DftpError::Io(format!("Error opening file {} for writing", name), Some(m))); }
+    };
+    if let Err(m) = file.seek(SeekFrom::Start(job.range.offset)) {
+        return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+    }
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    let mut left = job.range.length;
+    while left > 0 {
+        let want = std::cmp::min(TRANSFER_BUFF_SIZE as u64, left) as usize;
+        let n = match reader.read(&mut buff[..want]) {
+            Ok(0) => { return Err(DftpError::Network("Data connection closed by peer".to_string(), None)); }
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Network("Data connection lost".to_string(), Some(m))); }
+        };
+        if let Err(m) = file.write_all(&buff[..n]) {
+            return Err(DftpError::Io(format!("Error while writing {}", name), Some(m)));
+        }
+        left -= n as u64;
+        progress.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_cover_the_rest_of_a_file_once() {
+        let length = 10 * 1024 * 1024 + 7;
+        for (start, streams) in [(0, 2), (0, 3), (1_000_001, 4), (0, MAX_STREAMS as usize)] {
+            let ranges = split_ranges(start, length, streams).unwrap();
+            assert_eq!(ranges.len(), streams);
+            let mut at = start;
+            for range in ranges.iter() {
+                assert_eq!(range.offset, at);
+                assert!(range.length > 0);
+                at += range.length;
+            }
+            assert_eq!(at, length);
+        }
+        // one connection, or too little left to be worth splitting
+        assert!(split_ranges(0, length, 1).is_none());
+        assert!(split_ranges(0, PARALLEL_MIN - 1, 4).is_none());
+        assert!(split_ranges(length - PARALLEL_MIN + 1, length, 4).is_none());
+        assert!(split_ranges(length + 1, length, 4).is_none());
+    }
+}
@@ -1,102 +1,240 @@
-use std::io::{Write, Read, ErrorKind::ConnectionReset, Error};
-use std::net::{TcpStream};
-use std::io::ErrorKind::{WouldBlock, TimedOut, BrokenPipe};
-use std::fs::File;
-use std::path::Path;
-use std::process::exit;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::io::{Write, Read, Seek, SeekFrom, BufReader, BufWriter, ErrorKind::ConnectionReset, Error};
+use std::net::SocketAddr;
+use std::io::ErrorKind::{WouldBlock, TimedOut, BrokenPipe, UnexpectedEof, InvalidData};
+use std::fs;
+use std::fs::{File, FileTimes, Permissions};
+use std::os::unix::fs::{chown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fmt::Write as fWrite;
 
 use sha2::{Digest, Sha256};
 
-use crate::network::{build_send_stream, build_recv_stream};
+use crate::error::{describe, DftpError};
+use crate::network::{build_stream, Connect, PeerStream, Rendezvous};
 use crate::files::{
     build_file_reader, 
     build_file_writer,
+    read_file_from,
+    write_file_from,
+    make_dir,
     defer_kind,
+    collect_entries,
+    output_path,
+    output_root,
+    check_name,
+    check_confined,
+    check_link,
+    partial_path,
+    session_partial_path,
+    lock_partial,
+    commit_partial,
+    LocalEntry,
+    Link,
+    MAX_NAME_LEN,
+};
+use crate::compress::{
+    wrap_compressor,
+    wrap_decompressor,
+    codec_name,
+    codec_supported,
+    Codec,
+    CODEC_NONE,
+};
+use crate::daemon::{log, Expected, Registry};
+use crate::crypto::{
+    generate_code,
+    random_bytes,
+    pair_send,
+    pair_recv,
+    stream_keys,
+    wrap_encryptor,
+    wrap_decryptor,
+    SessionKeys,
+};
+use crate::limit::{wrap_limiter, Uplink};
+use crate::sparse::{data_extents, hash_hole, hash_map, MAX_EXTENTS};
+use crate::parallel::{
+    split_ranges,
+    start_send_pool,
+    start_recv_pool,
+    DataStream,
+    Pool,
+    Range,
+    MAX_STREAMS,
+};
+use crate::tls::{
+    generate_identity,
+    format_fingerprint,
+    wrap_tls,
+    TlsRole,
 };
-use crate::compress::{wrap_compressor, wrap_decompressor};
 
 pub const TRANSFER_BUFF_SIZE: usize = 131072;//262144; // 256 * 1024 bytes
 
-pub const COMPAT_NUMBER: u8         = 2;
+const STATUS_INTERVAL: Duration     = Duration::from_millis(500);
+
+pub const COMPAT_NUMBER: u8         = 19;
 
 pub const SIMPLE_MSG_SENDER_ID: u8  = 0;
 pub const SIMPLE_MSG_RECVER_ID: u8  = 1;
 pub const SIMPLE_MSG_HS_ACK: u8     = 0b11111001;
+pub const SIMPLE_MSG_HS_JOIN: u8    = 0b11111011; // ack on a data connection of a --streams session
 pub const SIMPLE_MSG_PN_ACC: u8     = 0b00001001;
 pub const SIMPLE_MSG_PN_DEC: u8     = 0b00001000;
+pub const SIMPLE_MSG_PN_DEC_SEC: u8 = 0b00001010; // declined, encryption mismatch
+pub const SIMPLE_MSG_PN_DEC_CODEC: u8 = 0b00001100; // declined, unsupported codec
+pub const SIMPLE_MSG_PN_DEC_BUSY: u8 = 0b00001011; // declined, too many transfers going on
+pub const SIMPLE_MSG_TR_PLAIN: u8   = 0b00010000; // transport request, plain tcp
+pub const SIMPLE_MSG_TR_TLS: u8     = 0b00010001; // transport request, tls
+pub const SIMPLE_MSG_FH_ACC: u8     = 0b00100001; // entry accepted by the receiver
+pub const SIMPLE_MSG_FH_DEC: u8     = 0b00100000; // entry declined by the receiver
 
-pub const PT_FLAG_COMPRESS: u8      = 1;
 pub const PT_FLAG_FILE: u8          = 2;
+pub const PT_FLAG_MULTI: u8         = 4;
+pub const PT_FLAG_ENCRYPT: u8       = 8;
+pub const PT_FLAG_META: u8          = 16;
 
 pub const FH_TYPE_FILE: u8          = 0;
 pub const FH_TYPE_DIR: u8           = 1;
+pub const FH_TYPE_END: u8           = 2; // no more entries follow
+pub const FH_TYPE_SYMLINK: u8       = 3; // the target follows the header, length bytes of it
+pub const FH_TYPE_HARDLINK: u8      = 4; // same, the target is the name of an earlier file
+pub const FH_TYPE_SPARSE: u8        = 5; // a file whose payload is an ExtentMap and the data it lists
+
+// a file header never gets bigger than this, whatever the peer claims
+pub const FH_MAX_NAME_LEN: usize    = MAX_NAME_LEN;
+pub const FH_MAX_SIZE: usize        = 8 + 1 + 4 + FH_MAX_NAME_LEN + 32;
 
-struct Simple{
-    content: u8,
+#[derive(Default)]
+pub struct Simple{
+    pub content: u8,
 }
 
-struct ProtocolTable{
-    compat_num: u8,
+pub struct ProtocolTable{
+    pub compat_num: u8,
+    pub codec: u8, // one of the CODEC_* ids
     //flags
-    compressed: bool,
-    isfile: bool,
+    pub isfile: bool,
+    pub multi: bool, // more than one top level entry
+    pub encrypted: bool,
+    pub meta: bool, // a FileMeta follows every file and directory header
+    pub streams: u8, // data connections next to this one, 0 for none
+    pub session: [u8; 16], // what the data connections identify the session by
+}
+
+// the first thing the sender says on a data connection of a --streams
+// session, in place of a protocol table
+#[derive(Default)]
+pub struct StreamJoin{
+    pub session: [u8; 16],
+    pub index: u8,
+}
+
+#[derive(Clone)]
+pub struct FileHeader{
+    pub length: u64, // file size not name length! total size of the tree for a dir, size of the target for a link
+    pub file_type: u8,
+    pub name: String,
+    pub hash: [u8; 32],
+}
+
+// what a file or directory looked like on the sender's side. the receiver
+// puts it on the entry once the entry is complete. links get one as well
+// but it is not applied to them.
+#[derive(Default)]
+pub struct FileMeta{
+    pub mode: u32, // permission bits
+    pub mtime: i64, // seconds since the epoch
+    pub mtime_nsec: u32,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    pub uid: u32, // only applied by a receiver running as root
+    pub gid: u32,
+}
+
+#[derive(Default)]
+pub struct FileTrailer{
+    pub hash: [u8; 32], // sha256 of the payload
+}
+
+#[derive(Default, Clone)]
+pub struct ResumePoint{
+    pub offset: u64, // bytes of the file already in place
+    pub hash: [u8; 32], // sha256 of those bytes
 }
 
-struct FileHeader{
-    length: u64, // file size not name length!
-    file_type: u8,
-    name: String,
-    hash: [u8; 32],
+// the parts of a sparse file that hold data, from where the transfer of it
+// starts on. only their bytes follow, in this order, everything in between
+// reads as zeros.
+#[derive(Default)]
+pub struct ExtentMap{
+    pub extents: Vec<Extent>,
 }
 
-trait TcpShovable {
-    fn shove(&self, stream: &mut TcpStream) -> Result<usize, String>;
-    fn pull(&mut self, stream: &mut TcpStream) -> Result<usize, String>;
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Extent{
+    pub offset: u64,
+    pub length: u64,
+}
+
+// the udp beacon a listening receiver sends out, one per datagram
+pub struct Announcement{
+    pub compat_num: u8,
+    pub port: u16, // the tcp port it listens on
+    pub name: String,
+}
+
+pub const ANNOUNCE_MAGIC: &[u8; 4]  = b"DFTP";
+
+pub trait TcpShovable {
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError>;
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError>;
 }
 
 impl TcpShovable for Simple {
-    fn shove(&self, stream: &mut TcpStream) -> Result<usize, String> {
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
         let mut buf:[u8; 1] = [0; 1];
         match self.content{
             SIMPLE_MSG_SENDER_ID |
             SIMPLE_MSG_RECVER_ID | 
             SIMPLE_MSG_HS_ACK    |
+            SIMPLE_MSG_HS_JOIN   |
             SIMPLE_MSG_PN_ACC    |
-            SIMPLE_MSG_PN_DEC => {},
-            _ => { return Err("Invalid value for simple message encountered while packing".to_string()); }
+            SIMPLE_MSG_PN_DEC    |
+            SIMPLE_MSG_PN_DEC_SEC |
+            SIMPLE_MSG_PN_DEC_CODEC |
+            SIMPLE_MSG_PN_DEC_BUSY |
+            SIMPLE_MSG_TR_PLAIN  |
+            SIMPLE_MSG_TR_TLS    |
+            SIMPLE_MSG_FH_ACC    |
+            SIMPLE_MSG_FH_DEC    => {},
+            _ => { return Err(DftpError::Protocol("Invalid value for simple message encountered while packing".to_string())); }
         }
         buf[0] = self.content;
-        match stream.write_all(&buf) {
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
+        stream.write_all(&buf).map_err(wire_error)?;
         Ok(1) // 1 byte written
     }
-    fn pull(&mut self, stream: &mut TcpStream) -> Result<usize, String> {
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
         let mut buf:[u8; 1] = [0; 1];
-        match stream.read_exact(&mut buf) {
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
+        stream.read_exact(&mut buf).map_err(wire_error)?;
         match buf[0]{
             SIMPLE_MSG_SENDER_ID |
             SIMPLE_MSG_RECVER_ID | 
             SIMPLE_MSG_HS_ACK    |
+            SIMPLE_MSG_HS_JOIN   |
             SIMPLE_MSG_PN_ACC    |
-            SIMPLE_MSG_PN_DEC => {},
-            _ => { return Err("Invalid value for simple message encountered while unpacking".to_string()); }
+            SIMPLE_MSG_PN_DEC    |
+            SIMPLE_MSG_PN_DEC_SEC |
+            SIMPLE_MSG_PN_DEC_CODEC |
+            SIMPLE_MSG_PN_DEC_BUSY |
+            SIMPLE_MSG_TR_PLAIN  |
+            SIMPLE_MSG_TR_TLS    |
+            SIMPLE_MSG_FH_ACC    |
+            SIMPLE_MSG_FH_DEC    => {},
+            _ => { return Err(DftpError::Protocol("Invalid value for simple message encountered while unpacking".to_string())); }
         }
         self.content = buf[0];
         Ok(1) // 1 bytes consumed
@@ -104,79 +242,100 @@ impl TcpShovable for Simple {
 }
 
 impl TcpShovable for ProtocolTable{
-    fn shove(&self, stream: &mut TcpStream) -> Result<usize, String> {
-        let mut buf:[u8; 2] = [0; 2];
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let mut buf:[u8; 20] = [0; 20];
         buf[0] = self.compat_num;
         let mut flags: u8 = 0;
-        if self.compressed {
-            flags |= PT_FLAG_COMPRESS;
-        }
         if self.isfile {
             flags |= PT_FLAG_FILE;
         }
+        if self.multi {
+            flags |= PT_FLAG_MULTI;
+        }
+        if self.encrypted {
+            flags |= PT_FLAG_ENCRYPT;
+        }
+        if self.meta {
+            flags |= PT_FLAG_META;
+        }
         // future implementation
         buf[1] = flags;
-        match stream.write_all(&buf) {
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
-        Ok(2) // 2 bytes written
-    }
-    fn pull(&mut self, stream: &mut TcpStream) -> Result<usize, String> {
-        let mut buf:[u8; 2] = [0; 2];
-        match stream.read_exact(&mut buf) {
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
+        buf[2] = self.codec;
+        buf[3] = self.streams;
+        buf[4..20].copy_from_slice(&self.session);
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(20) // 20 bytes written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut buf:[u8; 20] = [0; 20];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
         let compat_num = buf[0];
         if compat_num != COMPAT_NUMBER {
-            return Err (format!("Incompatible protocol versions. Ours is {}. Theirs is {}", 
-                                COMPAT_NUMBER, compat_num));
+            return Err(DftpError::Incompatible(format!("Incompatible protocol versions. Ours is {}. Theirs is {}",
+                                COMPAT_NUMBER, compat_num)));
         }
         self.compat_num = compat_num;
         let flags = buf[1];
-        self.compressed = (flags & PT_FLAG_COMPRESS) != 0;
         self.isfile = (flags & PT_FLAG_FILE) != 0;
+        self.multi = (flags & PT_FLAG_MULTI) != 0;
+        self.encrypted = (flags & PT_FLAG_ENCRYPT) != 0;
+        self.meta = (flags & PT_FLAG_META) != 0;
         // future implementation
-        Ok(2) // 2 bytes consumed
+        self.codec = buf[2];
+        if buf[3] > MAX_STREAMS {
+            return Err(DftpError::Protocol(format!("The sender asked for {} data connections, at most {} are allowed", buf[3], MAX_STREAMS)));
+        }
+        self.streams = buf[3];
+        self.session.copy_from_slice(&buf[4..20]);
+        Ok(20) // 20 bytes consumed
+    }
+}
+
+impl TcpShovable for StreamJoin{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let mut buf:[u8; 17] = [0; 17];
+        buf[0..16].copy_from_slice(&self.session);
+        buf[16] = self.index;
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(17) // 17 bytes written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut buf:[u8; 17] = [0; 17];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
+        self.session.copy_from_slice(&buf[0..16]);
+        self.index = buf[16];
+        Ok(17) // 17 bytes consumed
     }
 }
 
 impl TcpShovable for FileHeader{
-    fn shove(&self, stream: &mut TcpStream) -> Result<usize, String> {
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
         let name = &self.name;
         let buflen = 8 + 1 + 4 + 32 + name.len();
+        if buflen > FH_MAX_SIZE {
+            return Err(DftpError::Protocol(format!("Error when packing FH, names are limited to {} bytes", FH_MAX_NAME_LEN)));
+        }
         let mut buf: Vec<u8> = vec![0; buflen];
         let len = self.length;
         // big endian
-        buf[0] = ((len >> 56)       ) as u8;
+        buf[0] = (len >> 56) as u8;
         buf[1] = ((len >> 48) & 0xff) as u8;
         buf[2] = ((len >> 40) & 0xff) as u8;
         buf[3] = ((len >> 32) & 0xff) as u8;
         buf[4] = ((len >> 24) & 0xff) as u8;
         buf[5] = ((len >> 16) & 0xff) as u8;
         buf[6] = ((len >> 8 ) & 0xff) as u8;
-        buf[7] = ((len      ) & 0xff) as u8;
+        buf[7] = (len & 0xff) as u8;
 
         buf[8] = self.file_type;
         // get the string, write the len first 
         // and then the string itself.
         let name = name.as_bytes();
         let len = name.len();
-        buf[9] = ((len >> 24)       ) as u8;
+        buf[9] = (len >> 24) as u8;
         buf[10] = ((len >> 16) & 0xff) as u8;
         buf[11] = ((len >> 8) & 0xff) as u8;
-        buf[12] = ((len     ) & 0xff) as u8;
+        buf[12] = (len & 0xff) as u8;
         let mut i: usize = 13;
         for b in name {
             buf[i] = *b;
@@ -188,29 +347,13 @@ impl TcpShovable for FileHeader{
         }
         let buf = &buf[..];
         assert!(i == buflen);
-        match stream.write_all(buf){
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
+        stream.write_all(buf).map_err(wire_error)?;
         Ok(i) // i bytes written
     }
     
-    fn pull(&mut self, stream: &mut TcpStream) -> Result<usize, String> {
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
         let mut buf:[u8; 13] = [0; 13];
-        match stream.read_exact(&mut buf){
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                    _ => { panic!("something is seriously wrong"); },
-                }
-            },
-            Ok(_) => {},
-        }
+        stream.read_exact(&mut buf).map_err(wire_error)?;
         let mut len: u64 = 0;
         len <<= 8; len += buf[0] as u64;
         len <<= 8; len += buf[1] as u64;
@@ -223,8 +366,12 @@ impl TcpShovable for FileHeader{
         self.length = len;
         match buf[8] {
             FH_TYPE_FILE | 
-            FH_TYPE_DIR => {},
-            _ => { return Err(format!("Error when unpacking FH, invalid file type {}", buf[8])); }
+            FH_TYPE_DIR  |
+            FH_TYPE_END  |
+            FH_TYPE_SYMLINK |
+            FH_TYPE_HARDLINK |
+            FH_TYPE_SPARSE => {},
+            _ => { return Err(DftpError::Protocol(format!("Error when unpacking FH, invalid file type {}", buf[8]))); }
         }
         self.file_type = buf[8];
         let mut len: u32 = 0;
@@ -233,51 +380,257 @@ impl TcpShovable for FileHeader{
         len <<= 8; len += buf[11] as u32;
         len <<= 8; len += buf[12] as u32;
         let len = len as usize;
+        // checked before allocating anything for it
+        if len > FH_MAX_NAME_LEN {
+            return Err(DftpError::Protocol(format!("Error when unpacking FH, name of {} bytes, at most {} are allowed", len, FH_MAX_NAME_LEN)));
+        }
         let mut buf2: Vec<u8> = vec![0u8; len];
-        match stream.read_exact(&mut buf2[..]) {
-        Err(e) => {
-            match e.kind() {
-                WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                _ => { panic!("something is seriously wrong"); },
-            }
-        },
-        Ok(_) => {},
+        stream.read_exact(&mut buf2[..]).map_err(wire_error)?;
+        self.name = match String::from_utf8(buf2) {
+            Ok(n) => n,
+            Err(_) => { return Err(DftpError::Protocol("Error when unpacking FH, file name is not valid utf8".to_string())); }
         };
-        let name = String::from_utf8(buf2).expect("expecting valid utf8");
-        self.name = name;
         let mut hash = [0u8; 32];
-        match stream.read_exact(&mut hash){
-        Err(e) => {
-            match e.kind() {
-                WouldBlock | TimedOut => {return Err("requst timeout".to_string());}
-                _ => { panic!("something is seriously wrong"); },
+        stream.read_exact(&mut hash).map_err(wire_error)?;
+        self.hash = hash;
+        Ok(13 + len + 32) // 13 + len + 32 bytes consumed
+    }
+}
+
+impl TcpShovable for FileMeta{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let mut buf:[u8; 36] = [0; 36];
+        buf[0..4].copy_from_slice(&self.mode.to_be_bytes());
+        buf[4..12].copy_from_slice(&self.mtime.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.mtime_nsec.to_be_bytes());
+        buf[16..24].copy_from_slice(&self.atime.to_be_bytes());
+        buf[24..28].copy_from_slice(&self.atime_nsec.to_be_bytes());
+        buf[28..32].copy_from_slice(&self.uid.to_be_bytes());
+        buf[32..36].copy_from_slice(&self.gid.to_be_bytes());
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(36) // 36 bytes written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut buf:[u8; 36] = [0; 36];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
+        let word = |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let mut long = [0u8; 8];
+        self.mode = word(0);
+        long.copy_from_slice(&buf[4..12]);
+        self.mtime = i64::from_be_bytes(long);
+        self.mtime_nsec = word(12);
+        long.copy_from_slice(&buf[16..24]);
+        self.atime = i64::from_be_bytes(long);
+        self.atime_nsec = word(24);
+        if self.mtime_nsec >= 1_000_000_000 || self.atime_nsec >= 1_000_000_000 {
+            return Err(DftpError::Protocol("Error when unpacking file metadata, nanoseconds out of range".to_string()));
+        }
+        self.uid = word(28);
+        self.gid = word(32);
+        Ok(36) // 36 bytes consumed
+    }
+}
+
+impl TcpShovable for FileTrailer{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        stream.write_all(&self.hash).map_err(wire_error)?;
+        Ok(32) // 32 bytes written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        stream.read_exact(&mut self.hash).map_err(wire_error)?;
+        Ok(32) // 32 bytes consumed
+    }
+}
+
+impl TcpShovable for ExtentMap{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let mut buf: Vec<u8> = Vec::with_capacity(4 + 16 * self.extents.len());
+        buf.extend_from_slice(&(self.extents.len() as u32).to_be_bytes());
+        for extent in self.extents.iter() {
+            buf.extend_from_slice(&extent.offset.to_be_bytes());
+            buf.extend_from_slice(&extent.length.to_be_bytes());
+        }
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(buf.len()) // 4 + 16 bytes per extent written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut count = [0u8; 4];
+        stream.read_exact(&mut count).map_err(wire_error)?;
+        let count = u32::from_be_bytes(count) as usize;
+        // checked before allocating anything for it
+        if count > MAX_EXTENTS {
+            return Err(DftpError::Protocol(format!("Error when unpacking extent map, {} extents, at most {} are allowed", count, MAX_EXTENTS)));
+        }
+        let mut buf = vec![0u8; 16 * count];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
+        self.extents = Vec::with_capacity(count);
+        let mut end: u64 = 0;
+        for raw in buf.chunks_exact(16) {
+            let mut long = [0u8; 8];
+            long.copy_from_slice(&raw[0..8]);
+            let offset = u64::from_be_bytes(long);
+            long.copy_from_slice(&raw[8..16]);
+            let length = u64::from_be_bytes(long);
+            // in order, apart and not empty
+            match offset.checked_add(length) {
+                Some(e) if length > 0 && offset >= end => { end = e; },
+                _ => { return Err(DftpError::Protocol(format!("Error when unpacking extent map, bad extent of {} bytes at {}", length, offset))); }
             }
-        },
-        Ok(_) => {},
+            self.extents.push(Extent{ offset, length });
+        }
+        Ok(4 + 16 * count) // 4 + 16 bytes per extent consumed
+    }
+}
+
+impl TcpShovable for ResumePoint{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let mut buf:[u8; 40] = [0; 40];
+        buf[0..8].copy_from_slice(&self.offset.to_be_bytes());
+        buf[8..40].copy_from_slice(&self.hash);
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(40) // 40 bytes written
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut buf:[u8; 40] = [0; 40];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
+        let mut offset = [0u8; 8];
+        offset.copy_from_slice(&buf[0..8]);
+        self.offset = u64::from_be_bytes(offset);
+        self.hash.copy_from_slice(&buf[8..40]);
+        Ok(40) // 40 bytes consumed
+    }
+}
+
+impl TcpShovable for Announcement{
+    fn shove(&self, stream: &mut dyn Write) -> Result<usize, DftpError> {
+        let name = self.name.as_bytes();
+        if name.len() > u8::MAX as usize {
+            return Err(DftpError::Usage("Announced name is too long".to_string()));
+        }
+        let mut buf: Vec<u8> = Vec::with_capacity(8 + name.len());
+        buf.extend_from_slice(ANNOUNCE_MAGIC);
+        buf.push(self.compat_num);
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name);
+        stream.write_all(&buf).map_err(wire_error)?;
+        Ok(buf.len())
+    }
+    fn pull(&mut self, stream: &mut dyn Read) -> Result<usize, DftpError> {
+        let mut buf:[u8; 8] = [0; 8];
+        stream.read_exact(&mut buf).map_err(wire_error)?;
+        if &buf[0..4] != ANNOUNCE_MAGIC {
+            return Err(DftpError::Protocol("Not a dftp announcement".to_string()));
+        }
+        self.compat_num = buf[4];
+        self.port = u16::from_be_bytes([buf[5], buf[6]]);
+        let mut name = vec![0u8; buf[7] as usize];
+        stream.read_exact(&mut name).map_err(wire_error)?;
+        self.name = match String::from_utf8(name) {
+            Ok(n) => n,
+            Err(_) => { return Err(DftpError::Protocol("Announced name is not valid utf8".to_string())); }
         };
-        self.hash = hash;
-        Ok(9 + len + 32) // 9 + len bytes consumed
+        Ok(8 + self.name.len())
+    }
+}
+
+// how much of a message is still to come after head, the part of it read
+// so far. 0 once it is whole. the sizes are the ones pull reads.
+#[cfg(feature = "async")]
+pub(crate) trait WireLength: TcpShovable + Default {
+    fn missing(head: &[u8]) -> Result<usize, DftpError>;
+}
+
+#[cfg(feature = "async")]
+impl WireLength for Simple {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> { Ok(1 - head.len()) }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for ProtocolTable {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> { Ok(20 - head.len()) }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for FileMeta {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> { Ok(36 - head.len()) }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for FileTrailer {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> { Ok(32 - head.len()) }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for ResumePoint {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> { Ok(40 - head.len()) }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for ExtentMap {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> {
+        if head.len() < 4 {
+            return Ok(4 - head.len());
+        }
+        let count = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        if count > MAX_EXTENTS {
+            return Err(DftpError::Protocol(format!("Error when unpacking extent map, {} extents, at most {} are allowed", count, MAX_EXTENTS)));
+        }
+        Ok(4 + 16 * count - head.len())
+    }
+}
+
+#[cfg(feature = "async")]
+impl WireLength for FileHeader {
+    fn missing(head: &[u8]) -> Result<usize, DftpError> {
+        if head.len() < 13 {
+            return Ok(13 - head.len());
+        }
+        let len = u32::from_be_bytes([head[9], head[10], head[11], head[12]]) as usize;
+        // checked before reading the name, same as pull
+        if len > FH_MAX_NAME_LEN {
+            return Err(DftpError::Protocol(format!("Error when unpacking FH, name of {} bytes, at most {} are allowed", len, FH_MAX_NAME_LEN)));
+        }
+        Ok(13 + len + 32 - head.len())
     }
 }
 
-impl Simple {
-    pub fn default() -> Simple{
-        Simple{content: 0u8}
+pub(crate) fn wire_error(e: Error) -> DftpError {
+    match e.kind() {
+        WouldBlock | TimedOut => DftpError::Timeout("requst timeout".to_string()),
+        UnexpectedEof | ConnectionReset | BrokenPipe => DftpError::Network("connection closed by peer".to_string(), None),
+        InvalidData => DftpError::Protocol(format!("corrupt data from the peer: {}", e)),
+        _ => DftpError::Network("connection lost".to_string(), Some(e)),
     }
 }
 
-impl ProtocolTable {
-    pub fn default() -> ProtocolTable{
+impl Default for ProtocolTable {
+    fn default() -> ProtocolTable {
         ProtocolTable{
             compat_num: COMPAT_NUMBER,
-            compressed: false,
-            isfile: false
+            codec: CODEC_NONE,
+            isfile: false,
+            multi: false,
+            encrypted: false,
+            meta: false,
+            streams: 0,
+            session: [0u8; 16],
+        }
+    }
+}
+
+impl Default for Announcement {
+    fn default() -> Announcement {
+        Announcement{
+            compat_num: COMPAT_NUMBER,
+            port: 0,
+            name: String::new(),
         }
     }
 }
 
-impl FileHeader{
-    pub fn default() -> FileHeader{
+impl Default for FileHeader {
+    fn default() -> FileHeader {
         FileHeader{
             length: 0,
             file_type: FH_TYPE_FILE,
@@ -287,13 +640,8 @@ impl FileHeader{
     }
 }
 
-enum Message {
-    Simple,
-    ProtocolTable,
-    FileHeader
-}
-
-pub fn handshake_send(peer: &mut TcpStream) -> Result<(), String> {
+// join marks a data connection of a session that is already going
+pub fn handshake_send(peer: &mut dyn PeerStream, join: bool) -> Result<(), DftpError> {
     // send a sender id handshake message
     let mut message = Simple::default();
     message.content = SIMPLE_MSG_SENDER_ID;
@@ -303,26 +651,27 @@ pub fn handshake_send(peer: &mut TcpStream) -> Result<(), String> {
     let mut message = Simple::default();
     message.pull(peer)?;
     if message.content == SIMPLE_MSG_SENDER_ID {
-        return Err("Cannot perform handshake. The peer is also a sender".to_string());
+        return Err(DftpError::Protocol("Cannot perform handshake. The peer is also a sender".to_string()));
     }
     else if message.content != SIMPLE_MSG_RECVER_ID {
-        return Err("Malfunction 1".to_string());
+        return Err(DftpError::Protocol("Malfunction 1".to_string()));
     }
     
     // send ack
     let mut message = Simple::default();
-    message.content = SIMPLE_MSG_HS_ACK;
+    message.content = if join { SIMPLE_MSG_HS_JOIN } else { SIMPLE_MSG_HS_ACK };
     message.shove(peer)?;
 
     Ok(())
 }
 
-pub fn handshake_recv(peer: &mut TcpStream) -> Result<(), String> {
+// returns whether the sender is joining a session with a data connection
+pub fn handshake_recv(peer: &mut dyn PeerStream) -> Result<bool, DftpError> {
     // wait to recv a sender id handshake
     let mut message = Simple::default();
     message.pull(peer)?;
     if message.content != SIMPLE_MSG_SENDER_ID {
-        return Err("Malfunction 2".to_string());
+        return Err(DftpError::Protocol("Malfunction 2".to_string()));
     }
 
     // send a recver id message
@@ -333,298 +682,1498 @@ pub fn handshake_recv(peer: &mut TcpStream) -> Result<(), String> {
     // wait to recv ack
     let mut message = Simple::default();
     message.pull(peer)?;
-    if message.content != SIMPLE_MSG_HS_ACK {
-        return Err("Malfunction 3".to_string());
+    if message.content != SIMPLE_MSG_HS_ACK && message.content != SIMPLE_MSG_HS_JOIN {
+        return Err(DftpError::Protocol("Malfunction 3".to_string()));
+    }
+
+    Ok(message.content == SIMPLE_MSG_HS_JOIN)
+}
+
+// picks the transport before anything else is said, so the protocol table
+// and the file headers already go through tls when it is asked for
+pub fn transport_send(mut peer: Box<dyn PeerStream>, tls: &Option<TlsRole>) -> Result<Box<dyn PeerStream>, DftpError> {
+    let mut message = Simple::default();
+    message.content = if tls.is_some() { SIMPLE_MSG_TR_TLS } else { SIMPLE_MSG_TR_PLAIN };
+    message.shove(&mut peer)?;
+
+    let mut message = Simple::default();
+    message.pull(&mut peer)?;
+    transport_answer(message.content, tls.is_some())?;
+
+    match tls {
+        Some(t) => wrap_tls(peer, t),
+        None => Ok(peer),
+    }
+}
+
+pub fn transport_recv(mut peer: Box<dyn PeerStream>, tls: &Option<TlsRole>) -> Result<Box<dyn PeerStream>, DftpError> {
+    let mut message = Simple::default();
+    message.pull(&mut peer)?;
+    let (content, refusal) = transport_verdict(message.content, tls.is_some())?;
+    let mut reply = Simple::default();
+    reply.content = content;
+    reply.shove(&mut peer)?;
+    if let Some(e) = refusal {
+        return Err(e);
+    }
+
+    match tls {
+        Some(t) => wrap_tls(peer, t),
+        None => Ok(peer),
+    }
+}
+
+// what the sender makes of the receiver's answer to the transport it asked
+// for. the async sender goes by this too.
+pub(crate) fn transport_answer(content: u8, tls: bool) -> Result<(), DftpError> {
+    match content {
+        SIMPLE_MSG_PN_ACC => Ok(()),
+        SIMPLE_MSG_PN_DEC_SEC if tls => Err(DftpError::Incompatible("Transport negotiation failed: the receiver does not use tls.".to_string())),
+        SIMPLE_MSG_PN_DEC_SEC => Err(DftpError::Incompatible("Transport negotiation failed: the receiver only accepts tls.".to_string())),
+        SIMPLE_MSG_PN_DEC_BUSY => Err(DftpError::Declined("The receiver is busy with other transfers. Try again later.".to_string())),
+        _ => Err(DftpError::Protocol("Malfunction 6".to_string())),
+    }
+}
+
+// the receiver's answer to the transport the sender asked for, with the
+// reason when it is a decline
+pub(crate) fn transport_verdict(asked: u8, tls: bool) -> Result<(u8, Option<DftpError>), DftpError> {
+    if asked != SIMPLE_MSG_TR_PLAIN && asked != SIMPLE_MSG_TR_TLS {
+        return Err(DftpError::Protocol("Malfunction 7".to_string()));
     }
+    let wants_tls = asked == SIMPLE_MSG_TR_TLS;
+    if wants_tls == tls {
+        return Ok((SIMPLE_MSG_PN_ACC, None));
+    }
+    let reason = if wants_tls {
+        "Transport negotiation failed: the sender asked for tls but --tls was not given."
+    } else { "Transport negotiation failed: the sender does not use tls." };
+    Ok((SIMPLE_MSG_PN_DEC_SEC, Some(DftpError::Incompatible(reason.to_string()))))
+}
 
+// turns a sender away before anything else happens
+pub fn transport_refuse(mut peer: Box<dyn PeerStream>) -> Result<(), DftpError> {
+    let mut message = Simple::default();
+    message.pull(&mut peer)?;
+    let mut decl = Simple::default();
+    decl.content = SIMPLE_MSG_PN_DEC_BUSY;
+    decl.shove(&mut peer)?;
     Ok(())
 }
 
-fn protocol_adjust_send(mut peer: TcpStream, filename: &String, compress: bool) -> Result<Box<dyn Write>, String>{
-    let isfile = defer_kind(filename);
+type SendStack = (Box<dyn Write>, Box<dyn Read>, ProtocolTable, Option<SessionKeys>);
 
+// returns the upgraded stream to the receiver, the plain channel carrying
+// its replies, the protocol table it agreed to and the session keys if any
+fn protocol_adjust_send(mut peer: Box<dyn PeerStream>, filenames: &[String], codec: &Codec, streams: u8, code: &Option<String>, uplink: &Arc<Uplink>, preserve: bool) -> Result<SendStack, DftpError>{
     // craft a protocol table message and send it
-    let mut message = ProtocolTable::default();
-    message.compressed = compress;
-    message.isfile = isfile;
-    message.shove(&mut peer)?;
+    let mut table = session_table(filenames, codec, code.is_some(), preserve);
+    if streams > 1 {
+        table.streams = streams;
+        random_bytes(&mut table.session)?;
+    }
+    table.shove(&mut peer)?;
 
     // wait for a negotiation response
     let mut message = Simple::default();
     message.pull(&mut peer)?;
-    if message.content == SIMPLE_MSG_PN_DEC {
-        return Err("Protocol negotiation failed: peer declined.\nOne of you needs to update their dftp.".to_string());
-    }
-    if message.content != SIMPLE_MSG_PN_ACC {
-        return Err("Malfunction 4".to_string());
-    }
+    table_answer(message.content, &table)?;
 
     // here the peer has accepted out protocol negotiation
 
-    // send file header if necesary
-    if isfile {
-        match send_file_header(&mut peer, filename) {
-            Ok(_) => {},
-            Err(m) => {return Err(format!("Error while opening file: {:}", m.to_string()));}
-        };
-    }
+    let keys = match code {
+        Some(c) => Some(pair_send(&mut peer, c, &table_bytes(&table)?)?),
+        None => None,
+    };
 
+    let replies: Box<dyn Read> = match peer.try_clone_stream() {
+        Ok(p) => p,
+        Err(m) => { return Err(DftpError::Network("Cannot set up the reply channel".to_string(), Some(m))); }
+    };
 
     // time to upgrade protocol    
     
-    let writer: Box<dyn Write> = Box::new(peer);
-    let writer = if compress {
-        wrap_compressor(writer)
-    } else { writer };
+    // the limit is on what goes out on the wire, so it goes at the bottom
+    let writer = wrap_limiter(peer, uplink);
+    // encryption goes below compression, ciphertext doesnt compress
+    let (writer, replies) = match keys.as_ref() {
+        Some(k) => (wrap_encryptor(writer, &k.outgoing), wrap_decryptor(replies, &k.incoming)),
+        None => (writer, replies),
+    };
+    let writer = wrap_compressor(writer, codec)?;
 
     // more protocol upgrades here
     
-    Ok(writer)
+    Ok((writer, replies, table, keys))
 }
 
-fn protocol_adjust_recv(mut peer: TcpStream) -> Result<(Box<dyn Read>,
-                                                        Option<FileHeader>,
-                                                        ProtocolTable), String>{
+// the protocol table for a session of filenames, before any data
+// connections are asked for
+pub(crate) fn session_table(filenames: &[String], codec: &Codec, encrypted: bool, preserve: bool) -> ProtocolTable {
+    let isfile = defer_kind(&filenames[0]);
+    let mut table = ProtocolTable::default();
+    table.codec = codec.id;
+    table.isfile = isfile;
+    table.multi = filenames.len() > 1;
+    table.encrypted = encrypted;
+    table.meta = preserve && isfile;
+    table
+}
+
+// what the sender makes of the receiver's answer to its protocol table
+pub(crate) fn table_answer(content: u8, table: &ProtocolTable) -> Result<(), DftpError> {
+    match content {
+        SIMPLE_MSG_PN_ACC => Ok(()),
+        SIMPLE_MSG_PN_DEC => Err(DftpError::Incompatible("Protocol negotiation failed: peer declined.\nOne of you needs to update their dftp.".to_string())),
+        SIMPLE_MSG_PN_DEC_SEC if table.encrypted => Err(DftpError::Incompatible("Protocol negotiation failed: the receiver was not started with -e.".to_string())),
+        SIMPLE_MSG_PN_DEC_SEC => Err(DftpError::Incompatible("Protocol negotiation failed: the receiver only accepts encrypted transfers. Use -e.".to_string())),
+        SIMPLE_MSG_PN_DEC_CODEC => {
+            Err(DftpError::Incompatible(format!("Protocol negotiation failed: the receiver does not support {} compression. Pick another codec with -x.", codec_name(table.codec))))
+        },
+        _ => Err(DftpError::Protocol("Malfunction 4".to_string())),
+    }
+}
+
+// the receiver's answer to a protocol table, with the reason when it is a
+// decline. encrypted says whether this end was given a pairing code.
+pub(crate) fn table_verdict(table: &ProtocolTable, encrypted: bool) -> (u8, Option<DftpError>) {
+    if table.encrypted != encrypted {
+        let reason = if encrypted {
+            "Protocol negotiation failed: the sender did not ask for encryption."
+        } else { "Protocol negotiation failed: the sender asked for encryption but -e was not given." };
+        return (SIMPLE_MSG_PN_DEC_SEC, Some(DftpError::Incompatible(reason.to_string())));
+    }
+    if !codec_supported(table.codec) {
+        return (SIMPLE_MSG_PN_DEC_CODEC, Some(DftpError::Incompatible(format!("Protocol negotiation failed: the sender asked for {} compression, which this build does not support.", codec_name(table.codec)))));
+    }
+    (SIMPLE_MSG_PN_ACC, None)
+}
+
+// the protocol table as it went over the wire, for the pairing to vouch
+// for. codec, streams and session id all travel in the clear.
+fn table_bytes(table: &ProtocolTable) -> Result<Vec<u8>, DftpError> {
+    let mut wire: Vec<u8> = Vec::new();
+    table.shove(&mut wire)?;
+    Ok(wire)
+}
+
+type RecvStack<'a> = (Box<dyn Read>, Box<dyn Write>, ProtocolTable, Option<SessionKeys>, Option<Expected<'a>>);
+
+// returns the upgraded stream from the sender, the plain channel for
+// replies, the negotiated protocol table, the session keys if any and,
+// in the daemon, the claim on the data connections to come
+fn protocol_adjust_recv<'a>(mut peer: Box<dyn PeerStream>, code: &Option<String>, joins: &Joins<'a>) -> Result<RecvStack<'a>, DftpError>{
     // wait for a protocol table
     let mut message = ProtocolTable::default();
-    match message.pull(&mut peer) {
-        Ok(_) => {
-            let mut decl = Simple::default();
-            decl.content = SIMPLE_MSG_PN_ACC;
-            decl.shove(&mut peer)?;
-        },
-        Err(m) => {
-            // protocol request is fucked. send decline message
-            let mut decl = Simple::default();
-            decl.content = SIMPLE_MSG_PN_DEC;
-            decl.shove(&mut peer)?;
-            return Err(m);
-        }
+    let mut reply = Simple::default();
+    if let Err(m) = message.pull(&mut peer) {
+        // protocol request is fucked. send decline message
+        reply.content = SIMPLE_MSG_PN_DEC;
+        reply.shove(&mut peer)?;
+        return Err(m);
+    }
+    let (content, refusal) = table_verdict(&message, code.is_some());
+    // the sender opens the data connections as soon as it has the answer,
+    // the daemon has to know them by then
+    let expected = match joins {
+        Joins::Handover(registry) if refusal.is_none() && message.streams > 1 => Some(registry.expect(message.session)),
+        _ => None,
+    };
+    reply.content = content;
+    reply.shove(&mut peer)?;
+    if let Some(e) = refusal {
+        return Err(e);
     }
 
     // if we're here it means that protocl negotiation was successful.
 
-    // recv file header if necessary
-    let mut fileheader: Option<FileHeader> = None;
-    if message.isfile {
-        fileheader = match recv_file_header(&mut peer) {
-            Ok(e) => Some(e),
-            Err(m) => {return Err(format!("Error while receiving file header: {:}", m.to_string()));}
-        };
-    }
+    let keys = match code {
+        Some(c) => Some(pair_recv(&mut peer, c, &table_bytes(&message)?)?),
+        None => None,
+    };
+
+    let replies: Box<dyn Write> = match peer.try_clone_stream() {
+        Ok(p) => p,
+        Err(m) => { return Err(DftpError::Network("Cannot set up the reply channel".to_string(), Some(m))); }
+    };
 
     // time to upgrade protocol
 
-    let reader: Box<dyn Read> = Box::new(peer);
-    let reader = if message.compressed {
-        wrap_decompressor(reader)
-    } else { reader };
+    let reader: Box<dyn Read> = peer;
+    let (reader, replies) = match keys.as_ref() {
+        Some(k) => (wrap_decryptor(reader, &k.incoming), wrap_encryptor(replies, &k.outgoing)),
+        None => (reader, replies),
+    };
+    let reader = wrap_decompressor(reader, message.codec)?;
 
     // more protocol upgrades here
     
-    Ok((reader, fileheader, message))
+    Ok((reader, replies, message, keys, expected))
 }
 
-fn compute_hash(file: &mut File) -> [u8; 32] {
-    // Hasing is premature. Need better cmd coverage since hashing adds a lot of latency
-    //let mut sha = Sha256::new();
-    //std::io::copy(file, &mut sha).expect("Should be able to hash anything");
-    //let hash = sha.finalize();
-    //assert!(hash.len() == 32usize);
-    //let mut hashbuf = [0u8; 32];
-    //for i in 0..32{
-    //    hashbuf[i] = hash[i];
-    //}
-    return [0u8; 32];
+// opens the data connections of a --streams session next to the control
+// connection. they go through the same handshake and transport as the
+// first one, then name the session they belong to.
+fn open_streams_send(rendezvous: &Rendezvous, table: &ProtocolTable, codec: &Codec, security: &Security, keys: &Option<SessionKeys>, uplink: &Arc<Uplink>) -> Result<Pool, DftpError> {
+    let mut streams = Vec::new();
+    for index in 0..table.streams {
+        let mut peer = rendezvous.another_stream()?;
+        handshake_send(peer.as_mut(), true)?;
+        let mut peer = transport_send(peer, &security.tls)?;
+        let mut join = StreamJoin::default();
+        join.session = table.session;
+        join.index = index;
+        join.shove(&mut peer)?;
+        let mut reply = Simple::default();
+        reply.pull(&mut peer)?;
+        if reply.content != SIMPLE_MSG_PN_ACC {
+            return Err(DftpError::Declined(format!("The receiver refused data connection {}", index)));
+        }
+        let key = keys.as_ref().map(|k| stream_keys(k, index).outgoing);
+        streams.push(DataStream{ peer, key });
+    }
+    Ok(start_send_pool(streams, codec, uplink))
 }
 
-fn send_file_header(peer: &mut TcpStream, filename: &String) -> Result<(), Error>{
-    let mut header = FileHeader::default();
-    header.file_type = FH_TYPE_FILE; // dir sending is not available for now...
-    header.name = String::from(if filename.as_str() == "stdin" { "stdin" } else { Path::new(filename).file_name().unwrap().to_str().unwrap() });
-    let mut file = File::open(filename)?;
-    let size = file.metadata()?.len() as u64;
-    header.length = size;
-    header.hash = compute_hash(&mut file);
-    assert!(Sha256::output_size() == 32usize);
-    header.shove(peer).unwrap();
-    Ok(())
+// where a receiving session gets its data connections from
+pub enum Joins<'a> {
+    Rendezvous(&'a Rendezvous), // wherever the first connection came from
+    Handover(&'a Registry),     // the daemon's accept loop
 }
 
-fn recv_file_header(peer: &mut TcpStream) -> Result<FileHeader, Error>{
-    let mut header = FileHeader::default();
-    match header.pull(peer) {
-        Ok(_) => {},
-        Err(e) => {
-            return Err(Error::new(BrokenPipe, e));
-        }
-    };
-    Ok(header)
+// turns away a data connection nobody is waiting for
+pub fn join_decline(mut peer: Box<dyn PeerStream>) {
+    let mut reply = Simple::default();
+    reply.content = SIMPLE_MSG_PN_DEC;
+    let _ = reply.shove(&mut peer);
 }
 
-fn stringify_hash(hash: &[u8]) -> String {
-    let mut s = String::new();
-    //for i in 0..32 {
-        //write!(&mut s, "{:02x}", hash[i]).expect("should be able to write to string");
-    //}
-    return s;
+// the rest of a data connection after its handshake
+pub fn join_recv(peer: Box<dyn PeerStream>, security: &Security) -> Result<(StreamJoin, Box<dyn PeerStream>), DftpError> {
+    let mut peer = transport_recv(peer, &security.tls)?;
+    let mut join = StreamJoin::default();
+    join.pull(&mut peer)?;
+    Ok((join, peer))
 }
 
-fn print_file_info_send(filename: &String, compressed: bool){
-    if filename == "stdin" {return}; // dont fuck up the output!!
-    println!("Sending file: {} [{:}]", Path::new(filename).file_name().unwrap().to_str().unwrap(), stringify_hash(&compute_hash(File::open(filename).as_mut().unwrap())));
-    if compressed {
-        println!("Compressed stream");
+fn accept_join(rendezvous: &Rendezvous, security: &Security) -> Result<(StreamJoin, Box<dyn PeerStream>), DftpError> {
+    let mut peer = rendezvous.another_stream()?;
+    if !handshake_recv(peer.as_mut())? {
+        return Err(DftpError::Protocol("The sender opened a new session instead of a data connection".to_string()));
     }
-    println!();
+    join_recv(peer, security)
 }
 
-fn print_file_info(filename: &String, fileheader: &Option<FileHeader>, compressed: bool){
-    if fileheader.is_none() {return};
-    if filename == "stdout" {return}; // dont fuck up the output!!
-    let fileheader = fileheader.as_ref().unwrap();
-    println!("Receiving file: {} [{:}]", fileheader.name, stringify_hash(&fileheader.hash));
-    println!("Writing to: {}", filename);
-    if compressed {
-        println!("Compressed stream");
+fn open_streams_recv(joins: &Joins, expected: Option<Expected>, table: &ProtocolTable, security: &Security, keys: &Option<SessionKeys>) -> Result<Pool, DftpError> {
+    let mut slots: Vec<Option<DataStream>> = (0..table.streams).map(|_| None).collect();
+    for _ in 0..table.streams {
+        let (join, mut peer) = match joins {
+            Joins::Rendezvous(rendezvous) => accept_join(rendezvous, security)?,
+            Joins::Handover(_) => expected.as_ref().expect("registered during negotiation").next()?,
+        };
+        let index = join.index as usize;
+        let mut reply = Simple::default();
+        if join.session != table.session || index >= slots.len() || slots[index].is_some() {
+            reply.content = SIMPLE_MSG_PN_DEC;
+            reply.shove(&mut peer)?;
+            return Err(DftpError::Protocol("A data connection does not belong to this session".to_string()));
+        }
+        reply.content = SIMPLE_MSG_PN_ACC;
+        reply.shove(&mut peer)?;
+        let key = keys.as_ref().map(|k| stream_keys(k, join.index).incoming);
+        slots[index] = Some(DataStream{ peer, key });
     }
-    println!();
+    Ok(start_recv_pool(slots.into_iter().flatten().collect(), table.codec))
 }
 
-
-pub fn send(port:i32, filename:String, addrstr:String, compress: bool){
-    let mut sender = match build_send_stream(port, addrstr) {
-        Ok(s) => s,
-        Err(m) => { eprintln!("Error while starting stream:\n  {}", m); exit(1); }
-    };
-    match handshake_send(&mut sender) {
-        Ok(_) => {},
-        Err(s) => { eprintln!("Handshake failed: {}", s); exit(1); }
-    };
-    let mut sender = match protocol_adjust_send(sender, &filename, compress) {
-        Ok(s) => s,
-        Err(m) => { eprintln!("{}", m); exit(1); }
+pub(crate) fn send_file_header(peer: &mut dyn Write, entry: &LocalEntry) -> Result<(), DftpError>{
+    let mut header = FileHeader::default();
+    header.file_type = match (&entry.link, entry.is_dir) {
+        (Some(Link::Symbolic(_)), _) => FH_TYPE_SYMLINK,
+        (Some(Link::Hard(_)), _) => FH_TYPE_HARDLINK,
+        (None, true) => FH_TYPE_DIR,
+        (None, false) if entry.sparse => FH_TYPE_SPARSE,
+        (None, false) => FH_TYPE_FILE,
     };
-    let mut reader = match build_file_reader(&filename){
-        Ok(r) => r,
-        Err(m) => { eprintln!("Error while reading file:\n  {}", m);exit(1); }
+    header.name = entry.name.clone();
+    header.length = entry.length;
+    // the digest is computed while streaming and follows the payload
+    // in a FileTrailer, so the header hash is left zeroed.
+    header.shove(peer)?;
+    Ok(())
+}
+
+// the metadata of a local entry as it goes on the wire
+pub(crate) fn entry_meta(meta: &fs::Metadata) -> FileMeta {
+    let mut m = FileMeta::default();
+    m.mode = meta.mode() & 0o7777;
+    m.mtime = meta.mtime();
+    m.mtime_nsec = meta.mtime_nsec() as u32;
+    m.atime = meta.atime();
+    m.atime_nsec = meta.atime_nsec() as u32;
+    m.uid = meta.uid();
+    m.gid = meta.gid();
+    m
+}
+
+// a symlink is described as itself, everything else as what it leads to
+pub(crate) fn stat_entry(entry: &LocalEntry) -> Result<FileMeta, DftpError>{
+    let meta = match entry.link {
+        Some(Link::Symbolic(_)) => fs::symlink_metadata(&entry.path),
+        _ => fs::metadata(&entry.path),
     };
-    let mut bufflen:usize = 0;
-    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
-    print_file_info_send(&filename, compress);
-    let mut total: u32 = 0;
-    let mut counter = 0;
-    let mut bufflen_acc = 0;
-    let mut now = SystemTime::now();
-    let length = if filename == "stdin" { 0 } else { File::open(&filename).unwrap().metadata().unwrap().len() };
-    loop{
-        total += bufflen as u32;
-        bufflen = reader.read(&mut buff).expect("Unexpected Error while reading from file. Aborting.");
-        if bufflen == 0 { break; }
-        if counter == 0 {
-            if filename != "stdin" {
-                let micros = now.elapsed().unwrap().as_micros() as u64;
-                let speed = (bufflen_acc * 1_000_000) as f64 / micros as f64;
-                let speed = speed / 1024f64;
-                print_status(speed, length as f32 / (1024 * 1024) as f32, total as f32 / (1024 * 1024) as f32);
-                bufflen_acc = 0;
-            now = SystemTime::now();
+    match meta {
+        Ok(m) => Ok(entry_meta(&m)),
+        Err(m) => Err(DftpError::Io(format!("Cannot stat {}", entry.path.display()), Some(m))),
+    }
+}
 
-            }
+fn send_file_meta(peer: &mut dyn Write, entry: &LocalEntry) -> Result<(), DftpError>{
+    stat_entry(entry)?.shove(peer).map_err(|e| e.context(|m| format!("Error while sending file metadata: {}", m)))?;
+    Ok(())
+}
+
+// puts what the sender said about an entry on it once it is complete. the
+// owner only changes when running as root, and the setuid, setgid and
+// sticky bits never come over. whatever does not take is only a warning,
+// the data is in place by then.
+pub(crate) fn apply_meta(target: &Path, meta: &FileMeta) {
+    let mut failed: Vec<String> = Vec::new();
+    if unsafe { libc::geteuid() } == 0 {
+        if let Err(m) = chown(target, Some(meta.uid), Some(meta.gid)) {
+            failed.push(format!("owner ({})", m));
         }
-        match sender.write_all(&buff[0..bufflen]) {
-            Err(e) => {
-                match e.kind() {
-                    WouldBlock | TimedOut | ConnectionReset => {
-                        eprintln!("The sender has closed the connection. Aborting transfer.");
-                        break;
-                    }
-                    _ => { panic!("Unexpected network error. Aborting."); },
-                }
-            },
-            Ok(_)=>{}
-        };
-        bufflen_acc += bufflen;
-        counter += 1;
-        if counter == 80 { counter = 0; }
+    }
+    // before the mode, which may take away the right to open it
+    match meta_time(meta.atime, meta.atime_nsec).zip(meta_time(meta.mtime, meta.mtime_nsec)) {
+        Some((atime, mtime)) => {
+            let times = FileTimes::new().set_accessed(atime).set_modified(mtime);
+            if let Err(m) = File::open(target).and_then(|f| f.set_times(times)) {
+                failed.push(format!("times ({})", m));
+            }
+        },
+        None => failed.push("times (out of range)".to_string()),
+    }
+    if let Err(m) = fs::set_permissions(target, Permissions::from_mode(meta.mode & 0o777)) {
+        failed.push(format!("permissions ({})", m));
+    }
+    if !failed.is_empty() {
+        eprintln!("WARNING: could not restore the {} of {}", failed.join(", "), target.display());
     }
 }
 
-pub fn recv(port:i32, filename:String, _compress: bool) {
-    let mut recvr = match build_recv_stream(port) {
-        Ok(s) => s,
-        Err(m) => { eprintln!("Error while starting stream:\n  {}", m); exit(1); }
-    };
-    match handshake_recv(&mut recvr) {
-        Ok(_) => {},
-        Err(s) => { eprintln!("Handshake failed: {}", s); exit(1); }
+fn meta_time(secs: i64, nsec: u32) -> Option<SystemTime> {
+    let since = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
     };
-    let (mut recvr, fileheader, pt_header) = match protocol_adjust_recv(recvr) {
-        Ok(s) => s,
-        Err(m) => { eprintln!("{}", m); exit(1); }
-    };
-    let mut filename = filename;
-    if fileheader.is_some() && filename == "stdin" {
-        filename = fileheader.as_ref().unwrap().name.clone();
+    since?.checked_add(Duration::from_nanos(nsec as u64))
+}
+
+pub(crate) fn is_link(header: &FileHeader) -> bool {
+    header.file_type == FH_TYPE_SYMLINK || header.file_type == FH_TYPE_HARDLINK
+}
+
+// how many bytes of link target follow a link header, checked before
+// anything is allocated for them
+pub(crate) fn link_len(header: &FileHeader) -> Result<usize, DftpError>{
+    if header.length > MAX_NAME_LEN as u64 {
+        return Err(DftpError::Protocol(format!("link target of {} bytes, at most {} are allowed", header.length, MAX_NAME_LEN)));
     }
-    let mut writer = match build_file_writer(&filename){
-        Ok(r) => r,
-        Err(m) => { eprintln!("Error while writing to file:\n  {}", m); exit(1); }
-    };
-    let mut bufflen:usize = 0;
-    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
-    print_file_info(&filename, &fileheader, pt_header.compressed);
-    let mut total: u32 = 0;
-    let mut counter = 0;
-    let mut bufflen_acc = 0;
-    let mut now = SystemTime::now();
-    loop{
-        total += bufflen as u32;
-        bufflen = match recvr.read(&mut buff) {
-            Ok(n) => n,
-            Err(m) => {
-                match m.kind() {
-                    ConnectionReset => {
-                        eprintln!("Connection closed by peer");
-                        break;
-                    },
-                    _ => {
-                        panic!("{}", m);
-                    },
-                }
-            }
-        };
-        if bufflen == 0 { break; }
-        if counter == 0 {
-            if let Some(fh) = fileheader.as_ref() {
-                let micros = now.elapsed().unwrap().as_micros() as u64;
-                let speed = (bufflen_acc * 1_000_000) as f64 / micros as f64;
-                let speed = speed / 1024f64;
-                print_status(speed, fh.length as f32 / (1024 * 1024) as f32, total as f32 / (1024 * 1024) as f32);
-                bufflen_acc = 0;
-            now = SystemTime::now();
+    Ok(header.length as usize)
+}
 
-            }
-        }
-        writer.write_all(&buff[0..bufflen]).expect("Unexpected network error. Aborting.");
-        if filename == "stdin" { writer.flush().expect("wtf?"); }
-        bufflen_acc += bufflen;
-        counter += 1;
-        if counter == 80 { counter = 0; }
+pub(crate) fn recv_link(peer: &mut dyn Read, header: &FileHeader) -> Result<String, DftpError>{
+    let mut buf = vec![0u8; link_len(header)?];
+    peer.read_exact(&mut buf).map_err(wire_error)?;
+    match String::from_utf8(buf) {
+        Ok(t) => Ok(t),
+        Err(_) => Err(DftpError::Protocol(format!("link target of {} is not valid utf8", header.name))),
     }
 }
 
-fn print_status(speed: f64, length: f32, total: f32) {
-    static mut SIZE: usize = 0;
-    unsafe {
-        if SIZE > 0 {
-            print!("\r{}", " ".repeat(SIZE));
-            print!("\r");
+// makes a link the sender sent at target. a hard link may only point at a
+// file received earlier in the session, which received maps names to. a
+// symlink that check_link does not let through is left out with a warning,
+// trees often have some pointing at absolute paths.
+pub(crate) fn place_link(root: &Path, target: &Path, header: &FileHeader, link: &str, received: &HashMap<String, PathBuf>) -> Result<(), DftpError>{
+    let source = if header.file_type == FH_TYPE_HARDLINK {
+        check_name(link)?;
+        match received.get(link) {
+            Some(s) => Some(s),
+            None => { return Err(DftpError::Protocol(format!("{} is a hard link to {}, which was not sent before", header.name, link))); }
+        }
+    } else {
+        if let Err(e) = check_link(root, target, link) {
+            eprintln!("WARNING: skipping symlink {}: {}", header.name, describe(&e));
+            return Ok(());
+        }
+        None
+    };
+    // a link left by an earlier run of the same transfer
+    if fs::symlink_metadata(target).is_ok_and(|m| !m.is_dir()) {
+        if let Err(m) = fs::remove_file(target) {
+            return Err(DftpError::Io(format!("Error while replacing {}", target.display()), Some(m)));
         }
     }
-
-    let ratio = total as f32 / length as f32;
-    let percent = ratio * 100f32;
+    let made = match source {
+        Some(s) => fs::hard_link(s, target),
+        None => symlink(link, target),
+    };
+    match made {
+        Ok(_) => Ok(()),
+        Err(m) => Err(DftpError::Io(format!("Error while creating link {}", target.display()), Some(m))),
+    }
+}
+
+pub(crate) fn send_end_header(peer: &mut dyn Write) -> Result<(), DftpError>{
+    let mut header = FileHeader::default();
+    header.file_type = FH_TYPE_END;
+    header.shove(peer)?;
+    Ok(())
+}
+
+pub(crate) fn recv_file_header(peer: &mut dyn Read) -> Result<FileHeader, DftpError>{
+    let mut header = FileHeader::default();
+    header.pull(peer)?;
+    if header.file_type != FH_TYPE_END {
+        check_name(&header.name).map_err(|e| e.context(|m| format!("refusing unsafe name from the sender: {}", m)))?;
+    }
+    Ok(header)
+}
+
+// what the digest of an entry is called when shown. that of a sparse file
+// is not what sha256sum says about it, see hash_map.
+fn digest_label(sparse: bool) -> &'static str {
+    if sparse { "sparse digest" } else { "sha256" }
+}
+
+fn stringify_hash(hash: &[u8]) -> String {
+    let mut s = String::new();
+    for b in hash {
+        write!(&mut s, "{:02x}", b).expect("should be able to write to string");
+    }
+    s
+}
+
+fn print_file_info_send(entry: &LocalEntry, quiet: bool){
+    if quiet { return; }
+    println!("Sending file: {}", entry.name);
+}
+
+fn print_file_info(target: &Path, fileheader: &FileHeader, quiet: bool){
+    if quiet { return; }
+    println!("Receiving file: {}", fileheader.name);
+    println!("Writing to: {}", target.display());
+}
+
+enum Answer { Yes, No, All }
+
+// shows what is about to arrive and asks whether to take it. anything but
+// a yes, including a closed stdin, is a no.
+fn ask_accept(fileheader: &FileHeader, peer_addr: &SocketAddr) -> Answer {
+    let kind = match fileheader.file_type {
+        FH_TYPE_DIR => "directory",
+        FH_TYPE_SYMLINK => "symlink",
+        FH_TYPE_HARDLINK => "hard link",
+        _ => "file",
+    };
+    let hash = if fileheader.hash == [0u8; 32] {
+        "sent after the data".to_string()
+    } else { stringify_hash(&fileheader.hash) };
+    eprintln!("Incoming {} from {}", kind, peer_addr);
+    eprintln!("  name:   {}", fileheader.name);
+    eprintln!("  size:   {:.2} MiB ({} bytes)", fileheader.length as f64 / (1024 * 1024) as f64, fileheader.length);
+    eprintln!("  sha256: {}", hash);
+    eprint!("Accept? [y/N/a(ll)] ");
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).is_err() {
+        return Answer::No;
+    }
+    parse_answer(&line)
+}
+
+fn parse_answer(line: &str) -> Answer {
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => Answer::Yes,
+        "a" | "all" => Answer::All,
+        _ => Answer::No,
+    }
+}
+
+fn print_session_info(codec: u8, streams: u8, quiet: bool){
+    if quiet { return; }
+    if codec != CODEC_NONE {
+        println!("Compressed stream ({})", codec_name(codec));
+    }
+    if streams > 1 {
+        println!("{} data connections", streams);
+    }
+    println!();
+}
+
+// secrets for a session. whichever end waits for the other makes them up
+// and shows them, the dialing end has to be told.
+pub struct Security {
+    pub code: Option<String>,
+    pub tls: Option<TlsRole>,
+}
+
+pub fn prepare_security(listen: bool, encrypt: bool, code: Option<String>, tls: bool, fingerprint: Option<[u8; 32]>) -> Result<Security, DftpError> {
+    let code = if encrypt && listen { Some(generate_code()?) } else { code };
+    let tls = if tls && listen {
+        Some(TlsRole::Server(generate_identity()?))
+    } else { fingerprint.map(TlsRole::Client) };
+    // stdout may be carrying the file
+    if listen {
+        if let Some(c) = code.as_ref() {
+            eprintln!("Pairing code: {}", c);
+        }
+        if let Some(TlsRole::Server(i)) = tls.as_ref() {
+            eprintln!("TLS fingerprint: {}", format_fingerprint(&i.fingerprint));
+        }
+    }
+    Ok(Security{ code, tls })
+}
+
+// quiet keeps stdout clear of the progress and of what is being sent
+#[allow(clippy::too_many_arguments)]
+pub fn send(port:i32, filenames:Vec<String>, connect: Connect, codec: Codec, streams: u8, limit: Option<u64>, security: Security, preserve: bool, follow: bool, quiet: bool) -> Result<(), DftpError>{
+    // walk the trees before connecting so a bad path does not bother the peer
+    let entries = collect_session(&filenames, follow)?;
+    if let Connect::Listen = connect {
+        eprintln!("Waiting for the receiver on port {}", port);
+    }
+    let (mut sender, rendezvous) = build_stream(port, &connect).map_err(|e| e.context(|m| format!("Error while starting stream:\n  {}", m)))?;
+    handshake_send(&mut sender, false).map_err(|e| e.context(|m| format!("Handshake failed: {}", m)))?;
+    send_session(Box::new(sender), rendezvous, &filenames, &entries, &codec, streams, limit, &security, preserve, quiet)
+}
+
+// the entries of every file named, nothing for stdin. follow says whether
+// symlinks inside directories are sent as what they point at.
+pub(crate) fn collect_session(filenames: &[String], follow: bool) -> Result<Vec<LocalEntry>, DftpError> {
+    let mut entries: Vec<LocalEntry> = Vec::new();
+    let mut roots: Vec<String> = Vec::new();
+    let mut seen = HashMap::new();
+    if !defer_kind(&filenames[0]) {
+        return Ok(entries);
+    }
+    for filename in filenames.iter() {
+        let found = collect_entries(filename, follow, &mut seen).map_err(|e| e.context(|m| format!("Error while reading file:\n  {}", m)))?;
+        if let Some(root) = found.first() {
+            if roots.contains(&root.name) {
+                return Err(DftpError::Usage(format!("Cannot send two files named {} in one session", root.name)));
+            }
+            roots.push(root.name.clone());
+        }
+        entries.extend(found);
+    }
+    Ok(entries)
+}
+
+// everything after the handshake of a sending session
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn send_session(sender: Box<dyn PeerStream>, rendezvous: Rendezvous, filenames: &[String], entries: &[LocalEntry], codec: &Codec, streams: u8, limit: Option<u64>, security: &Security, preserve: bool, quiet: bool) -> Result<(), DftpError>{
+    let isfile = defer_kind(&filenames[0]);
+    let sender = transport_send(sender, &security.tls)?;
+    let uplink = Uplink::new(limit);
+    let (mut sender, mut replies, table, keys) = protocol_adjust_send(sender, filenames, codec, streams, &security.code, &uplink, preserve)?;
+    let pool = if table.streams > 1 {
+        Some(open_streams_send(&rendezvous, &table, codec, security, &keys, &uplink)
+             .map_err(|e| e.context(|m| format!("Error while opening the data connections: {}", m)))?)
+    } else { None };
+    drop(rendezvous);
+    if !isfile {
+        let reader = build_file_reader(&filenames[0]).map_err(|e| e.context(|m| format!("Error while reading file:\n  {}", m)))?;
+        let mut trailer = FileTrailer::default();
+        trailer.hash = send_stream(&mut sender, Box::new(reader))?;
+        if let Err(e) = trailer.shove(&mut sender).and_then(|_| sender.flush().map_err(wire_error)) {
+            return Err(e.context(|m| format!("\nError while sending stream digest: {}", m)));
+        }
+        return Ok(());
+    }
+    print_session_info(codec.id, table.streams, quiet);
+    let mut summary = Summary::new(quiet);
+    for entry in entries.iter() {
+        let head = entry_head(entry, table.meta)?;
+        if let Err(m) = sender.write_all(&head) {
+            return Err(wire_error(m).context(|m| format!("Error while sending file header: {}", m)));
+        }
+        if needs_accept(&entry.name) {
+            let mut reply = Simple::default();
+            if let Err(e) = sender.flush().map_err(wire_error).and_then(|_| reply.pull(&mut replies)) {
+                return Err(e.context(|m| format!("Error while waiting for the receiver: {}", m)));
+            }
+            accept_answer(reply.content, entry)?;
+        }
+        if let Some(link) = entry.link.as_ref() {
+            if let Err(m) = sender.write_all(link.target().as_bytes()) {
+                return Err(wire_error(m).context(|m| format!("Error while sending link target: {}", m)));
+            }
+            continue;
+        }
+        if entry.is_dir { continue; }
+        // the receiver tells us how much of the file it already has
+        let mut offer = ResumePoint::default();
+        if let Err(e) = sender.flush().map_err(wire_error).and_then(|_| offer.pull(&mut replies)) {
+            return Err(e.context(|m| format!("Error while waiting for the receiver: {}", m)));
+        }
+        let (file, start, sha) = resume_send(&entry.path, entry.length, &offer).map_err(|e| e.context(|m| format!("Error while reading file:\n  {}", m)))?;
+        let reader: Box<dyn Read> = Box::new(BufReader::new(file));
+        // sparse files stay on this connection
+        let ranges = pool.as_ref().filter(|_| !entry.sparse).and_then(|p| split_ranges(start, entry.length, p.size()));
+        let mut answer = ResumePoint::default();
+        answer.offset = start;
+        // the receiver cannot set up the ranges before it has the answer
+        let sent = answer.shove(&mut sender).and_then(|_| match ranges {
+            Some(_) => sender.flush().map_err(wire_error),
+            None => Ok(()),
+        });
+        if let Err(e) = sent {
+            return Err(e.context(|m| format!("Error while answering the receiver: {}", m)));
+        }
+        print_file_info_send(entry, quiet);
+        if start > 0 && !quiet {
+            println!("Resuming at {:.2} MiB", start as f64 / (1024 * 1024) as f64);
+        }
+        let hash = match (pool.as_ref(), ranges) {
+            (Some(pool), Some(ranges)) => send_parallel(pool, ranges, entry, reader, start, sha, &uplink, quiet)?,
+            _ if entry.sparse => send_sparse(&mut sender, entry, start, sha, &uplink, quiet)?,
+            _ => send_body(&mut sender, reader, entry.length, start, sha, &uplink, quiet)?,
+        };
+        let mut trailer = FileTrailer::default();
+        trailer.hash = hash;
+        if let Err(e) = trailer.shove(&mut sender) {
+            return Err(e.context(|m| format!("\nError while sending file digest: {}", m)));
+        }
+        if !quiet {
+            println!("\n{}: {}", digest_label(entry.sparse), stringify_hash(&trailer.hash));
+        }
+        summary.add(entry.length);
+    }
+    if let Err(e) = send_end_header(&mut sender).and_then(|_| sender.flush().map_err(wire_error)) {
+        return Err(e.context(|m| format!("Error while finishing the transfer: {}", m)));
+    }
+    if let Some(pool) = pool {
+        pool.finish();
+    }
+    summary.print("Sent");
+    Ok(())
+}
+
+// the part of an entry that goes out before the receiver has a say: its
+// header, and its metadata when the session carries any
+pub(crate) fn entry_head(entry: &LocalEntry, meta: bool) -> Result<Vec<u8>, DftpError> {
+    let mut head: Vec<u8> = Vec::new();
+    send_file_header(&mut head, entry).map_err(|e| e.context(|m| format!("Error while sending file header: {}", m)))?;
+    if meta {
+        send_file_meta(&mut head, entry)?;
+    }
+    Ok(head)
+}
+
+// top level entries have to be accepted by the receiver first
+pub(crate) fn needs_accept(name: &str) -> bool {
+    !name.contains('/')
+}
+
+pub(crate) fn accept_answer(content: u8, entry: &LocalEntry) -> Result<(), DftpError> {
+    match content {
+        SIMPLE_MSG_FH_ACC => Ok(()),
+        SIMPLE_MSG_FH_DEC => Err(DftpError::Declined(format!("The receiver declined {}. Aborting transfer.", entry.name))),
+        _ => Err(DftpError::Protocol("Malfunction 8".to_string())),
+    }
+}
+
+// picks where to continue a file from. the prefix the receiver offered is
+// hashed on our side as well, if it matches we carry on after it,
+// otherwise the whole file is sent again. returns the file to read the
+// rest from, where that starts and the sha256 of what is before it.
+pub(crate) fn resume_send(path: &Path, length: u64, offer: &ResumePoint) -> Result<(File, u64, Sha256), DftpError> {
+    let mut file = read_file_from(path, 0)?;
+    let mut sha = Sha256::new();
+    if offer.offset == 0 || offer.offset > length {
+        return Ok((file, 0, sha));
+    }
+    let hashed = match hash_prefix(&mut file, &mut sha, offer.offset) {
+        Ok(n) => n,
+        Err(m) => { return Err(DftpError::Io(format!("Error while reading {}", path.display()), Some(m))); }
+    };
+    if hashed == offer.offset && sha.clone().finalize()[..] == offer.hash[..] {
+        return Ok((file, offer.offset, sha));
+    }
+    Ok((read_file_from(path, 0)?, 0, Sha256::new()))
+}
+
+// sends the ranges of a file over the data connections. meanwhile the file
+// is hashed front to back here, the digest still goes over the control
+// connection once everything is out.
+#[allow(clippy::too_many_arguments)]
+fn send_parallel(pool: &Pool, ranges: Vec<Range>, entry: &LocalEntry, mut reader: Box<dyn Read>, start: u64, mut sha: Sha256, uplink: &Arc<Uplink>, quiet: bool) -> Result<[u8; 32], DftpError> {
+    let count = ranges.len();
+    let base = pool.moved();
+    if let Err(e) = pool.dispatch(&entry.path, ranges) {
+        return Err(e.context(|m| format!("Error while sending {}: {}", entry.name, m)));
+    }
+    let hashed = match hash_prefix(&mut reader, &mut sha, entry.length - start) {
+        Ok(n) => n,
+        Err(m) => { return Err(DftpError::Io(format!("Error while reading {}", entry.name), Some(m))); }
+    };
+    if let Err(e) = pool.wait(count, parallel_status(entry.length, start, base, Some(uplink.clone()), quiet)) {
+        return Err(e.context(|m| format!("\nError while sending {}: {}", entry.name, m)));
+    }
+    if hashed != entry.length - start {
+        return Err(DftpError::Io("\nThe file changed size during the transfer. Aborting.".to_string(), None));
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&sha.finalize());
+    Ok(hash)
+}
+
+// progress of a file going over the data connections, which count their
+// bytes from base. the speed is taken from the uplink when there is one.
+fn parallel_status(length: u64, start: u64, base: u64, uplink: Option<Arc<Uplink>>, quiet: bool) -> impl FnMut(u64) {
+    let speed_of = move |moved: u64| uplink.as_ref().map_or(moved, |u| u.sent());
+    let mut last = speed_of(base);
+    let mut now = SystemTime::now();
+    move |moved| {
+        let micros = now.elapsed().unwrap_or_default().as_micros() as u64;
+        let counted = speed_of(moved);
+        let speed = ((counted - last) * 1_000_000) as f64 / micros as f64 / 1024f64;
+        let total = start + moved - base;
+        print_status(quiet, speed, length as f32 / (1024 * 1024) as f32, total as f32 / (1024 * 1024) as f32);
+        last = counted;
+        now = SystemTime::now();
+    }
+}
+
+// sends the data extents of a sparse file from start on, after the map of
+// them
+fn send_sparse(sender: &mut Box<dyn Write>, entry: &LocalEntry, start: u64, sha: Sha256, uplink: &Uplink, quiet: bool) -> Result<[u8; 32], DftpError> {
+    let name = entry.path.display();
+    let mut file = read_file_from(&entry.path, 0)?;
+    let mut map = ExtentMap::default();
+    map.extents = data_extents(&file, start, entry.length);
+    if let Err(e) = map.shove(sender) {
+        return Err(e.context(|m| format!("Error while sending extent map: {}", m)));
+    }
+    let mut cursor = SparseCursor::new(&map, &entry.path, start, entry.length, sha)?;
+    let mut status = sparse_status(entry.length, Some(uplink), quiet);
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    while let Some(piece) = cursor.next() {
+        if let Some(offset) = piece.seek {
+            if let Err(m) = file.seek(SeekFrom::Start(offset)) {
+                return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+            }
+        }
+        let n = match file.read(&mut buff[..piece.want]) {
+            Ok(0) => { return Err(DftpError::Io("\nThe file changed size during the transfer. Aborting.".to_string(), None)); }
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io(format!("\nError while reading {}", name), Some(m))); }
+        };
+        if let Err(m) = sender.write_all(&buff[..n]) {
+            return Err(wire_error(m).context(|m| format!("\nError while sending: {}", m)));
+        }
+        cursor.moved(&buff[..n]);
+        status(cursor.at(), n as u64);
+    }
+    Ok(cursor.finish())
+}
+
+// progress through a sparse file, by where in the file it is. the speed is
+// of the bytes actually moved, as the uplink counts them on the sending end.
+fn sparse_status(length: u64, uplink: Option<&Uplink>, quiet: bool) -> impl FnMut(u64, u64) + '_ {
+    let mut moved: u64 = 0;
+    let mut mark = uplink.map_or(0, |u| u.sent());
+    let mut now = SystemTime::now();
+    let mut first = true;
+    move |at, n| {
+        moved += n;
+        if !first && now.elapsed().unwrap_or_default() < STATUS_INTERVAL { return; }
+        first = false;
+        let counted = uplink.map_or(moved, |u| u.sent());
+        let micros = now.elapsed().unwrap_or_default().as_micros().max(1) as u64;
+        let speed = (counted.saturating_sub(mark) * 1_000_000) as f64 / micros as f64 / 1024f64;
+        print_status(quiet, speed, length as f32 / (1024 * 1024) as f32, at as f32 / (1024 * 1024) as f32);
+        mark = counted;
+        now = SystemTime::now();
+    }
+}
+
+// streams a payload to the peer, exactly as long as its header says. the
+// reader and sha are expected to be past the first start bytes already.
+// returns the sha256 of the whole file.
+// the speed shown is what goes out on the wire, after compression.
+#[allow(clippy::too_many_arguments)]
+fn send_body(sender: &mut Box<dyn Write>, mut reader: Box<dyn Read>, length: u64, start: u64, sha: Sha256, uplink: &Uplink, quiet: bool) -> Result<[u8; 32], DftpError> {
+    let mut payload = Payload::new(Some(length), start, sha);
+    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
+    let mut first = true;
+    let mut sent_mark = uplink.sent();
+    let mut now = SystemTime::now();
+    loop{
+        let want = payload.want();
+        if want == 0 { break; }
+        let bufflen = match reader.read(&mut buff[0..want]) {
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io("\nError while reading the input".to_string(), Some(m))); }
+        };
+        if bufflen == 0 { break; }
+        // a limited transfer can crawl, so this goes by the clock
+        if first || now.elapsed().unwrap_or_default() >= STATUS_INTERVAL {
+            let micros = now.elapsed().unwrap_or_default().as_micros() as u64;
+            let sent = uplink.sent();
+            let speed = ((sent - sent_mark) * 1_000_000) as f64 / micros as f64;
+            let speed = speed / 1024f64;
+            print_status(quiet, speed, length as f32 / (1024 * 1024) as f32, payload.total() as f32 / (1024 * 1024) as f32);
+            sent_mark = sent;
+            now = SystemTime::now();
+            first = false;
+        }
+        if let Err(e) = sender.write_all(&buff[0..bufflen]) {
+            return Err(send_error(e));
+        }
+        payload.moved(&buff[0..bufflen]);
+    }
+    payload.sent()
+}
+
+// stdin has no length to announce, so it goes in frames of up to
+// TRANSFER_BUFF_SIZE bytes, each after its length, and an empty one ends
+// it. a stream cut short is then told apart from one that is through.
+pub(crate) fn frame_head(n: usize) -> [u8; 4] {
+    (n as u32).to_be_bytes()
+}
+
+pub(crate) fn frame_length(head: [u8; 4]) -> Result<usize, DftpError> {
+    let n = u32::from_be_bytes(head) as usize;
+    if n > TRANSFER_BUFF_SIZE {
+        return Err(DftpError::Protocol(format!("Error when unpacking a stream frame of {} bytes, at most {} are allowed", n, TRANSFER_BUFF_SIZE)));
+    }
+    Ok(n)
+}
+
+// sends stdin in frames. returns the sha256 of all of it, for the trailer
+// that follows.
+fn send_stream(sender: &mut Box<dyn Write>, mut reader: Box<dyn Read>) -> Result<[u8; 32], DftpError> {
+    let mut payload = Payload::new(None, 0, Sha256::new());
+    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
+    loop {
+        let n = match reader.read(&mut buff) {
+            Ok(n) => n,
+            Err(m) => { return Err(DftpError::Io("\nError while reading the input".to_string(), Some(m))); }
+        };
+        if let Err(e) = sender.write_all(&frame_head(n)).and_then(|_| sender.write_all(&buff[..n])) {
+            return Err(send_error(e));
+        }
+        if n == 0 { break; }
+        payload.moved(&buff[..n]);
+    }
+    payload.sent()
+}
+
+// what the digest of a stream has to be, there is no file to remove when
+// it is not
+pub(crate) fn check_stream(target: &str, hash: &[u8; 32], trailer: &FileTrailer) -> Result<(), DftpError> {
+    if hash[..] != trailer.hash[..] {
+        return Err(DftpError::Integrity(format!("\nIntegrity check failed for {}!\n  expected: {}\n  received: {}",
+                           target, stringify_hash(&trailer.hash), stringify_hash(hash))));
+    }
+    Ok(())
+}
+
+pub(crate) fn send_error(e: Error) -> DftpError {
+    match e.kind() {
+        WouldBlock | TimedOut | ConnectionReset | BrokenPipe => {
+            DftpError::Network("The receiver has closed the connection. Aborting transfer.".to_string(), None)
+        }
+        _ => DftpError::Network("Error while sending".to_string(), Some(e)),
+    }
+}
+
+// the bookkeeping of one payload on its way, the same on both ends and in
+// the async loops: how much may be moved next without running into the
+// trailer, how much has been and the sha256 of all of it
+pub(crate) struct Payload {
+    length: Option<u64>, // none for stdin, which comes in frames
+    total: u64,
+    sha: Sha256,
+}
+
+impl Payload {
+    // sha is of the first start bytes, which are through already
+    pub(crate) fn new(length: Option<u64>, start: u64, sha: Sha256) -> Payload {
+        Payload{ length, total: start, sha }
+    }
+
+    // how much to move next, 0 once the payload is through
+    pub(crate) fn want(&self) -> usize {
+        match self.length {
+            Some(l) => std::cmp::min(TRANSFER_BUFF_SIZE as u64, l - self.total) as usize,
+            None => TRANSFER_BUFF_SIZE,
+        }
+    }
+
+    pub(crate) fn moved(&mut self, data: &[u8]) {
+        self.sha.update(data);
+        self.total += data.len() as u64;
+    }
+
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+
+    // the digest of a payload that went out, which has to be as long as
+    // its header said
+    pub(crate) fn sent(self) -> Result<[u8; 32], DftpError> {
+        if self.length.is_some_and(|l| l != self.total) {
+            return Err(DftpError::Io("\nThe file changed size during the transfer. Aborting.".to_string(), None));
+        }
+        Ok(self.received().1)
+    }
+
+    // how much came in and its digest. a peer that went away early only
+    // shows in the count.
+    pub(crate) fn received(self) -> (u64, [u8; 32]) {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.sha.finalize());
+        (self.total, hash)
+    }
+}
+
+// walks the data extents of a sparse payload front to back on either end.
+// says where the next data is and how much of it to move, and keeps the
+// digest, see hash_map.
+pub(crate) struct SparseCursor {
+    extents: std::vec::IntoIter<Extent>,
+    length: u64,
+    at: u64,  // how far into the file the payload is
+    end: u64, // end of the extent being moved
+    sha: Sha256,
+}
+
+pub(crate) struct Piece {
+    pub(crate) seek: Option<u64>, // where the data is, when it is not right after the last piece
+    pub(crate) want: usize,
+}
+
+impl SparseCursor {
+    // the map came from the peer on the receiving end, so it is checked to
+    // lie between start and length
+    pub(crate) fn new(map: &ExtentMap, target: &Path, start: u64, length: u64, mut sha: Sha256) -> Result<SparseCursor, DftpError> {
+        if map.extents.first().is_some_and(|e| e.offset < start) || map.extents.last().is_some_and(|e| e.offset + e.length > length) {
+            return Err(DftpError::Protocol(format!("extent map of {} does not fit between {} and {}", target.display(), start, length)));
+        }
+        hash_map(&mut sha, map);
+        Ok(SparseCursor{ extents: map.extents.clone().into_iter(), length, at: start, end: start, sha })
+    }
+
+    pub(crate) fn next(&mut self) -> Option<Piece> {
+        let mut seek = None;
+        if self.at == self.end {
+            let extent = self.extents.next()?;
+            hash_hole(&mut self.sha, extent.offset - self.at);
+            self.at = extent.offset;
+            self.end = extent.offset + extent.length;
+            seek = Some(self.at);
+        }
+        let want = std::cmp::min(TRANSFER_BUFF_SIZE as u64, self.end - self.at) as usize;
+        Some(Piece{ seek, want })
+    }
+
+    pub(crate) fn moved(&mut self, data: &[u8]) {
+        self.sha.update(data);
+        self.at += data.len() as u64;
+    }
+
+    pub(crate) fn at(&self) -> u64 {
+        self.at
+    }
+
+    // the digest, once all the data is through
+    pub(crate) fn finish(mut self) -> [u8; 32] {
+        hash_hole(&mut self.sha, self.length - self.at);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.sha.finalize());
+        hash
+    }
+}
+
+// where a receiving session puts what it gets
+#[derive(Clone)]
+pub struct Inbox {
+    pub filename: String, // output name, "stdin" for the sender's own names
+    pub dir: String,
+    pub yes: bool,
+    pub log: bool, // log each file, for the daemon
+    pub preserve: bool, // apply the sender's metadata, when it sends any
+    pub quiet: bool, // nothing on stdout but a stream received there
+}
+
+// everything after the handshake of a receiving session
+pub fn recv_session(recvr: Box<dyn PeerStream>, peer_addr: &SocketAddr, joins: &Joins, inbox: &Inbox, security: &Security) -> Result<(), DftpError> {
+    let recvr = transport_recv(recvr, &security.tls)?;
+    let (mut recvr, mut replies, pt_header, keys, expected) = protocol_adjust_recv(recvr, &security.code, joins)?;
+    let pool = if pt_header.streams > 1 {
+        Some(open_streams_recv(joins, expected, &pt_header, security, &keys)
+             .map_err(|e| e.context(|m| format!("Error while opening the data connections: {}", m)))?)
+    } else { None };
+    let (filename, dir) = (&inbox.filename, &inbox.dir);
+    if !pt_header.isfile {
+        // nobody is watching a daemon's stdout
+        let target = if filename == "stdin" && inbox.log {
+            let secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            Path::new(dir).join(format!("stdin-{}-{}", secs, peer_addr.port())).to_string_lossy().to_string()
+        } else if filename == "stdin" { filename.clone() } else {
+            Path::new(dir).join(filename).to_string_lossy().to_string()
+        };
+        let writer = build_file_writer(&target).map_err(|e| e.context(|m| format!("Error while writing to file:\n  {}", m)))?;
+        let (total, hash) = recv_stream(&mut recvr, writer, target == "stdin")?;
+        let mut trailer = FileTrailer::default();
+        if let Err(e) = trailer.pull(&mut recvr) {
+            return Err(e.context(|m| format!("\nError while receiving stream digest: {}", m)));
+        }
+        check_stream(&target, &hash, &trailer)?;
+        if inbox.log {
+            log(&format!("{}: received {} ({} bytes)", peer_addr, target, total));
+        }
+        return Ok(());
+    }
+    print_session_info(pt_header.codec, pt_header.streams, inbox.quiet);
+    // nothing the sender names may end up outside of this
+    let root = output_root(dir, filename, pt_header.multi);
+    make_dir(&root)?;
+    let tag = session_tag(&pt_header)?;
+    let mut summary = Summary::new(inbox.quiet);
+    let mut accept_all = inbox.yes;
+    // directories get theirs last, writing into them changes their times
+    let mut dirs: Vec<(PathBuf, FileMeta)> = Vec::new();
+    // what hard links may point at
+    let mut received: HashMap<String, PathBuf> = HashMap::new();
+    loop {
+        let fileheader = match recv_file_header(&mut recvr) {
+            Ok(h) => h,
+            Err(e) => { return Err(e.context(|m| format!("Error while receiving file header: {}", m))); }
+        };
+        if fileheader.file_type == FH_TYPE_END { break; }
+        let meta = if pt_header.meta {
+            let mut meta = FileMeta::default();
+            if let Err(e) = meta.pull(&mut recvr) {
+                return Err(e.context(|m| format!("Error while receiving file metadata: {}", m)));
+            }
+            Some(meta).filter(|_| inbox.preserve)
+        } else { None };
+        let target = entry_target(&root, inbox, pt_header.multi, &fileheader)?;
+        if needs_accept(&fileheader.name) {
+            let accepted = accept_all || match ask_accept(&fileheader, peer_addr) {
+                Answer::Yes => true,
+                Answer::All => { accept_all = true; true },
+                Answer::No => false,
+            };
+            let mut reply = Simple::default();
+            reply.content = if accepted { SIMPLE_MSG_FH_ACC } else { SIMPLE_MSG_FH_DEC };
+            if let Err(e) = reply.shove(&mut replies).and_then(|_| replies.flush().map_err(wire_error)) {
+                return Err(e.context(|m| format!("Error while answering the sender: {}", m)));
+            }
+            if !accepted {
+                return Err(DftpError::Declined(format!("Declined {}", fileheader.name)));
+            }
+        }
+        if fileheader.file_type == FH_TYPE_DIR {
+            make_dir(&target)?;
+            if let Some(meta) = meta {
+                dirs.push((target, meta));
+            }
+            continue;
+        }
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+            make_dir(parent)?;
+        }
+        if is_link(&fileheader) {
+            let link = match recv_link(&mut recvr, &fileheader) {
+                Ok(l) => l,
+                Err(e) => { return Err(e.context(|m| format!("Error while receiving link target: {}", m))); }
+            };
+            place_link(&root, &target, &fileheader, &link, &received)?;
+            if inbox.log {
+                log(&format!("{}: received {} -> {}", peer_addr, target.display(), link));
+            }
+            continue;
+        }
+        recv_file(&mut recvr, &mut replies, &pool, &target, &fileheader, &tag, inbox.quiet)?;
+        received.insert(fileheader.name.clone(), target.clone());
+        if let Some(meta) = meta {
+            apply_meta(&target, &meta);
+        }
+        if inbox.log {
+            log(&format!("{}: received {} ({} bytes)", peer_addr, target.display(), fileheader.length));
+        }
+        summary.add(fileheader.length);
+    }
+    if let Some(pool) = pool {
+        pool.finish();
+    }
+    for (dir, meta) in dirs.iter().rev() {
+        apply_meta(dir, meta);
+    }
+    summary.print("Received");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn recv_file(recvr: &mut Box<dyn Read>, replies: &mut Box<dyn Write>, pool: &Option<Pool>, target: &Path, fileheader: &FileHeader, tag: &str, quiet: bool) -> Result<(), DftpError> {
+    let mut incoming = Incoming::new(target, fileheader.length, tag)?;
+    let sparse = fileheader.file_type == FH_TYPE_SPARSE;
+    // ranges written side by side leave gaps when cut, so nothing before
+    // is a prefix to go on from and nothing after will be one
+    if !sparse && pool.as_ref().is_some_and(|p| split_ranges(0, fileheader.length, p.size()).is_some()) {
+        incoming.offer = ResumePoint::default();
+    }
+    if let Err(e) = incoming.offer.shove(replies).and_then(|_| replies.flush().map_err(wire_error)) {
+        return Err(e.context(|m| format!("Error while answering the sender: {}", m)));
+    }
+    let mut answer = ResumePoint::default();
+    if let Err(e) = answer.pull(recvr) {
+        return Err(e.context(|m| format!("Error while waiting for the sender: {}", m)));
+    }
+    let (file, start, sha) = incoming.open(&answer)?;
+    print_file_info(target, fileheader, quiet);
+    if start > 0 && !quiet {
+        println!("Resuming at {:.2} MiB", start as f64 / (1024 * 1024) as f64);
+    }
+    let ranges = pool.as_ref().filter(|_| !sparse).and_then(|p| split_ranges(start, fileheader.length, p.size()));
+    let (total, hash) = match (pool.as_ref(), ranges) {
+        (Some(pool), Some(ranges)) => {
+            drop(file);
+            recv_parallel(pool, ranges, &incoming.partial, fileheader.length, start, sha, quiet)?
+        },
+        _ if sparse => recv_sparse(recvr, file, &incoming.partial, fileheader.length, start, sha, quiet)?,
+        _ => recv_body(recvr, Box::new(BufWriter::new(file)), fileheader.length, start, sha, quiet)?,
+    };
+    incoming.arrived(total, fileheader.length)?;
+    let mut trailer = FileTrailer::default();
+    if let Err(e) = trailer.pull(recvr) {
+        return Err(e.context(|m| format!("\nError while receiving file digest: {}", m)));
+    }
+    incoming.settle(target, &hash, &trailer)?;
+    if !quiet {
+        println!("\n{}: {} OK", digest_label(sparse), stringify_hash(&hash));
+    }
+    Ok(())
+}
+
+// where an incoming entry goes, refused if that is outside of root. a link
+// replaces whatever is at its path rather than writing through it, so for
+// one it is the directory it goes in that has to stay inside.
+pub(crate) fn entry_target(root: &Path, inbox: &Inbox, multi: bool, header: &FileHeader) -> Result<PathBuf, DftpError> {
+    let target = output_path(&inbox.dir, &inbox.filename, &header.name, multi);
+    let confined = if is_link(header) { target.parent().unwrap_or(&target) } else { &target };
+    if let Err(e) = check_confined(root, confined) {
+        return Err(e.context(|m| format!("Refusing to write {}: {}", header.name, m)));
+    }
+    Ok(target)
+}
+
+// a file on its way in. it is written to a partial file next to target,
+// and target is left alone until the new file is complete and checked.
+#[derive(Clone)]
+pub(crate) struct Incoming {
+    pub(crate) partial: PathBuf,
+    pub(crate) offer: ResumePoint, // what of an earlier attempt is there to go on from
+    prefix: Sha256,
+    _lock: Arc<File>, // keeps partial ours until this is dropped
+}
+
+impl Incoming {
+    // claims the partial file of target. one that another session is
+    // writing to is left alone, this one then goes to a file named after
+    // tag. only what is in a partial file held by this session is offered.
+    pub(crate) fn new(target: &Path, length: u64, tag: &str) -> Result<Incoming, DftpError> {
+        let mut partial = partial_path(target);
+        clear_symlink(&partial)?;
+        let mut lock = lock_partial(&partial)?;
+        if lock.is_none() {
+            partial = session_partial_path(target, tag);
+            clear_symlink(&partial)?;
+            lock = lock_partial(&partial)?;
+        }
+        let lock = match lock {
+            Some(l) => l,
+            None => { return Err(DftpError::Io(format!("{} is being received by another session", target.display()), None)); }
+        };
+        let (offer, prefix) = resume_offer(&partial, length);
+        Ok(Incoming{ partial, offer, prefix, _lock: Arc::new(lock) })
+    }
+
+    // opens the partial file where the sender's answer says to go on from.
+    // returns it with that offset and the sha256 of what is before it.
+    pub(crate) fn open(&self, answer: &ResumePoint) -> Result<(File, u64, Sha256), DftpError> {
+        if answer.offset != 0 && answer.offset != self.offer.offset {
+            return Err(DftpError::Protocol("Malfunction 5".to_string()));
+        }
+        let sha = if answer.offset > 0 { self.prefix.clone() } else { Sha256::new() };
+        let file = write_file_from(&self.partial, answer.offset).map_err(|e| e.context(|m| format!("Error while writing to file:\n  {}", m)))?;
+        Ok((file, answer.offset, sha))
+    }
+
+    // a file that ended short stays where it is, to resume from
+    pub(crate) fn arrived(&self, total: u64, length: u64) -> Result<(), DftpError> {
+        if total != length {
+            return Err(DftpError::Network(format!("\nThe transfer ended after {} of {} bytes. Partial file left at {}", total, length, self.partial.display()), None));
+        }
+        Ok(())
+    }
+
+    // puts the file in place of target if its digest is the sender's,
+    // removes it otherwise
+    pub(crate) fn settle(&self, target: &Path, hash: &[u8; 32], trailer: &FileTrailer) -> Result<(), DftpError> {
+        if hash[..] != trailer.hash[..] {
+            let removed = match fs::remove_file(&self.partial) {
+                Ok(_) => "The corrupt file has been removed.".to_string(),
+                Err(m) => format!("Could not remove the corrupt file: {}", m),
+            };
+            return Err(DftpError::Integrity(format!("\nIntegrity check failed for {}!\n  expected: {}\n  received: {}\n{}",
+                               target.display(), stringify_hash(&trailer.hash), stringify_hash(hash), removed)));
+        }
+        commit_partial(&self.partial, target).map_err(|e| e.context(|m| format!("\n{}", m)))
+    }
+}
+
+// names the partial files a session falls back on, see Incoming::new
+pub(crate) fn session_tag(table: &ProtocolTable) -> Result<String, DftpError> {
+    let mut id = table.session;
+    if id == [0u8; 16] {
+        random_bytes(&mut id)?;
+    }
+    Ok(stringify_hash(&id[..8]))
+}
+
+// a symlink where the partial file goes would redirect the writes to it,
+// whoever put it there
+pub(crate) fn clear_symlink(partial: &Path) -> Result<(), DftpError> {
+    if fs::symlink_metadata(partial).is_ok_and(|m| m.file_type().is_symlink()) {
+        if let Err(m) = fs::remove_file(partial) {
+            return Err(DftpError::Io(format!("Error while removing {}", partial.display()), Some(m)));
+        }
+    }
+    Ok(())
+}
+
+// has the data connections write their ranges straight into place, then
+// reads back what they wrote to hash it
+fn recv_parallel(pool: &Pool, ranges: Vec<Range>, target: &Path, length: u64, start: u64, mut sha: Sha256, quiet: bool) -> Result<(u64, [u8; 32]), DftpError> {
+    let count = ranges.len();
+    let base = pool.moved();
+    if let Err(e) = pool.dispatch(target, ranges) {
+        return Err(e.context(|m| format!("Error while receiving {}: {}", target.display(), m)));
+    }
+    if let Err(e) = pool.wait(count, parallel_status(length, start, base, None, quiet)) {
+        let _ = fs::remove_file(target);
+        return Err(e.context(|m| format!("\nError while receiving {}: {}. Ranges received side by side cannot be resumed, the partial file has been removed.", target.display(), m)));
+    }
+    let hashed = File::open(target)
+        .and_then(|mut f| f.seek(SeekFrom::Start(start)).map(|_| f))
+        .and_then(|mut f| hash_prefix(&mut f, &mut sha, length - start));
+    let hashed = match hashed {
+        Ok(n) => n,
+        Err(m) => { return Err(DftpError::Io(format!("\nError while reading back {}", target.display()), Some(m))); }
+    };
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&sha.finalize());
+    Ok((start + hashed, hash))
+}
+
+// writes the data extents of a sparse file where they belong and leaves
+// the rest as holes. the file only grows to its full length at the end, so
+// after a failure what is there is still a prefix to resume from.
+fn recv_sparse(recvr: &mut Box<dyn Read>, mut file: File, target: &Path, length: u64, start: u64, sha: Sha256, quiet: bool) -> Result<(u64, [u8; 32]), DftpError> {
+    let name = target.display();
+    let mut map = ExtentMap::default();
+    if let Err(e) = map.pull(recvr) {
+        return Err(e.context(|m| format!("Error while receiving extent map: {}", m)));
+    }
+    let mut cursor = SparseCursor::new(&map, target, start, length, sha)?;
+    let mut status = sparse_status(length, None, quiet);
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    while let Some(piece) = cursor.next() {
+        if let Some(offset) = piece.seek {
+            if let Err(m) = file.seek(SeekFrom::Start(offset)) {
+                return Err(DftpError::Io(format!("Error seeking in file {}", name), Some(m)));
+            }
+        }
+        let n = match recvr.read(&mut buff[..piece.want]) {
+            Ok(n) => n,
+            Err(m) if m.kind() == ConnectionReset || m.kind() == UnexpectedEof => 0,
+            Err(m) => { return Err(wire_error(m).context(|s| format!("\nError while receiving: {}", s))); }
+        };
+        // short of the length, which the caller reports
+        if n == 0 {
+            return Ok((cursor.at(), [0u8; 32]));
+        }
+        if let Err(m) = file.write_all(&buff[..n]) {
+            return Err(DftpError::Io(format!("\nError while writing {}", name), Some(m)));
+        }
+        cursor.moved(&buff[..n]);
+        status(cursor.at(), n as u64);
+    }
+    if let Err(m) = file.set_len(length) {
+        return Err(DftpError::Io(format!("\nError while writing {}", name), Some(m)));
+    }
+    Ok((length, cursor.finish()))
+}
+
+// looks for what is left of an earlier attempt at the target. anything no
+// longer than the incoming file is offered to the sender along with its
+// sha256, the sender decides whether it is really a prefix of the file.
+pub(crate) fn resume_offer(target: &Path, length: u64) -> (ResumePoint, Sha256) {
+    let mut offer = ResumePoint::default();
+    let mut sha = Sha256::new();
+    let size = match fs::metadata(target) {
+        Ok(m) if m.is_file() && m.len() > 0 && m.len() <= length => m.len(),
+        _ => { return (offer, sha); }
+    };
+    let mut file = match File::open(target) {
+        Ok(f) => f,
+        Err(_) => { return (offer, sha); }
+    };
+    match hash_prefix(&mut file, &mut sha, size) {
+        Ok(n) if n == size => {},
+        _ => { return (ResumePoint::default(), Sha256::new()); }
+    }
+    offer.offset = size;
+    offer.hash.copy_from_slice(&sha.clone().finalize());
+    (offer, sha)
+}
+
+// feeds the first n bytes of reader into sha, returns how many there were
+fn hash_prefix(reader: &mut dyn Read, sha: &mut Sha256, n: u64) -> Result<u64, Error> {
+    let mut buff = vec![0u8; TRANSFER_BUFF_SIZE];
+    let mut total: u64 = 0;
+    while total < n {
+        let want = std::cmp::min(TRANSFER_BUFF_SIZE as u64, n - total) as usize;
+        let got = reader.read(&mut buff[0..want])?;
+        if got == 0 { break; }
+        sha.update(&buff[0..got]);
+        total += got as u64;
+    }
+    Ok(total)
+}
+
+// reads a payload off the peer. it never reads past the payload, since the
+// trailer follows it. the writer and sha are expected to
+// be past the first start bytes already. returns the number of bytes in
+// place and the sha256 of the whole file. a peer that goes away early only
+// shows in the count.
+fn recv_body(recvr: &mut Box<dyn Read>, mut writer: Box<dyn Write>, length: u64, start: u64, sha: Sha256, quiet: bool) -> Result<(u64, [u8; 32]), DftpError> {
+    let mut payload = Payload::new(Some(length), start, sha);
+    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
+    let mut counter = 0;
+    let mut bufflen_acc = 0;
+    let mut now = SystemTime::now();
+    loop{
+        let want = payload.want();
+        if want == 0 { break; }
+        let bufflen = match recvr.read(&mut buff[0..want]) {
+            Ok(n) => n,
+            Err(m) => {
+                match m.kind() {
+                    ConnectionReset | UnexpectedEof => {
+                        eprintln!("Connection closed by peer");
+                        break;
+                    },
+                    _ => { return Err(wire_error(m).context(|s| format!("\nError while receiving: {}", s))); },
+                }
+            }
+        };
+        if bufflen == 0 { break; }
+        if counter == 0 {
+            let micros = now.elapsed().unwrap_or_default().as_micros() as u64;
+            let speed = (bufflen_acc * 1_000_000) as f64 / micros as f64;
+            let speed = speed / 1024f64;
+            print_status(quiet, speed, length as f32 / (1024 * 1024) as f32, payload.total() as f32 / (1024 * 1024) as f32);
+            bufflen_acc = 0;
+            now = SystemTime::now();
+        }
+        if let Err(m) = writer.write_all(&buff[0..bufflen]) {
+            return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+        }
+        payload.moved(&buff[0..bufflen]);
+        bufflen_acc += bufflen;
+        counter += 1;
+        if counter == 80 { counter = 0; }
+    }
+    if let Err(m) = writer.flush() {
+        return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+    }
+    Ok(payload.received())
+}
+
+// reads the frames of a stream off the peer, see frame_head. a peer going
+// away before the empty frame is an error here, not a shorter stream.
+// returns how much came in and its sha256.
+fn recv_stream(recvr: &mut Box<dyn Read>, mut writer: Box<dyn Write>, unbuffered: bool) -> Result<(u64, [u8; 32]), DftpError> {
+    let mut payload = Payload::new(None, 0, Sha256::new());
+    let mut buff:[u8; TRANSFER_BUFF_SIZE] = [0; TRANSFER_BUFF_SIZE];
+    loop {
+        let mut head = [0u8; 4];
+        if let Err(m) = recvr.read_exact(&mut head) {
+            return Err(wire_error(m).context(|s| format!("\nThe stream ended early: {}", s)));
+        }
+        let n = frame_length(head)?;
+        if n == 0 { break; }
+        if let Err(m) = recvr.read_exact(&mut buff[..n]) {
+            return Err(wire_error(m).context(|s| format!("\nThe stream ended early: {}", s)));
+        }
+        let written = writer.write_all(&buff[..n]).and_then(|_| if unbuffered { writer.flush() } else { Ok(()) });
+        if let Err(m) = written {
+            return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+        }
+        payload.moved(&buff[..n]);
+    }
+    if let Err(m) = writer.flush() {
+        return Err(DftpError::Io("\nError while writing the output".to_string(), Some(m)));
+    }
+    Ok(payload.received())
+}
+
+struct Summary{
+    files: u64,
+    bytes: u64,
+    start: SystemTime,
+    quiet: bool,
+}
+
+impl Summary{
+    fn new(quiet: bool) -> Summary{
+        Summary{ files: 0, bytes: 0, start: SystemTime::now(), quiet }
+    }
+
+    fn add(&mut self, length: u64){
+        self.files += 1;
+        self.bytes += length;
+    }
+
+    fn print(&self, verb: &str){
+        if self.quiet { return; }
+        let secs = self.start.elapsed().unwrap_or_default().as_secs_f64();
+        println!("\n{} {} file(s), {:.2} MiB in {:.1}s", 
+            verb, self.files, self.bytes as f64 / (1024 * 1024) as f64, secs);
+    }
+}
+
+fn print_status(quiet: bool, speed: f64, length: f32, total: f32) {
+    if quiet { return; }
+    static mut SIZE: usize = 0;
+    unsafe {
+        if SIZE > 0 {
+            print!("\r{}", " ".repeat(SIZE));
+            print!("\r");
+        }
+    }
+
+    let ratio = total / length;
+    let percent = ratio * 100f32;
     let spaced = ratio * 20f32;
     let mut tiled = spaced as u32;
     let remain = spaced - tiled as f32;
@@ -651,9 +2200,726 @@ fn print_status(speed: f64, length: f32, total: f32) {
     }
 
     print!("{}", s);
-    std::io::stdout().flush().unwrap(); // Flush the output to ensure immediate printing
+    let _ = std::io::stdout().flush(); // Flush the output to ensure immediate printing
 }
 
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::thread;
+    use std::path::PathBuf;
+
+    use crate::compress::parse_codec;
+    use crate::duplex::{pair, rendezvous};
+
+    // shoves a message, pulls it back into a fresh one and checks both
+    // sides agree on the length
+    fn round_trip<T: TcpShovable + Default>(message: &T) -> T {
+        let mut wire: Vec<u8> = Vec::new();
+        let written = message.shove(&mut wire).unwrap();
+        assert_eq!(written, wire.len());
+        let mut back = T::default();
+        let mut reader = &wire[..];
+        let read = back.pull(&mut reader).unwrap();
+        assert_eq!(read, written);
+        assert!(reader.is_empty());
+        back
+    }
+
+    fn pull_bytes<T: TcpShovable + Default>(mut wire: &[u8]) -> Result<T, DftpError> {
+        let mut message = T::default();
+        message.pull(&mut wire).map(|_| message)
+    }
+
+    #[test]
+    fn simple_round_trip() {
+        for content in [SIMPLE_MSG_SENDER_ID, SIMPLE_MSG_RECVER_ID, SIMPLE_MSG_HS_ACK, SIMPLE_MSG_HS_JOIN,
+                        SIMPLE_MSG_PN_ACC, SIMPLE_MSG_PN_DEC, SIMPLE_MSG_PN_DEC_SEC, SIMPLE_MSG_PN_DEC_CODEC,
+                        SIMPLE_MSG_PN_DEC_BUSY, SIMPLE_MSG_TR_PLAIN, SIMPLE_MSG_TR_TLS, SIMPLE_MSG_FH_ACC,
+                        SIMPLE_MSG_FH_DEC] {
+            let mut message = Simple::default();
+            message.content = content;
+            assert_eq!(round_trip(&message).content, content);
+        }
+        let mut bogus = Simple::default();
+        bogus.content = 0b01010101;
+        assert!(matches!(bogus.shove(&mut Vec::new()), Err(DftpError::Protocol(_))));
+        assert!(matches!(pull_bytes::<Simple>(&[0b01010101]), Err(DftpError::Protocol(_))));
+    }
+
+    #[test]
+    fn protocol_table_round_trip() {
+        let mut table = ProtocolTable::default();
+        table.codec = 3;
+        table.isfile = true;
+        table.multi = true;
+        table.encrypted = true;
+        table.streams = MAX_STREAMS;
+        table.session = [7u8; 16];
+        let back = round_trip(&table);
+        assert_eq!(back.compat_num, COMPAT_NUMBER);
+        assert_eq!(back.codec, 3);
+        assert!(back.isfile && back.multi && back.encrypted);
+        assert_eq!(back.streams, MAX_STREAMS);
+        assert_eq!(back.session, [7u8; 16]);
+
+        let back = round_trip(&ProtocolTable::default());
+        assert!(!back.isfile && !back.multi && !back.encrypted);
+        assert_eq!(back.streams, 0);
+    }
+
+    #[test]
+    fn protocol_table_refusals() {
+        let mut wire = Vec::new();
+        ProtocolTable::default().shove(&mut wire).unwrap();
+        wire[0] = COMPAT_NUMBER + 1;
+        assert!(matches!(pull_bytes::<ProtocolTable>(&wire), Err(DftpError::Incompatible(_))));
+        wire[0] = COMPAT_NUMBER;
+        wire[3] = MAX_STREAMS + 1;
+        assert!(matches!(pull_bytes::<ProtocolTable>(&wire), Err(DftpError::Protocol(_))));
+    }
+
+    #[test]
+    fn small_messages_round_trip() {
+        let mut join = StreamJoin::default();
+        join.session = [9u8; 16];
+        join.index = 5;
+        let back = round_trip(&join);
+        assert_eq!((back.session, back.index), ([9u8; 16], 5));
+
+        let mut trailer = FileTrailer::default();
+        trailer.hash = [0xab; 32];
+        assert_eq!(round_trip(&trailer).hash, [0xab; 32]);
+
+        let mut point = ResumePoint::default();
+        point.offset = u64::MAX - 1;
+        point.hash = [3u8; 32];
+        let back = round_trip(&point);
+        assert_eq!((back.offset, back.hash), (u64::MAX - 1, [3u8; 32]));
+
+        let mut meta = FileMeta::default();
+        meta.mode = 0o755;
+        meta.mtime = -86_400;
+        meta.mtime_nsec = 999_999_999;
+        meta.atime = i64::MAX;
+        meta.uid = u32::MAX;
+        meta.gid = 1000;
+        let back = round_trip(&meta);
+        assert_eq!((back.mode, back.mtime, back.mtime_nsec, back.atime, back.atime_nsec, back.uid, back.gid),
+                   (0o755, -86_400, 999_999_999, i64::MAX, 0, u32::MAX, 1000));
+        let mut wire = Vec::new();
+        meta.shove(&mut wire).unwrap();
+        wire[12..16].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        assert!(matches!(pull_bytes::<FileMeta>(&wire), Err(DftpError::Protocol(_))));
+
+        let mut map = ExtentMap::default();
+        map.extents = vec![Extent{ offset: 0, length: 4096 }, Extent{ offset: 1 << 40, length: 1 }];
+        assert!(round_trip(&map).extents == map.extents);
+        // overlapping, then claiming more extents than allowed
+        let mut wire = Vec::new();
+        map.extents[1].offset = 4095;
+        map.shove(&mut wire).unwrap();
+        assert!(matches!(pull_bytes::<ExtentMap>(&wire), Err(DftpError::Protocol(_))));
+        wire[0..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(pull_bytes::<ExtentMap>(&wire), Err(DftpError::Protocol(_))));
+
+        let mut announcement = Announcement::default();
+        announcement.port = 8086;
+        announcement.name = "alice-laptop".to_string();
+        let back = round_trip(&announcement);
+        assert_eq!((back.compat_num, back.port, back.name.as_str()), (COMPAT_NUMBER, 8086, "alice-laptop"));
+        assert!(matches!(pull_bytes::<Announcement>(b"HTTP/1.1"), Err(DftpError::Protocol(_))));
+    }
+
+    // the async side reads as much as missing says, byte by byte if it
+    // has to, and must land exactly on the end of what shove wrote
+    #[cfg(feature = "async")]
+    fn wire_length_matches<T: WireLength>(message: &T) {
+        let mut wire: Vec<u8> = Vec::new();
+        message.shove(&mut wire).unwrap();
+        let mut at = 0;
+        loop {
+            match T::missing(&wire[..at]).unwrap() {
+                0 => break,
+                _ => { at += 1; }
+            }
+        }
+        assert_eq!(at, wire.len());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn wire_lengths_match_what_is_shoved() {
+        wire_length_matches(&Simple::default());
+        wire_length_matches(&ProtocolTable::default());
+        wire_length_matches(&FileMeta::default());
+        wire_length_matches(&FileTrailer::default());
+        wire_length_matches(&ResumePoint::default());
+        let mut map = ExtentMap::default();
+        map.extents = vec![Extent{ offset: 0, length: 4096 }, Extent{ offset: 8192, length: 1 }];
+        wire_length_matches(&map);
+        let mut header = FileHeader::default();
+        header.name = "dir/ünï cødé.bin".to_string();
+        wire_length_matches(&header);
+    }
+
+    #[test]
+    fn file_header_round_trip() {
+        for (file_type, name, length) in [(FH_TYPE_FILE, "dir/ünï cødé.bin", 1u64 << 40),
+                                          (FH_TYPE_DIR, "dir", 12345),
+                                          (FH_TYPE_SYMLINK, "dir/link", 9),
+                                          (FH_TYPE_END, "", 0)] {
+            let mut header = FileHeader::default();
+            header.file_type = file_type;
+            header.name = name.to_string();
+            header.length = length;
+            header.hash = [file_type; 32];
+            let back = round_trip(&header);
+            assert_eq!((back.file_type, back.name.as_str(), back.length, back.hash), (file_type, name, length, [file_type; 32]));
+        }
+    }
+
+    #[test]
+    fn file_header_from_a_misbehaving_peer() {
+        let mut header = FileHeader::default();
+        header.name = "abc".to_string();
+        let mut wire = Vec::new();
+        header.shove(&mut wire).unwrap();
+
+        // cut off anywhere, including in the middle of the name
+        for cut in [0, 5, 13, 15, wire.len() - 1] {
+            assert!(matches!(pull_bytes::<FileHeader>(&wire[..cut]), Err(DftpError::Network(..))));
+        }
+        let mut bad = wire.clone();
+        bad[13] = 0xff;
+        assert!(matches!(pull_bytes::<FileHeader>(&bad), Err(DftpError::Protocol(_))));
+        let mut bad = wire.clone();
+        bad[8] = 0x7f;
+        assert!(matches!(pull_bytes::<FileHeader>(&bad), Err(DftpError::Protocol(_))));
+
+        // a name length of 4 GiB is refused without waiting for the name
+        let mut bad = wire[..13].to_vec();
+        bad[9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(pull_bytes::<FileHeader>(&bad), Err(DftpError::Protocol(_))));
+        header.name = "n".repeat(FH_MAX_NAME_LEN + 1);
+        assert!(matches!(header.shove(&mut Vec::new()), Err(DftpError::Protocol(_))));
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dftp-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // bytes that do not compress much
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len).map(|_| { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x as u8 }).collect()
+    }
+
+    fn plain() -> Security {
+        Security{ code: None, tls: None }
+    }
+
+    // runs a whole session over an in-memory connection and returns what
+    // both ends made of it
+    fn run_session(filenames: Vec<String>, out: &Path, codec: Codec, streams: u8,
+                   send_security: Security, recv_security: Security) -> (Result<(), DftpError>, Result<(), DftpError>) {
+        run_session_into(filenames, &inbox(out, "stdin"), codec, streams, send_security, recv_security)
+    }
+
+    // an inbox that takes everything into out, under the receiver's -f
+    fn inbox(out: &Path, filename: &str) -> Inbox {
+        Inbox{ filename: filename.to_string(), dir: out.to_string_lossy().to_string(), yes: true, log: false, preserve: true, quiet: true }
+    }
+
+    fn run_session_into(filenames: Vec<String>, inbox: &Inbox, codec: Codec, streams: u8,
+                        send_security: Security, recv_security: Security) -> (Result<(), DftpError>, Result<(), DftpError>) {
+        let entries = collect_session(&filenames, false).unwrap();
+        let (mut ours, mut theirs) = pair();
+        let (dial, listen) = rendezvous();
+        let sender = thread::spawn(move || {
+            handshake_send(&mut ours, false)?;
+            send_session(Box::new(ours), dial, &filenames, &entries, &codec, streams, None, &send_security, true, true)
+        });
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let received = handshake_recv(&mut theirs)
+            .and_then(|_| recv_session(Box::new(theirs), &peer, &Joins::Rendezvous(&listen), inbox, &recv_security));
+        (sender.join().unwrap(), received)
+    }
+
+    #[test]
+    fn session_with_files_and_a_tree() {
+        let dir = scratch("tree");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("tree/sub")).unwrap();
+        fs::write(src.join("a.txt"), b"hello ".repeat(50_000)).unwrap();
+        fs::write(src.join("tree/sub/b.bin"), noise(300_000, 1)).unwrap();
+        fs::write(src.join("tree/empty.txt"), b"").unwrap();
+        let names = vec![src.join("a.txt").to_string_lossy().to_string(), src.join("tree").to_string_lossy().to_string()];
+        let out = dir.join("out");
+        let (sent, received) = run_session(names, &out, parse_codec("deflate").unwrap(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        for name in ["a.txt", "tree/sub/b.bin", "tree/empty.txt"] {
+            assert!(fs::read(src.join(name)).unwrap() == fs::read(out.join(name)).unwrap(), "{} differs", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn several_files_go_into_the_receivers_directory() {
+        let dir = scratch("several");
+        fs::create_dir_all(dir.join("one")).unwrap();
+        fs::create_dir_all(dir.join("two")).unwrap();
+        fs::write(dir.join("one/a.txt"), b"first").unwrap();
+        fs::write(dir.join("two/b.txt"), b"second").unwrap();
+        fs::write(dir.join("two/a.txt"), b"same name").unwrap();
+        let name = |n: &str| dir.join(n).to_string_lossy().to_string();
+        let out = dir.join("out");
+        let (sent, received) = run_session_into(vec![name("one/a.txt"), name("two/b.txt")], &inbox(&out, "batch"), Codec::default(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(fs::read(out.join("batch/a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(out.join("batch/b.txt")).unwrap(), b"second");
+        // both would land on the same name
+        assert!(matches!(collect_session(&[name("one/a.txt"), name("two/a.txt")], false), Err(DftpError::Usage(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_a_yes_accepts_an_entry() {
+        for (line, yes, all) in [("y\n", true, false), (" YES \n", true, false), ("a\n", true, true), ("all\n", true, true),
+                                 ("\n", false, false), ("n\n", false, false), ("yep\n", false, false), ("", false, false)] {
+            let (got_yes, got_all) = match parse_answer(line) {
+                Answer::Yes => (true, false),
+                Answer::All => (true, true),
+                Answer::No => (false, false),
+            };
+            assert_eq!((got_yes, got_all), (yes, all), "answer {:?}", line);
+        }
+        // what the sender makes of the receiver's reply
+        let dir = scratch("accept");
+        fs::write(dir.join("a.txt"), b"maybe").unwrap();
+        let entries = collect_session(&[dir.join("a.txt").to_string_lossy().to_string()], false).unwrap();
+        assert!(accept_answer(SIMPLE_MSG_FH_ACC, &entries[0]).is_ok());
+        assert!(matches!(accept_answer(SIMPLE_MSG_FH_DEC, &entries[0]), Err(DftpError::Declined(_))));
+        assert!(matches!(accept_answer(SIMPLE_MSG_PN_ACC, &entries[0]), Err(DftpError::Protocol(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tree_goes_under_the_receivers_name() {
+        let dir = scratch("tree-name");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("tree/sub/deeper")).unwrap();
+        fs::create_dir_all(src.join("tree/hollow")).unwrap();
+        fs::write(src.join("tree/sub/deeper/c.txt"), b"deep down").unwrap();
+        let out = dir.join("out");
+        let names = vec![src.join("tree").to_string_lossy().to_string()];
+        let (sent, received) = run_session_into(names, &inbox(&out, "copy"), Codec::default(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(fs::read(out.join("copy/sub/deeper/c.txt")).unwrap(), b"deep down");
+        assert!(out.join("copy/hollow").is_dir());
+        assert!(!out.join("tree").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reverse_mode_sender_waits_and_receiver_dials() {
+        let dir = scratch("reverse");
+        let data = noise(5 * 1024 * 1024, 5);
+        fs::write(dir.join("big.bin"), &data).unwrap();
+        let names = vec![dir.join("big.bin").to_string_lossy().to_string()];
+        let entries = collect_session(&names, false).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the data connections follow the first one the same way round
+        let sender = thread::spawn(move || {
+            let (mut peer, _) = listener.accept().unwrap();
+            handshake_send(&mut peer, false)?;
+            send_session(Box::new(peer), Rendezvous::Listener(listener), &names, &entries, &Codec::default(), 2, None, &plain(), true, true)
+        });
+        let mut peer = std::net::TcpStream::connect(addr).unwrap();
+        let received = handshake_recv(&mut peer)
+            .and_then(|_| recv_session(Box::new(peer), &addr, &Joins::Rendezvous(&Rendezvous::Dialed(addr)), &inbox(&dir.join("out"), "stdin"), &plain()));
+        sender.join().unwrap().unwrap();
+        received.unwrap();
+        assert!(fs::read(dir.join("out/big.bin")).unwrap() == data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_keeps_modes_and_times() {
+        let dir = scratch("meta");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("tools")).unwrap();
+        fs::write(src.join("tools/run.sh"), b"#!/bin/sh\necho hi\n").unwrap();
+        fs::set_permissions(src.join("tools/run.sh"), Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(src.join("tools"), Permissions::from_mode(0o750)).unwrap();
+        let then = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_789);
+        for path in [src.join("tools/run.sh"), src.join("tools")] {
+            File::open(&path).unwrap().set_times(FileTimes::new().set_modified(then).set_accessed(then)).unwrap();
+        }
+        let out = dir.join("out");
+        let (sent, received) = run_session(vec![src.join("tools").to_string_lossy().to_string()], &out, Codec::default(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        for (name, mode) in [("tools/run.sh", 0o755), ("tools", 0o750)] {
+            let meta = fs::metadata(out.join(name)).unwrap();
+            assert_eq!(meta.mode() & 0o7777, mode, "mode of {}", name);
+            assert_eq!(meta.modified().unwrap(), then, "mtime of {}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_with_links_and_special_files() {
+        let dir = scratch("links");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("tree/sub")).unwrap();
+        fs::create_dir_all(src.join("tree/empty")).unwrap();
+        fs::write(src.join("tree/data.txt"), b"linked").unwrap();
+        fs::hard_link(src.join("tree/data.txt"), src.join("tree/sub/same.txt")).unwrap();
+        symlink("data.txt", src.join("tree/rel")).unwrap();
+        symlink("../data.txt", src.join("tree/sub/up")).unwrap();
+        symlink("/etc/passwd", src.join("tree/absolute")).unwrap();
+        symlink("../../../outside", src.join("tree/sub/escape")).unwrap();
+        let fifo = std::ffi::CString::new(src.join("tree/fifo").to_string_lossy().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let out = dir.join("out");
+        let (sent, received) = run_session(vec![src.join("tree").to_string_lossy().to_string()], &out, Codec::default(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        let tree = out.join("tree");
+        assert_eq!(fs::read_link(tree.join("rel")).unwrap(), Path::new("data.txt"));
+        assert_eq!(fs::read(tree.join("sub/up")).unwrap(), b"linked");
+        assert_eq!(fs::metadata(tree.join("sub/same.txt")).unwrap().ino(), fs::metadata(tree.join("data.txt")).unwrap().ino());
+        assert!(fs::metadata(tree.join("empty")).unwrap().is_dir());
+        for name in ["absolute", "sub/escape", "fifo"] {
+            assert!(fs::symlink_metadata(tree.join(name)).is_err(), "{} should have been left out", name);
+        }
+        // a hard link has to point at something the session brought
+        let mut header = FileHeader::default();
+        header.file_type = FH_TYPE_HARDLINK;
+        header.name = "tree/stolen".to_string();
+        let refused = place_link(&out, &tree.join("stolen"), &header, "tree/elsewhere", &HashMap::new());
+        assert!(matches!(refused, Err(DftpError::Protocol(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn followed_link_loops_are_walked_once() {
+        let dir = scratch("loop");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub/a.txt"), b"a").unwrap();
+        symlink("..", tree.join("sub/loop")).unwrap();
+        let entries = collect_entries(&tree.to_string_lossy(), true, &mut HashMap::new()).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["tree", "tree/sub", "tree/sub/a.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_keeps_sparse_files_sparse() {
+        let dir = scratch("sparse");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        // big enough to be split over the streams, were it not sparse
+        let image = src.join("disk.img");
+        let mut file = File::create(&image).unwrap();
+        file.set_len(24 << 20).unwrap();
+        for at in [1u64 << 20, 16 << 20] {
+            file.seek(SeekFrom::Start(at)).unwrap();
+            file.write_all(&noise(200_000, at as u32)).unwrap();
+        }
+        drop(file);
+        let out = dir.join("out");
+        let (sent, received) = run_session(vec![image.to_string_lossy().to_string()], &out, parse_codec("deflate").unwrap(), 2, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        assert!(fs::read(&image).unwrap() == fs::read(out.join("disk.img")).unwrap());
+        let meta = fs::metadata(out.join("disk.img")).unwrap();
+        assert_eq!(meta.len(), 24 << 20);
+        assert!(meta.blocks() * 512 < 4 << 20, "{} blocks allocated", meta.blocks());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sparse_holes_are_not_read_through() {
+        let dir = scratch("huge-sparse");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        // hashing this many zeros would take minutes
+        let image = src.join("huge.img");
+        let file = File::create(&image).unwrap();
+        file.set_len(64 << 30).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &noise(4096, 9), 40 << 30).unwrap();
+        drop(file);
+        let out = dir.join("out");
+        let (sent, received) = run_session(vec![image.to_string_lossy().to_string()], &out, Codec::default(), 1, plain(), plain());
+        sent.unwrap();
+        received.unwrap();
+        let got = File::open(out.join("huge.img")).unwrap();
+        assert_eq!(got.metadata().unwrap().len(), 64 << 30);
+        let mut data = vec![0u8; 4096];
+        std::os::unix::fs::FileExt::read_exact_at(&got, &mut data, 40 << 30).unwrap();
+        assert!(data == noise(4096, 9));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_encrypted_over_parallel_streams() {
+        let dir = scratch("streams");
+        let data = noise(6 * 1024 * 1024 + 11, 2);
+        fs::write(dir.join("big.bin"), &data).unwrap();
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        // what an earlier attempt left behind, next to an older copy. it is
+        // not offered, the ranges start over.
+        fs::write(out.join("big.bin.dftp-partial"), b"not a prefix of big.bin").unwrap();
+        fs::write(out.join("big.bin"), b"older").unwrap();
+        let code = || Security{ code: Some("123-test-code".to_string()), tls: None };
+        let names = vec![dir.join("big.bin").to_string_lossy().to_string()];
+        let (sent, received) = run_session(names, &out, parse_codec("auto").unwrap(), 3, code(), code());
+        sent.unwrap();
+        received.unwrap();
+        assert!(fs::read(out.join("big.bin")).unwrap() == data);
+        assert!(!out.join("big.bin.dftp-partial").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume_goes_on_from_a_matching_prefix_only() {
+        let dir = scratch("resume");
+        let data = noise(200_000, 3);
+        fs::write(dir.join("big.bin"), &data).unwrap();
+        let partial = dir.join("big.bin.dftp-partial");
+        fs::write(&partial, &data[..70_000]).unwrap();
+        let (offer, _) = resume_offer(&partial, data.len() as u64);
+        assert_eq!(offer.offset, 70_000);
+        let (mut file, start, _) = resume_send(&dir.join("big.bin"), data.len() as u64, &offer).unwrap();
+        assert_eq!(start, 70_000);
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert!(rest == data[70_000..]);
+        // the sender's copy changed since
+        fs::write(&partial, noise(70_000, 4)).unwrap();
+        let (offer, _) = resume_offer(&partial, data.len() as u64);
+        assert_eq!(resume_send(&dir.join("big.bin"), data.len() as u64, &offer).unwrap().1, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cut_transfer_leaves_the_target_alone() {
+        let dir = scratch("cut");
+        let target = dir.join("a.txt");
+        fs::write(&target, b"the old one").unwrap();
+        let mut header = FileHeader::default();
+        header.name = "a.txt".to_string();
+        header.length = 10;
+        // the sender answers the offer and goes away halfway through
+        let mut wire = Vec::new();
+        ResumePoint::default().shove(&mut wire).unwrap();
+        wire.extend_from_slice(b"new o");
+        let mut recvr: Box<dyn Read> = Box::new(std::io::Cursor::new(wire));
+        let mut replies: Box<dyn Write> = Box::new(Vec::new());
+        let cut = recv_file(&mut recvr, &mut replies, &None, &target, &header, "test", true);
+        assert!(matches!(cut, Err(DftpError::Network(..))));
+        assert_eq!(fs::read(&target).unwrap(), b"the old one");
+        assert_eq!(fs::read(dir.join("a.txt.dftp-partial")).unwrap(), b"new o");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn streams_are_framed_and_checked_to_the_end() {
+        let data = noise(3 * TRANSFER_BUFF_SIZE / 2, 5);
+        let (ours, mut theirs) = pair();
+        let mut framed: Box<dyn Write> = Box::new(ours);
+        let mut trailer = FileTrailer::default();
+        trailer.hash = send_stream(&mut framed, Box::new(std::io::Cursor::new(data.clone()))).unwrap();
+        trailer.shove(&mut framed).unwrap();
+        drop(framed);
+        let mut sent = Vec::new();
+        theirs.read_to_end(&mut sent).unwrap();
+        let received = |wire: Vec<u8>| {
+            let mut recvr: Box<dyn Read> = Box::new(std::io::Cursor::new(wire));
+            let (total, hash) = recv_stream(&mut recvr, Box::new(std::io::sink()), false)?;
+            let mut trailer = FileTrailer::default();
+            trailer.pull(&mut recvr)?;
+            check_stream("stdout", &hash, &trailer).map(|_| total)
+        };
+        assert_eq!(received(sent.clone()).unwrap(), data.len() as u64);
+        // cut short, with or without a whole frame at the end
+        assert!(matches!(received(sent[..sent.len() - 100].to_vec()), Err(DftpError::Network(..))));
+        assert!(matches!(received(sent[..4 + TRANSFER_BUFF_SIZE].to_vec()), Err(DftpError::Network(..))));
+        // changed on the way
+        let mut bent = sent.clone();
+        bent[10] ^= 1;
+        assert!(matches!(received(bent), Err(DftpError::Integrity(_))));
+        // a frame longer than allowed
+        let mut big = sent.clone();
+        big[..4].copy_from_slice(&frame_head(TRANSFER_BUFF_SIZE + 1));
+        assert!(matches!(received(big), Err(DftpError::Protocol(_))));
+    }
+
+    #[test]
+    fn names_leading_out_of_the_output_directory_are_refused() {
+        for name in ["a.txt", "dir/sub/b.bin", "dot.in.name", "..hidden"] {
+            assert!(check_name(name).is_ok(), "{} refused", name);
+        }
+        for name in ["", "/etc/passwd", "../up", "dir/../../up", "dir//b", "./a", "a\\b", "nul\0byte"] {
+            assert!(matches!(check_name(name), Err(DftpError::Protocol(_))), "{} let through", name);
+        }
+        assert!(check_name(&"x/".repeat(MAX_NAME_LEN)).is_err());
+        assert!(check_name(&"x".repeat(256)).is_err());
+
+        // a symlink already in the output directory does not lead out either
+        let dir = scratch("confined");
+        let (out, elsewhere) = (dir.join("out"), dir.join("elsewhere"));
+        fs::create_dir_all(&out).unwrap();
+        fs::create_dir_all(&elsewhere).unwrap();
+        symlink(&elsewhere, out.join("door")).unwrap();
+        let mut header = FileHeader::default();
+        header.name = "door/planted.txt".to_string();
+        let inbox = inbox(&out, "stdin");
+        assert!(matches!(entry_target(&out, &inbox, true, &header), Err(DftpError::Protocol(_))));
+        header.name = "inside/fine.txt".to_string();
+        assert_eq!(entry_target(&out, &inbox, true, &header).unwrap(), out.join("inside/fine.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_file_is_not_kept() {
+        let dir = scratch("corrupt");
+        let target = dir.join("a.txt");
+        let mut header = FileHeader::default();
+        header.name = "a.txt".to_string();
+        header.length = 5;
+        // all of it arrives, followed by the digest of something else
+        let mut wire = Vec::new();
+        ResumePoint::default().shove(&mut wire).unwrap();
+        wire.extend_from_slice(b"hellp");
+        let mut trailer = FileTrailer::default();
+        trailer.hash.copy_from_slice(&Sha256::digest(b"hello"));
+        trailer.shove(&mut wire).unwrap();
+        let mut recvr: Box<dyn Read> = Box::new(std::io::Cursor::new(wire));
+        let mut replies: Box<dyn Write> = Box::new(Vec::new());
+        let corrupt = recv_file(&mut recvr, &mut replies, &None, &target, &header, "test", true);
+        assert!(matches!(corrupt, Err(DftpError::Integrity(_))));
+        assert!(!target.exists());
+        assert!(!dir.join("a.txt.dftp-partial").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn busy_partial_file_is_not_shared() {
+        let dir = scratch("busy-partial");
+        let target = dir.join("a.txt");
+        fs::write(dir.join("a.txt.dftp-partial"), b"from before").unwrap();
+        let first = Incoming::new(&target, 100, "aaaa").unwrap();
+        assert_eq!(first.offer.offset, 11);
+        // a second session receiving the same name meanwhile
+        let second = Incoming::new(&target, 100, "bbbb").unwrap();
+        assert_eq!(second.partial, dir.join("a.txt.bbbb.dftp-partial"));
+        assert_eq!(second.offer.offset, 0);
+        drop(first);
+        let third = Incoming::new(&target, 100, "cccc").unwrap();
+        assert_eq!((third.partial, third.offer.offset), (dir.join("a.txt.dftp-partial"), 11));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_over_tls() {
+        let dir = scratch("tls");
+        fs::write(dir.join("c.bin"), noise(100_000, 3)).unwrap();
+        let identity = generate_identity().unwrap();
+        let fingerprint = identity.fingerprint;
+        let client = Security{ code: None, tls: Some(TlsRole::Client(fingerprint)) };
+        let server = Security{ code: None, tls: Some(TlsRole::Server(identity)) };
+        let out = dir.join("out");
+        let names = vec![dir.join("c.bin").to_string_lossy().to_string()];
+        let (sent, received) = run_session(names, &out, Codec::default(), 1, client, server);
+        sent.unwrap();
+        received.unwrap();
+        assert!(fs::read(dir.join("c.bin")).unwrap() == fs::read(out.join("c.bin")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tls_refuses_a_certificate_with_another_fingerprint() {
+        let dir = scratch("tls-pin");
+        fs::write(dir.join("c.bin"), b"for the pinned one only").unwrap();
+        let identity = generate_identity().unwrap();
+        let other = generate_identity().unwrap().fingerprint;
+        assert!(other != identity.fingerprint);
+        let client = Security{ code: None, tls: Some(TlsRole::Client(other)) };
+        let server = Security{ code: None, tls: Some(TlsRole::Server(identity)) };
+        let names = vec![dir.join("c.bin").to_string_lossy().to_string()];
+        let (sent, received) = run_session(names, &dir.join("out"), Codec::default(), 1, client, server);
+        assert!(matches!(sent, Err(DftpError::Auth(_))));
+        assert!(received.is_err());
+        assert!(!dir.join("out/c.bin").exists());
+        // the printed form is what gets typed back in
+        assert_eq!(crate::tls::parse_fingerprint(&format_fingerprint(&other).to_lowercase()).unwrap(), other);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_with_the_wrong_code() {
+        let dir = scratch("code");
+        fs::write(dir.join("d.txt"), b"secret").unwrap();
+        let names = vec![dir.join("d.txt").to_string_lossy().to_string()];
+        let with = |c: &str| Security{ code: Some(c.to_string()), tls: None };
+        let (sent, received) = run_session(names, &dir.join("out"), Codec::default(), 1, with("1-a-b"), with("2-c-d"));
+        assert!(matches!(received, Err(DftpError::Auth(_))));
+        assert!(sent.is_err());
+        assert!(!dir.join("out/d.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pairing_vouches_for_the_protocol_table() {
+        let pairing = |ours: &ProtocolTable, theirs: &ProtocolTable| {
+            let (mut a, mut b) = pair();
+            let ours = table_bytes(ours).unwrap();
+            let sender = thread::spawn(move || pair_send(&mut a, "417-maple-quartz", &ours).map(|k| k.outgoing));
+            let received = pair_recv(&mut b, "417-maple-quartz", &table_bytes(theirs).unwrap()).map(|k| k.incoming);
+            (sender.join().unwrap(), received)
+        };
+        let mut table = ProtocolTable::default();
+        table.encrypted = true;
+        table.streams = 4;
+        let (sent, received) = pairing(&table, &table);
+        assert_eq!(sent.unwrap(), received.unwrap());
+        // someone in the middle talked the receiver out of compression
+        let mut changed = ProtocolTable::default();
+        changed.encrypted = true;
+        changed.streams = 4;
+        changed.codec = CODEC_NONE + 1;
+        let (sent, received) = pairing(&changed, &table);
+        assert!(matches!(sent, Err(DftpError::Auth(_))));
+        assert!(matches!(received, Err(DftpError::Auth(_))));
+    }
+
+    #[test]
+    fn session_with_mismatched_security() {
+        let dir = scratch("mismatch");
+        fs::write(dir.join("e.txt"), b"data").unwrap();
+        let names = vec![dir.join("e.txt").to_string_lossy().to_string()];
+        let code = Security{ code: Some("1-a-b".to_string()), tls: None };
+        let (sent, received) = run_session(names, &dir.join("out"), Codec::default(), 1, code, plain());
+        assert!(matches!(sent, Err(DftpError::Incompatible(_))));
+        assert!(matches!(received, Err(DftpError::Incompatible(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::{File, Metadata};
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use sha2::{Digest, Sha256};
+
+use crate::protocol::{Extent, ExtentMap, TcpShovable};
+
+pub const MAX_EXTENTS: usize = 65536; // a map never gets bigger than this, whatever the peer claims
+
+// whether a file is worth sending as its data extents. fewer blocks than
+// its size needs means there are holes in it.
+pub fn looks_sparse(meta: &Metadata) -> bool {
+    meta.is_file() && meta.blocks() * 512 < meta.len()
+}
+
+// the parts of a file between start and length that hold data, in order.
+// where the system cannot tell, or there are too many of them for a map,
+// all of it counts as data.
+pub fn data_extents(file: &File, start: u64, length: u64) -> Vec<Extent> {
+    let whole = || if start < length { vec![Extent{ offset: start, length: length - start }] } else { Vec::new() };
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut at = start;
+    while at < length {
+        let data = unsafe { libc::lseek(fd, at as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            // ENXIO: nothing but a hole from here on
+            if Error::last_os_error().raw_os_error() == Some(libc::ENXIO) { break; }
+            return whole();
+        }
+        let data = data as u64;
+        if data >= length { break; }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return whole();
+        }
+        let end = std::cmp::min(hole as u64, length);
+        extents.push(Extent{ offset: data, length: end - data });
+        if extents.len() > MAX_EXTENTS {
+            return whole();
+        }
+        at = end;
+    }
+    extents
+}
+
+// the digest of a sparse file covers its map and its data, with each hole
+// going in as its length. hashing the zeros a hole reads as would cost as
+// much as sending them.
+pub fn hash_map(sha: &mut Sha256, map: &ExtentMap) {
+    let mut wire: Vec<u8> = Vec::new();
+    map.shove(&mut wire).expect("a map always encodes");
+    sha.update(&wire);
+}
+
+pub fn hash_hole(sha: &mut Sha256, n: u64) {
+    sha.update(n.to_be_bytes());
+}
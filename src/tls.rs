@@ -0,0 +1,327 @@
+use std::fmt::Write as fWrite;
+use std::io::{Read, Write, Error};
+use std::io::ErrorKind::{InvalidData, WouldBlock};
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::ring::default_provider;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig,
+    ClientConnection,
+    Connection,
+    DigitallySignedStruct,
+    ServerConfig,
+    ServerConnection,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+use crate::network::PeerStream;
+use crate::error::DftpError;
+
+// the name baked into the certificate. nobody checks it, the pin does the work.
+const TLS_SERVER_NAME: &str = "dftp";
+
+const TLS_READ_SIZE: usize = 16 * 1024 + 256; // a whole record at most
+
+// an ephemeral self signed certificate for the listening end
+pub struct TlsIdentity {
+    cert: CertificateDer<'static>,
+    key: Vec<u8>, // pkcs8
+    pub fingerprint: [u8; 32],
+}
+
+pub fn generate_identity() -> Result<TlsIdentity, DftpError> {
+    let certified = match rcgen::generate_simple_self_signed(vec![TLS_SERVER_NAME.to_string()]) {
+        Ok(c) => c,
+        Err(m) => { return Err(DftpError::Io(format!("Cannot generate a certificate: {}", m), None)); }
+    };
+    let cert = certified.cert.der().clone();
+    Ok(TlsIdentity{
+        fingerprint: fingerprint_of(&cert),
+        cert,
+        key: certified.signing_key.serialize_der(),
+    })
+}
+
+fn fingerprint_of(cert: &CertificateDer<'_>) -> [u8; 32] {
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(&Sha256::digest(cert.as_ref()));
+    fingerprint
+}
+
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    let mut s = String::new();
+    for (i, b) in fingerprint.iter().enumerate() {
+        if i > 0 { s.push(':'); }
+        write!(&mut s, "{:02X}", b).expect("should be able to write to string");
+    }
+    s
+}
+
+// accepts the fingerprint with or without colons, in either case
+pub fn parse_fingerprint(s: &str) -> Result<[u8; 32], DftpError> {
+    let hex: String = s.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 {
+        return Err(DftpError::Usage("a fingerprint is 32 hex encoded bytes".to_string()));
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, b) in fingerprint.iter_mut().enumerate() {
+        *b = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(b) => b,
+            Err(_) => { return Err(DftpError::Usage(format!("invalid hex in fingerprint: {}", &hex[i * 2..i * 2 + 2]))); }
+        };
+    }
+    Ok(fingerprint)
+}
+
+// trusts exactly one certificate, the one the listening end printed
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+                          _server_name: &ServerName<'_>, _ocsp: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint_of(end_entity) != self.fingerprint {
+            return Err(rustls::Error::General("the peer's certificate does not match the pinned fingerprint".to_string()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// the session is shared by every handle on the connection. the last one to
+// go tells the peer we are done, so a stream running until EOF ends cleanly.
+struct TlsSession {
+    tls: Mutex<Tls>,
+    // reading off the socket happens outside of the lock on tls, so a
+    // writer on the other half does not wait for the peer to say something
+    incoming: Mutex<Incoming>,
+}
+
+struct Tls {
+    conn: Connection,
+    socket: Box<dyn PeerStream>, // what goes out, records the reading brought up included
+}
+
+impl Tls {
+    fn send_pending(&mut self) -> Result<(), Error> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+struct Incoming {
+    socket: Box<dyn PeerStream>,
+    pending: Vec<u8>, // read off the socket, not taken by the session yet
+}
+
+impl Drop for TlsSession {
+    fn drop(&mut self) {
+        if let Ok(tls) = self.tls.get_mut() {
+            tls.conn.send_close_notify();
+            let _ = tls.send_pending().and_then(|_| tls.socket.flush());
+        }
+    }
+}
+
+struct TlsStream {
+    session: Arc<TlsSession>,
+}
+
+impl TlsStream {
+    fn new(conn: Connection, peer: Box<dyn PeerStream>) -> Result<TlsStream, Error> {
+        let incoming = Incoming{ socket: peer.try_clone_stream()?, pending: Vec::new() };
+        let session = TlsSession{ tls: Mutex::new(Tls{ conn, socket: peer }), incoming: Mutex::new(incoming) };
+        Ok(TlsStream{ session: Arc::new(session) })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() { return Ok(0); }
+        let mut incoming = self.session.incoming.lock().unwrap();
+        loop {
+            {
+                let mut tls = self.session.tls.lock().unwrap();
+                match tls.conn.reader().read(buf) {
+                    Err(m) if m.kind() == WouldBlock => {},
+                    // 0 once the peer said it is done
+                    done => { return done; }
+                }
+                if !incoming.pending.is_empty() {
+                    let taken = tls.conn.read_tls(&mut &incoming.pending[..])?;
+                    incoming.pending.drain(..taken);
+                    tls.conn.process_new_packets().map_err(|m| Error::new(InvalidData, m))?;
+                    tls.send_pending()?;
+                    continue;
+                }
+            }
+            let mut raw = [0u8; TLS_READ_SIZE];
+            let n = incoming.socket.read(&mut raw)?;
+            if n == 0 {
+                // the session notes the EOF and the reader reports it
+                let mut tls = self.session.tls.lock().unwrap();
+                tls.conn.read_tls(&mut &raw[..0])?;
+                tls.conn.process_new_packets().map_err(|m| Error::new(InvalidData, m))?;
+                continue;
+            }
+            incoming.pending.extend_from_slice(&raw[..n]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut tls = self.session.tls.lock().unwrap();
+        let n = tls.conn.writer().write(buf)?;
+        tls.send_pending()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut tls = self.session.tls.lock().unwrap();
+        tls.conn.writer().flush()?;
+        tls.send_pending()?;
+        tls.socket.flush()
+    }
+}
+
+impl PeerStream for TlsStream {
+    fn try_clone_stream(&self) -> Result<Box<dyn PeerStream>, Error> {
+        Ok(Box::new(TlsStream{ session: self.session.clone() }))
+    }
+}
+
+// setting up our end, nothing the peer had a say in
+fn tls_error(m: rustls::Error) -> DftpError {
+    DftpError::Io(format!("TLS setup failed: {}", m), None)
+}
+
+// the end that waits for the other holds the certificate, the dialing end
+// pins it. that is independent of who sends the files.
+pub enum TlsRole {
+    Server(TlsIdentity),
+    Client([u8; 32]), // pinned fingerprint
+}
+
+pub fn wrap_tls(peer: Box<dyn PeerStream>, role: &TlsRole) -> Result<Box<dyn PeerStream>, DftpError> {
+    match role {
+        TlsRole::Server(identity) => wrap_tls_server(peer, identity),
+        TlsRole::Client(fingerprint) => wrap_tls_client(peer, fingerprint),
+    }
+}
+
+fn wrap_tls_client(mut peer: Box<dyn PeerStream>, fingerprint: &[u8; 32]) -> Result<Box<dyn PeerStream>, DftpError> {
+    let provider = Arc::new(default_provider());
+    let verifier = Arc::new(PinnedVerifier{ fingerprint: *fingerprint, provider: provider.clone() });
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    let name = ServerName::try_from(TLS_SERVER_NAME).expect("the server name should be valid");
+    let mut conn = ClientConnection::new(Arc::new(config), name).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        if let Err(m) = conn.complete_io(&mut peer) {
+            return Err(DftpError::Auth(format!("TLS handshake failed: {}", m)));
+        }
+    }
+    match TlsStream::new(Connection::Client(conn), peer) {
+        Ok(s) => Ok(Box::new(s)),
+        Err(m) => Err(DftpError::Network("TLS setup failed".to_string(), Some(m))),
+    }
+}
+
+fn wrap_tls_server(mut peer: Box<dyn PeerStream>, identity: &TlsIdentity) -> Result<Box<dyn PeerStream>, DftpError> {
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(identity.key.clone()));
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(vec![identity.cert.clone()], key)
+        .map_err(tls_error)?;
+    let mut conn = ServerConnection::new(Arc::new(config)).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        if let Err(m) = conn.complete_io(&mut peer) {
+            return Err(DftpError::Auth(format!("TLS handshake failed: {}", m)));
+        }
+    }
+    match TlsStream::new(Connection::Server(conn), peer) {
+        Ok(s) => Ok(Box::new(s)),
+        Err(m) => Err(DftpError::Network("TLS setup failed".to_string(), Some(m))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::duplex::pair;
+
+    fn connected() -> (Box<dyn PeerStream>, Box<dyn PeerStream>) {
+        let (ours, theirs) = pair();
+        let identity = generate_identity().unwrap();
+        let fingerprint = identity.fingerprint;
+        let server = thread::spawn(move || wrap_tls(Box::new(theirs), &TlsRole::Server(identity)));
+        let client = wrap_tls(Box::new(ours), &TlsRole::Client(fingerprint)).unwrap();
+        (client, server.join().unwrap().unwrap())
+    }
+
+    #[test]
+    fn a_waiting_reader_does_not_hold_up_the_writer() {
+        let (client, mut server) = connected();
+        let mut reader = client.try_clone_stream().unwrap();
+        let waiting = thread::spawn(move || {
+            let mut got = [0u8; 5];
+            reader.read_exact(&mut got).map(|_| got)
+        });
+        // the reader is stuck until the server says something
+        thread::sleep(Duration::from_millis(100));
+        let (done, written) = channel();
+        let mut writer = client;
+        thread::spawn(move || {
+            let _ = done.send(writer.write_all(b"hello").and_then(|_| writer.flush()));
+        });
+        written.recv_timeout(Duration::from_secs(5)).expect("the writer waited for the reader").unwrap();
+        let mut got = [0u8; 5];
+        server.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"hello");
+        server.write_all(b"world").unwrap();
+        server.flush().unwrap();
+        assert_eq!(&waiting.join().unwrap().unwrap(), b"world");
+    }
+
+    #[test]
+    fn a_wrong_fingerprint_is_an_auth_failure() {
+        let (ours, theirs) = pair();
+        let identity = generate_identity().unwrap();
+        let server = thread::spawn(move || wrap_tls(Box::new(theirs), &TlsRole::Server(identity)).map(|_| ()));
+        assert!(matches!(wrap_tls(Box::new(ours), &TlsRole::Client([0u8; 32])), Err(DftpError::Auth(_))));
+        assert!(server.join().unwrap().is_err());
+    }
+}